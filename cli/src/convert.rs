@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use byte_unit::Byte;
 use rpc::mayastor::ShareProtocolNexus;
 
@@ -25,11 +27,165 @@ pub(crate) fn parse_size(src: &str) -> Result<u64, String> {
     }
 }
 
+/// parses a range given either as "start..end" or "start:length" into a
+/// start and length in bytes, each of which can be a human string
+#[allow(dead_code)]
+pub(crate) fn parse_range(src: &str) -> Result<(u64, u64), String> {
+    let component = |s: &str| {
+        parse_size(s.trim())
+            .map_err(|e| format!("Invalid range {}: bad size {}", src, e))
+    };
+
+    if let Some(pos) = src.find("..") {
+        let start = component(&src[.. pos])?;
+        let end = component(&src[pos + 2 ..])?;
+        if start >= end {
+            return Err(format!(
+                "Invalid range {}: start must be before end",
+                src
+            ));
+        }
+        Ok((start, end - start))
+    } else if let Some(pos) = src.find(':') {
+        let start = component(&src[.. pos])?;
+        let len = component(&src[pos + 1 ..])?;
+        if len == 0 {
+            return Err(format!("Invalid range {}: length is zero", src));
+        }
+        Ok((start, len))
+    } else {
+        Err(format!(
+            "Invalid range {}: expected start..end or start:length",
+            src
+        ))
+    }
+}
+
+/// parses a human string such as "500ms", "2s" or "1m30s" into a duration,
+/// the units supported are ms, s, m and h
+#[allow(dead_code)]
+pub(crate) fn parse_duration(src: &str) -> Result<Duration, String> {
+    let mut total = Duration::from_secs(0);
+    let mut rest = src.trim();
+
+    if rest.is_empty() {
+        return Err(format!("Invalid duration {}", src));
+    }
+
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or_else(|| rest.len());
+        if digits == 0 {
+            return Err(format!("Invalid duration {}: expected a number", src));
+        }
+        let value = rest[.. digits]
+            .parse::<u64>()
+            .map_err(|e| format!("Invalid duration {}: {}", src, e))?;
+        rest = &rest[digits ..];
+
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or_else(|| rest.len());
+        total += match &rest[.. unit] {
+            "ms" => Duration::from_millis(value),
+            "s" => Duration::from_secs(value),
+            "m" => Duration::from_secs(value * 60),
+            "h" => Duration::from_secs(value * 60 * 60),
+            "" => {
+                return Err(format!("Invalid duration {}: missing unit", src))
+            }
+            u => {
+                return Err(format!(
+                    "Invalid duration {}: unknown unit {}",
+                    src, u
+                ))
+            }
+        };
+        rest = &rest[unit ..];
+    }
+
+    Ok(total)
+}
+
 pub(crate) fn parse_proto(src: &str) -> Result<ShareProtocolNexus, &str> {
     match src.to_lowercase().trim() {
         "nbd" => Ok(ShareProtocolNexus::NexusNbd),
         "nvmf" => Ok(ShareProtocolNexus::NexusNvmf),
         "iscsi" => Ok(ShareProtocolNexus::NexusIscsi),
-        _ => Err("Protocol needs be either NVMf, iSCSI or NBD"),
+        "vhost" => Ok(ShareProtocolNexus::NexusVhost),
+        _ => Err("Protocol needs be either NVMf, iSCSI, NBD or vhost"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range() {
+        assert_eq!(parse_range("0..1MiB"), Ok((0, 1024 * 1024)));
+        assert_eq!(parse_range("512:4096"), Ok((512, 4096)));
+        assert_eq!(parse_range(" 512 : 4096 "), Ok((512, 4096)));
+
+        // the second component is the end of one and the length of the other
+        assert_eq!(parse_range("1KiB..2KiB"), Ok((1024, 1024)));
+        assert_eq!(parse_range("1KiB:2KiB"), Ok((1024, 2048)));
+
+        let err = parse_range("1KiB..1KiB").unwrap_err();
+        assert!(err.contains("start must be before end"), "{}", err);
+        let err = parse_range("2KiB..1KiB").unwrap_err();
+        assert!(err.contains("start must be before end"), "{}", err);
+
+        let err = parse_range("512:0").unwrap_err();
+        assert!(err.contains("length is zero"), "{}", err);
+
+        let err = parse_range("512").unwrap_err();
+        assert!(
+            err.contains("expected start..end or start:length"),
+            "{}",
+            err
+        );
+        let err = parse_range("").unwrap_err();
+        assert!(
+            err.contains("expected start..end or start:length"),
+            "{}",
+            err
+        );
+        let err = parse_range("abc..1KiB").unwrap_err();
+        assert!(err.contains("bad size abc"), "{}", err);
+        let err = parse_range("0:").unwrap_err();
+        assert!(err.contains("bad size"), "{}", err);
+    }
+
+    #[test]
+    fn duration() {
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
+        assert_eq!(parse_duration("1m"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration(" 1m30s "), Ok(Duration::from_secs(90)));
+        assert_eq!(
+            parse_duration("1h1m1s1ms"),
+            Ok(Duration::from_millis(3_661_001))
+        );
+
+        // ms is a unit of its own rather than minutes followed by something
+        assert_eq!(parse_duration("1ms30s"), Ok(Duration::from_millis(30_001)));
+
+        let err = parse_duration("30").unwrap_err();
+        assert!(err.contains("missing unit"), "{}", err);
+        let err = parse_duration("1m30").unwrap_err();
+        assert!(err.contains("missing unit"), "{}", err);
+
+        let err = parse_duration("5d").unwrap_err();
+        assert!(err.contains("unknown unit d"), "{}", err);
+        let err = parse_duration("1.5s").unwrap_err();
+        assert!(err.contains("unknown unit ."), "{}", err);
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("  ").is_err());
+        let err = parse_duration("ms").unwrap_err();
+        assert!(err.contains("expected a number"), "{}", err);
     }
 }
@@ -90,7 +90,7 @@ enum Sub {
         /// UUID of the nexus to be published
         uuid: String,
         /// Protocol to use when sharing the nexus.
-        /// Can be NVMf, ISCSI, NBD
+        /// Can be NVMf, ISCSI, NBD, vhost
         #[structopt(
             name = "protocol",
             parse(try_from_str = "convert::parse_proto")
@@ -46,6 +46,9 @@ function getConstants() {
       NEXUS_ISCSI: pkgDef.mayastor.ShareProtocolNexus.type.value.find(
         ent => ent.name == 'NEXUS_ISCSI'
       ).number,
+      NEXUS_VHOST: pkgDef.mayastor.ShareProtocolNexus.type.value.find(
+        ent => ent.name == 'NEXUS_VHOST'
+      ).number,
     },
   };
 }
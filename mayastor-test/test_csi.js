@@ -182,7 +182,7 @@ describe('csi', function() {
                 'create_nexus',
                 {
                   uuid: uuid,
-                  size: 25 * 1024 * 1024,
+                  size: 20 * 1024 * 1024,
                   children: ['bdev:///' + BASE_UUID + n],
                 },
                 next
@@ -282,7 +282,7 @@ describe('nexus', function() {
   it('should create a nexus using all types of replicas', done => {
     let args = {
       uuid: UUID,
-      size: diskSize,
+      size: 131072,
       children: [
         'bdev:///Malloc0',
         `aio:///${aioFile}?blk_size=4096`,
@@ -1,4 +1,10 @@
-use std::{convert::TryFrom, ffi::CString};
+use std::{
+    convert::TryFrom,
+    ffi::CString,
+    fs::File,
+    io,
+    os::unix::io::AsRawFd,
+};
 
 use futures::channel::oneshot;
 use snafu::{ResultExt, Snafu};
@@ -58,6 +64,35 @@ impl AioBdev {
         .await
     }
 
+    /// return the extents, as (offset, length) in bytes, of the file which
+    /// hold data. The holes in between have never been written to and read
+    /// as zeroes. Fails if the file system cannot tell data from holes.
+    pub fn allocated_ranges(&self) -> io::Result<Vec<(u64, u64)>> {
+        let file = File::open(&self.file)?;
+        let fd = file.as_raw_fd();
+        let mut ranges = Vec::new();
+        let mut offset = 0;
+
+        loop {
+            let start = unsafe { libc::lseek(fd, offset, libc::SEEK_DATA) };
+            if start < 0 {
+                let err = io::Error::last_os_error();
+                // there is no data past the offset
+                if err.raw_os_error() == Some(libc::ENXIO) {
+                    break;
+                }
+                return Err(err);
+            }
+            let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+            if end < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            ranges.push((start as u64, (end - start) as u64));
+            offset = end;
+        }
+        Ok(ranges)
+    }
+
     /// destroy the given aio bdev
     pub async fn destroy(self) -> Result<(), BdevCreateDestroy> {
         if let Some(bdev) = Bdev::lookup_by_name(&self.name) {
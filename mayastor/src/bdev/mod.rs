@@ -3,8 +3,41 @@ use std::ffi::CStr;
 pub use aio_dev::{AioBdev, AioParseError};
 pub use iscsi_dev::{IscsiBdev, IscsiParseError};
 pub use nexus::{
-    nexus_bdev::{nexus_create, nexus_lookup, Nexus, NexusState},
-    nexus_label::{GPTHeader, GptEntry},
+    nexus_bdev::{
+        nexus_create,
+        nexus_lookup,
+        Nexus,
+        NexusCapacity,
+        NexusState,
+        RecoveryPolicy,
+    },
+    nexus_child::{
+        ChildState,
+        FaultReason,
+        LabelStatus,
+        LabelType,
+        LatencyStats,
+    },
+    nexus_dirty_log::{DirtyLog, DirtyLogError},
+    nexus_label::{
+        GPTHeader,
+        GptEntry,
+        LabelBytes,
+        LabelDiff,
+        NexusLabel,
+        Pmbr,
+        WriteOrder,
+    },
+    nexus_share::{
+        available_crypto_drivers,
+        list_shared_nexuses,
+        unshare_nexuses,
+        CachePolicy,
+        KeyFormat,
+        ShareInfo,
+        ShareOptions,
+    },
+    nexus_topology::{BdevEdge, BdevGraph, BdevNode, NodeKind},
 };
 pub use nvmf_dev::{NvmeCtlAttachReq, NvmfParseError};
 use spdk_sys::{spdk_conf_section, spdk_conf_section_get_nmval};
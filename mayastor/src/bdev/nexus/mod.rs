@@ -13,14 +13,18 @@ pub mod nexus_bdev_children;
 mod nexus_channel;
 pub(crate) mod nexus_child;
 mod nexus_config;
+pub mod nexus_dirty_log;
 pub mod nexus_fn_table;
 pub mod nexus_io;
 pub mod nexus_iscsi;
 pub mod nexus_label;
 pub mod nexus_module;
 pub mod nexus_nbd;
+pub mod nexus_nvmf;
 pub mod nexus_rpc;
 pub mod nexus_share;
+pub mod nexus_topology;
+pub mod nexus_vhost;
 
 /// public function which simply calls register module
 pub fn register_module() {
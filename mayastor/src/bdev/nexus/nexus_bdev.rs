@@ -5,9 +5,9 @@
 //! application needs synchronous mirroring may be required.
 
 use std::{
-    fmt,
     fmt::{Display, Formatter},
     os::raw::c_void,
+    time::Duration,
 };
 
 use futures::channel::oneshot;
@@ -25,12 +25,14 @@ use spdk_sys::{
     spdk_bdev_unmap_blocks,
     spdk_bdev_unregister,
     spdk_bdev_writev_blocks,
+    spdk_get_ticks,
+    spdk_get_ticks_hz,
     spdk_io_channel,
     spdk_io_device_register,
     spdk_io_device_unregister,
 };
 
-use rpc::mayastor::RebuildProgressReply;
+use rpc::mayastor::{RebuildProgressReply, ShareProtocolNexus};
 
 use crate::{
     bdev::{
@@ -38,14 +40,23 @@ use crate::{
         nexus::{
             instances,
             nexus_channel::{DREvent, NexusChannel, NexusChannelInner},
-            nexus_child::{ChildError, ChildState, NexusChild},
+            nexus_child::{
+                ChildError,
+                ChildIoError,
+                ChildState,
+                FaultReason,
+                NexusChild,
+            },
             nexus_io::{io_status, Bio},
-            nexus_iscsi::{NexusIscsiError, NexusIscsiTarget},
-            nexus_label::LabelError,
-            nexus_nbd::{NbdDisk, NbdError},
+            nexus_iscsi::NexusIscsiError,
+            nexus_label::{LabelDiff, LabelError, WriteOrder},
+            nexus_nbd::NbdError,
+            nexus_nvmf::NexusNvmfError,
+            nexus_share::{CachePolicy, ShareTransport},
+            nexus_vhost::NexusVhostError,
         },
     },
-    core::{Bdev, DmaBuf, DmaError},
+    core::{Bdev, CoreError, DmaBuf, DmaError},
     ffihelper::errno_result_from_i32,
     jsonrpc::{Code, RpcErrorCode},
     nexus_uri::BdevCreateDestroy,
@@ -63,10 +74,68 @@ pub enum Error {
     InvalidUuid { uuid: String },
     #[snafu(display("Invalid encryption key"))]
     InvalidKey {},
+    #[snafu(display("Invalid encoding of the encryption key: {}", reason))]
+    InvalidKeyEncoding { reason: String },
+    #[snafu(display(
+        "Encryption key of {} bytes does not match the key size {} of the cipher",
+        len,
+        expected
+    ))]
+    InvalidKeyLength { len: usize, expected: usize },
+    #[snafu(display(
+        "Cache policy {:?} is not supported by nexus {}: {}",
+        policy,
+        name,
+        reason
+    ))]
+    CachePolicyUnsupported {
+        policy: CachePolicy,
+        name: String,
+        reason: String,
+    },
+    #[snafu(display(
+        "Crypto driver {} is not available, available drivers: {:?}",
+        requested,
+        available
+    ))]
+    CryptoDriverUnavailable {
+        requested: String,
+        available: Vec<String>,
+    },
     #[snafu(display("Failed to create crypto bdev for nexus {}", name))]
     CreateCryptoBdev { source: Errno, name: String },
     #[snafu(display("Failed to destroy crypto bdev for nexus {}", name))]
     DestroyCryptoBdev { source: Errno, name: String },
+    #[snafu(display(
+        "Crypto bdev {} of nexus {} still exists after it was destroyed",
+        bdev,
+        name
+    ))]
+    CryptoBdevStillPresent { bdev: String, name: String },
+    #[snafu(display(
+        "The key does not match the key marker of nexus {}",
+        name
+    ))]
+    CryptoKeyMismatch { name: String },
+    #[snafu(display(
+        "The nexus {} has no key marker to verify the key",
+        name
+    ))]
+    NoKeyMarker { name: String },
+    #[snafu(display("Failed to verify the key of nexus {}", name))]
+    VerifyCryptoKey { source: ChildIoError, name: String },
+    #[snafu(display(
+        "Failed to access the key marker of child {} of nexus {}",
+        child,
+        name
+    ))]
+    KeyMarkerIo {
+        source: CoreError,
+        child: String,
+        name: String,
+    },
+    #[snafu(display("Failed to allocate the key marker of nexus {}", name))]
+    AllocKeyMarker { source: DmaError, name: String },
     #[snafu(display(
         "The nexus {} has been already shared with a different protocol",
         name
@@ -81,6 +150,34 @@ pub enum Error {
         source: NexusIscsiError,
         name: String,
     },
+    #[snafu(display("Failed to share nvmf nexus {}", name))]
+    ShareNvmfNexus {
+        source: NexusNvmfError,
+        name: String,
+    },
+    #[snafu(display("Failed to share nexus {} over vhost", name))]
+    ShareVhostNexus {
+        source: NexusVhostError,
+        name: String,
+    },
+    #[snafu(display("Failed to unshare nexus {} from NBD", name))]
+    UnshareNbdNexus { source: NbdError, name: String },
+    #[snafu(display("Failed to unshare nexus {} from vhost", name))]
+    UnshareVhostNexus {
+        source: NexusVhostError,
+        name: String,
+    },
+    #[snafu(display("Invalid namespace uuid \"{}\"", uuid))]
+    InvalidNsUuid { uuid: String },
+    #[snafu(display(
+        "Read only sharing of nexus {} is not supported over {:?}",
+        name,
+        protocol
+    ))]
+    ReadOnlyShare {
+        name: String,
+        protocol: ShareProtocolNexus,
+    },
     #[snafu(display("Failed to allocate label of nexus {}", name))]
     AllocLabel { source: DmaError, name: String },
     #[snafu(display("Failed to write label of nexus {}", name))]
@@ -116,12 +213,34 @@ pub enum Error {
         child: String,
         name: String,
     },
+    #[snafu(display(
+        "Failed to replace the bdev of child {} of nexus {}",
+        child,
+        name
+    ))]
+    ReplaceChildBdev {
+        source: ChildError,
+        child: String,
+        name: String,
+    },
+    #[snafu(display("Failed to reconcile child {} of nexus {}", child, name))]
+    ReconcileChild {
+        source: ChildError,
+        child: String,
+        name: String,
+    },
     #[snafu(display(
         "Cannot delete the last child {} of nexus {}",
         child,
         name
     ))]
     DestroyLastChild { child: String, name: String },
+    #[snafu(display(
+        "Failed to destroy children of nexus {}: {}",
+        name,
+        errors.join(", ")
+    ))]
+    DestroyChildren { name: String, errors: Vec<String> },
     #[snafu(display("Failed to destroy child {} of nexus {}", child, name))]
     DestroyChild {
         source: BdevCreateDestroy,
@@ -161,8 +280,42 @@ pub enum Error {
         name,
     ))]
     RebuildTaskNotFound { child: String, name: String },
+    #[snafu(display(
+        "Rebuild of child {} of nexus {} is already in progress",
+        child,
+        name,
+    ))]
+    RebuildInProgress { child: String, name: String },
+    #[snafu(display(
+        "Rebuild of child {} of nexus {} cannot be aborted in state {}",
+        child,
+        name,
+        state
+    ))]
+    AbortRebuild {
+        child: String,
+        name: String,
+        state: String,
+    },
     #[snafu(display("Invalid ShareProtocol value {}", sp_value))]
     InvalidShareProtocol { sp_value: i32 },
+    #[snafu(display(
+        "Child {} of nexus {} cannot be a rebuild source in state {}",
+        child,
+        name,
+        state
+    ))]
+    InvalidRebuildSource {
+        child: String,
+        name: String,
+        state: String,
+    },
+    #[snafu(display(
+        "Invalid recovery policy for nexus {}: {}",
+        name,
+        reason
+    ))]
+    InvalidRecoveryPolicy { name: String, reason: String },
 }
 
 impl RpcErrorCode for Error {
@@ -177,6 +330,24 @@ impl RpcErrorCode for Error {
             Error::InvalidKey {
                 ..
             } => Code::InvalidParams,
+            Error::InvalidKeyEncoding {
+                ..
+            } => Code::InvalidParams,
+            Error::InvalidKeyLength {
+                ..
+            } => Code::InvalidParams,
+            Error::CachePolicyUnsupported {
+                ..
+            } => Code::InvalidParams,
+            Error::CryptoDriverUnavailable {
+                ..
+            } => Code::InvalidParams,
+            Error::CryptoKeyMismatch {
+                ..
+            } => Code::InvalidParams,
+            Error::NoKeyMarker {
+                ..
+            } => Code::InvalidParams,
             Error::AlreadyShared {
                 ..
             } => Code::InvalidParams,
@@ -195,6 +366,9 @@ impl RpcErrorCode for Error {
             Error::OpenChild {
                 ..
             } => Code::InvalidParams,
+            Error::ReplaceChildBdev {
+                ..
+            } => Code::InvalidParams,
             Error::DestroyLastChild {
                 ..
             } => Code::InvalidParams,
@@ -204,6 +378,18 @@ impl RpcErrorCode for Error {
             Error::InvalidShareProtocol {
                 ..
             } => Code::InvalidParams,
+            Error::InvalidNsUuid {
+                ..
+            } => Code::InvalidParams,
+            Error::ReadOnlyShare {
+                ..
+            } => Code::InvalidParams,
+            Error::InvalidRecoveryPolicy {
+                ..
+            } => Code::InvalidParams,
+            Error::RebuildInProgress {
+                ..
+            } => Code::InvalidParams,
             _ => Code::InternalError,
         }
     }
@@ -211,20 +397,6 @@ impl RpcErrorCode for Error {
 
 pub(crate) static NEXUS_PRODUCT_ID: &str = "Nexus CAS Driver v0.0.1";
 
-pub enum NexusTarget {
-    NbdDisk(NbdDisk),
-    NexusIscsiTarget(NexusIscsiTarget),
-}
-
-impl fmt::Debug for NexusTarget {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            NexusTarget::NbdDisk(disk) => fmt::Debug::fmt(&disk, f),
-            NexusTarget::NexusIscsiTarget(tgt) => fmt::Debug::fmt(&tgt, f),
-        }
-    }
-}
-
 /// The main nexus structure
 #[derive(Debug)]
 pub struct Nexus {
@@ -232,6 +404,9 @@ pub struct Nexus {
     pub(crate) name: String,
     /// the requested size of the nexus, children are allowed to be larger
     pub(crate) size: u64,
+    /// the block size of the nexus, all children must have the same block
+    /// size. Zero until it is set when the children are first opened
+    pub(crate) block_size: u32,
     /// number of children part of this nexus
     pub(crate) child_count: u32,
     /// vector of children
@@ -246,17 +421,75 @@ pub struct Nexus {
     pub dr_complete_notify: Option<oneshot::Sender<i32>>,
     /// the offset in num blocks where the data partition starts
     pub data_ent_offset: u64,
+    /// the offset in num blocks where the meta partition starts
+    pub meta_ent_offset: u64,
+    /// the size of the meta partition in num blocks
+    pub(crate) meta_ent_blocks: u64,
     /// the handle to be used when sharing the nexus, this allows for the bdev
     /// to be shared with vbdevs on top
     pub(crate) share_handle: Option<String>,
+    /// name of the crypto bdev created on top of the nexus when it was
+    /// shared with a key
+    pub(crate) crypto_bdev: Option<String>,
+    /// key the nexus has been shared with, whether it is encrypted with it
+    /// or the key is merely carried along
+    pub(crate) share_key: Option<String>,
     /// vector of rebuild tasks
     pub rebuilds: Vec<RebuildTask>,
-    /// enum containing the protocol-specific target used to publish the nexus
-    pub nexus_target: Option<NexusTarget>,
+    /// child to read from when rebuilding, if not set any open child is used
+    pub(crate) rebuild_source: Option<String>,
+    /// the protocol-specific transport used to publish the nexus
+    pub nexus_target: Option<Box<dyn ShareTransport>>,
+    /// policy for recovering faulted children automatically, None if they
+    /// are left alone
+    pub(crate) recovery: Option<RecoveryPolicy>,
+    /// identifies the recovery loop belonging to the current policy, such
+    /// that loops of replaced policies stop
+    pub(crate) recovery_generation: u64,
+    /// identifies the loop persisting the dirty region logs, such that loops
+    /// of replaced intervals stop
+    pub(crate) dirty_log_generation: u64,
+    /// sequence number of the last dirty region log persisted, the highest
+    /// one found among the children wins when loading it
+    pub(crate) dirty_log_sequence: u64,
 }
 
 unsafe impl core::marker::Sync for Nexus {}
 
+/// How faulted children are recovered automatically, see
+/// Nexus::set_recovery_policy(). A faulted child is probed every
+/// probe_interval, and rebuilt once it has been probed successfully
+/// success_threshold times in a row. Neither may be zero.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecoveryPolicy {
+    /// time between probes of the faulted children
+    pub probe_interval: Duration,
+    /// number of consecutive successful probes before a child is recovered
+    pub success_threshold: u32,
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        Self {
+            probe_interval: Duration::from_secs(10),
+            success_threshold: 3,
+        }
+    }
+}
+
+/// capacity of a nexus as provided by its children. As the children mirror
+/// each other, the capacity is bounded by the smallest of them.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+pub struct NexusCapacity {
+    /// the number of bytes every child can hold in its data partition, i.e.
+    /// the largest size the nexus can have
+    pub usable_bytes: u64,
+    /// the number of children taken into account
+    pub child_count: u32,
+    /// the raw size in bytes of the smallest child
+    pub smallest_child_bytes: u64,
+}
+
 #[derive(Debug, Serialize, Clone, Copy, PartialEq, PartialOrd)]
 pub enum NexusState {
     /// nexus created but no children attached
@@ -326,10 +559,20 @@ impl Nexus {
             bdev_raw: Box::into_raw(b),
             dr_complete_notify: None,
             data_ent_offset: 0,
+            meta_ent_offset: 0,
+            meta_ent_blocks: 0,
             share_handle: None,
+            crypto_bdev: None,
+            share_key: None,
+            rebuild_source: None,
             size,
+            block_size: 0,
             rebuilds: Vec::new(),
             nexus_target: None,
+            recovery: None,
+            recovery_generation: 0,
+            dirty_log_generation: 0,
+            dirty_log_sequence: 0,
         });
 
         n.bdev.set_uuid(match uuid {
@@ -357,6 +600,18 @@ impl Nexus {
         self.state = state;
         state
     }
+    /// returns the block size of the nexus, children are validated against
+    /// it when they are opened
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// set the block size of the nexus and of the bdev it exposes
+    pub(crate) fn set_block_size(&mut self, block_size: u32) {
+        self.block_size = block_size;
+        self.bdev.set_block_len(block_size);
+    }
+
     /// returns the size in bytes of the nexus instance
     pub fn size(&self) -> u64 {
         u64::from(self.bdev.block_len()) * self.bdev.num_blocks()
@@ -389,10 +644,30 @@ impl Nexus {
 
         self.try_open_children()?;
         self.sync_labels().await?;
-        self.register()
+        if let Err(e) = self.load_dirty_log().await {
+            warn!("{}: failed to load the dirty region log: {}", self.name, e);
+        }
+        self.register()?;
+
+        // children faulted by the dirty region log need a rebuild
+        if self.count_children_in_state(ChildState::Faulted) > 0 {
+            self.set_state(NexusState::Degraded);
+        }
+        Ok(())
     }
 
+    /// Make sure all children carry the same label, writing a new one to
+    /// them if they do not. The labels are probed more than once while doing
+    /// so, the repeated reads are served from the label cache of the
+    /// children, which is dropped again once done.
     pub async fn sync_labels(&mut self) -> Result<(), Error> {
+        self.children.iter().for_each(|c| c.enable_label_cache());
+        let result = self.sync_child_labels().await;
+        self.children.iter().for_each(|c| c.disable_label_cache());
+        result
+    }
+
+    async fn sync_child_labels(&mut self) -> Result<(), Error> {
         if let Ok(label) = self.update_child_labels().await {
             // now register the bdev but update its size first to
             // ensure we adhere to the partitions
@@ -403,6 +678,8 @@ impl Nexus {
 
             info!("{}: {} ", self.name, label);
             self.data_ent_offset = label.offset();
+            self.meta_ent_offset = label.meta_offset();
+            self.meta_ent_blocks = label.meta_block_count();
             self.bdev.set_block_count(label.get_block_count());
         } else {
             // one or more children do not have, or have an invalid gpt label.
@@ -414,8 +691,96 @@ impl Nexus {
                 self.name
             );
 
-            let mut label = self.generate_label();
+            let generated = self.generate_label();
+            let probed = self.probe_child_labels().await;
+
+            // a label already found on the children which fits the nexus is
+            // kept, as the generated one only differs from it by the random
+            // partition GUIDs, such that children which have it are left
+            // alone
+            let mut label = probed
+                .iter()
+                .filter_map(|r| r.as_ref().ok())
+                .find(|l| {
+                    l.diff(&generated).iter().all(|d| match d {
+                        LabelDiff::PartitionGuid {
+                            ..
+                        } => true,
+                        _ => false,
+                    })
+                })
+                .cloned()
+                .unwrap_or(generated);
+
+            // log what is about to change for children that do have a label,
+            // and which children are initialized with a fresh one. Only those
+            // children are written to.
+            let mut stale = Vec::new();
+            let mut unreadable = Vec::new();
+            for (child, current) in self.children.iter().zip(probed) {
+                match current {
+                    Ok(current) => {
+                        let diffs = current.diff(&label);
+                        for diff in &diffs {
+                            info!(
+                                "{}: child {}: {}",
+                                self.name, child.name, diff
+                            );
+                        }
+                        if !diffs.is_empty() {
+                            stale.push(child.name.clone());
+                        }
+                    }
+                    Err(_) if child.needs_label() => {
+                        info!(
+                            "{}: child {} has {:?} label, writing a new one",
+                            self.name,
+                            child.name,
+                            child.label_status()
+                        );
+                        stale.push(child.name.clone());
+                    }
+                    Err(e) => {
+                        error!(
+                            "{}: label of child {} could not be probed: {}",
+                            self.name, child.name, e
+                        );
+                        unreadable.push(child.name.clone());
+                    }
+                }
+            }
+
+            // where the data of a child whose label cannot be read lives is
+            // unknown, so it is faulted and has to be rebuilt in full
+            if !unreadable.is_empty() {
+                if self.state == NexusState::Init {
+                    // there are no IO channels to reconfigure yet, open()
+                    // degrades the nexus once it has been registered
+                    self.children
+                        .iter_mut()
+                        .filter(|c| unreadable.contains(&c.name))
+                        .for_each(|c| {
+                            c.set_state(ChildState::Faulted);
+                        });
+                } else {
+                    self.fault_children(&unreadable, FaultReason::IoError)
+                        .await?;
+                }
+                self.children
+                    .iter_mut()
+                    .filter(|c| unreadable.contains(&c.name))
+                    .for_each(|c| c.incremental = false);
+            }
+
+            if self.count_children_in_state(ChildState::Open) == 0 {
+                return Err(Error::NexusIncomplete {
+                    name: self.name.clone(),
+                });
+            }
+
             self.data_ent_offset = label.offset();
+            self.meta_ent_offset = label.meta_offset();
+            self.meta_ent_blocks = label.meta_block_count();
             self.bdev.set_block_count(label.get_block_count());
 
             let blk_size = self.bdev.block_len();
@@ -427,19 +792,37 @@ impl Nexus {
                 name: self.name.clone(),
             })?;
 
-            self.write_label(&mut buf, &mut label, true).await.context(
-                WriteLabel {
-                    name: self.name.clone(),
-                },
-            )?;
-            self.write_label(&mut buf, &mut label, false)
-                .await
-                .context(WriteLabel {
-                    name: self.name.clone(),
-                })?;
+            self.write_label_to(
+                &mut buf,
+                &mut label,
+                true,
+                WriteOrder::Relaxed,
+                &stale,
+            )
+            .await
+            .context(WriteLabel {
+                name: self.name.clone(),
+            })?;
+            self.write_label_to(
+                &mut buf,
+                &mut label,
+                false,
+                WriteOrder::Relaxed,
+                &stale,
+            )
+            .await
+            .context(WriteLabel {
+                name: self.name.clone(),
+            })?;
             info!("{}: {} ", self.name, label);
 
-            self.write_pmbr().await.context(WritePmbr {
+            self.write_pmbr_to(&stale).await.context(WritePmbr {
+                name: self.name.clone(),
+            })?;
+
+            // only now that both halves have been written do the labels of
+            // children which carried another one agree with themselves
+            self.probe_labels_of(&stale).await.context(WriteLabel {
                 name: self.name.clone(),
             })?;
         }
@@ -475,8 +858,37 @@ impl Nexus {
         self.set_state(NexusState::Closed)
     }
 
-    /// Destroy the nexus
-    pub async fn destroy(&mut self) {
+    /// Unshare the nexus and close all of its children, in that order. The
+    /// children are closed even if unsharing fails, in which case the error
+    /// is returned afterwards. The nexus itself stays registered, destroy()
+    /// takes care of that.
+    pub async fn shutdown(&mut self) -> Result<(), Error> {
+        info!("{}: shutting down", self.name);
+
+        let unshared = self.unshare().await;
+        if let Err(ref e) = unshared {
+            error!("{}: failed to unshare: {}", self.name, e);
+        }
+
+        // children which failed to open are closed too, as they may be left
+        // in any state
+        self.children
+            .iter_mut()
+            .filter(|c| c.state != ChildState::Closed)
+            .for_each(|c| {
+                c.close();
+            });
+
+        unshared
+    }
+
+    /// Destroy the nexus and the bdevs of its children. If the nexus cannot
+    /// be unshared, e.g. because a VM is still connected to its vhost
+    /// controller, the error is returned and nothing is destroyed, such that
+    /// destroy can be retried. Otherwise the nexus is destroyed even if some
+    /// of the child bdevs cannot be, in which case DestroyChildren lists them
+    /// along with what went wrong.
+    pub async fn destroy(&mut self) -> Result<(), Error> {
         // used to synchronize the destroy call
         extern "C" fn nexus_destroy_cb(arg: *mut c_void, rc: i32) {
             let s = unsafe { Box::from_raw(arg as *mut oneshot::Sender<bool>) };
@@ -489,16 +901,18 @@ impl Nexus {
             }
         }
 
-        let _ = self.unshare().await;
-        assert_eq!(self.share_handle, None);
+        if let Err(e) = self.shutdown().await {
+            error!("{}: not destroyed as it is still shared", self.name);
+            return Err(e);
+        }
 
+        let mut errors = Vec::new();
         for child in self.children.iter_mut() {
-            let _ = child.close();
             info!("Destroying child bdev {}", child.name);
 
-            let r = child.destroy().await;
-            if r.is_err() {
-                error!("Failed to destroy child {}", child.name);
+            if let Err(e) = child.destroy().await {
+                error!("Failed to destroy child {}: {}", child.name, e);
+                errors.push(format!("{}: {}", child.name, e));
             }
         }
 
@@ -516,6 +930,15 @@ impl Nexus {
         }
 
         let _ = r.await;
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::DestroyChildren {
+                name: self.name.clone(),
+                errors,
+            })
+        }
     }
 
     /// register the bdev with SPDK and set the callbacks for io channel
@@ -594,6 +1017,8 @@ impl Nexus {
             );
 
             pio.ctx_as_mut_ref().status = io_status::FAILED;
+        } else {
+            pio.nexus_as_ref().record_child_latency(child_io);
         }
         pio.assess();
         // always free the child IO
@@ -713,6 +1138,8 @@ impl Nexus {
             })
             .collect::<Vec<_>>();
 
+        self.mark_dirty(&io);
+
         // if any of the children failed to dispatch
         if results.iter().any(|r| *r != 0) {
             error!(
@@ -746,6 +1173,8 @@ impl Nexus {
             })
             .collect::<Vec<_>>();
 
+        self.mark_dirty(&io);
+
         if results.iter().any(|r| *r != 0) {
             error!(
                 "{}: Failed to submit dispatched IO {:?}",
@@ -755,6 +1184,31 @@ impl Nexus {
         }
     }
 
+    /// record the range modified by the IO on the faulted children, which
+    /// are not part of the IO path and hence miss out on it
+    fn mark_dirty(&self, io: &Bio) {
+        let offset = io.offset() * io.block_len();
+        let len = io.num_blocks() * io.block_len();
+        self.children_in_state(ChildState::Faulted)
+            .iter()
+            .for_each(|c| c.mark_dirty(offset, len));
+    }
+
+    /// record the time it took the child to complete the IO with the child
+    /// it was submitted to
+    unsafe fn record_child_latency(&self, child_io: *mut spdk_bdev_io) {
+        let ticks = spdk_get_ticks() - (*child_io).internal.submit_tsc;
+        let latency =
+            Duration::from_micros(ticks * 1_000_000 / spdk_get_ticks_hz());
+        if let Some(child) = self.children.iter().find(|c| {
+            c.bdev
+                .as_ref()
+                .map_or(false, |b| b.as_ptr() == (*child_io).bdev)
+        }) {
+            child.record_latency(latency);
+        }
+    }
+
     /// returns the current status of the nexus
     pub fn status(&self) -> NexusState {
         self.state
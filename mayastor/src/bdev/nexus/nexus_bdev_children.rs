@@ -12,18 +12,25 @@
 //! but simply does what its told. Therefore, the callee must be careful when
 //! using this method.
 //!
-//! 'fault_child` will do the same as `offline_child` except, it will not close
-//! the child.
+//! `fault_children` will do the same as `offline_child` for any number of
+//! children at once, except, it will not close them.
 //!
 //! `add_child` will construct a new `NexusChild` and add the bdev given by the
 //! uri to the nexus. The nexus will transition to degraded mode as the new
 //! child requires rebuild first.
 //!
+//! `set_recovery_policy` enables the automatic recovery of faulted children,
+//! which are probed periodically and rebuilt once they respond again. It is
+//! disabled by default.
+//!
 //! When reconfiguring the nexus, we traverse all our children, create new IO
 //! channels for all children that are in the open state.
 
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use crossbeam::channel::Receiver;
 use futures::future::join_all;
+use futures_timer::Delay;
 use rpc::mayastor::RebuildStateReply;
 use snafu::ResultExt;
 
@@ -35,13 +42,23 @@ use crate::{
             DestroyChild,
             Error,
             Nexus,
+            NexusCapacity,
             NexusState,
             OpenChild,
             ReadLabel,
+            ReconcileChild,
+            RecoveryPolicy,
+            ReplaceChildBdev,
             StartRebuild,
         },
         nexus_channel::DREvent,
-        nexus_child::{ChildState, NexusChild},
+        nexus_child::{
+            probe_labels,
+            ChildError,
+            ChildState,
+            FaultReason,
+            NexusChild,
+        },
         nexus_label::NexusLabel,
     },
     core::{Bdev, Reactors},
@@ -49,23 +66,33 @@ use crate::{
     rebuild::{RebuildActions, RebuildState, RebuildTask},
 };
 
+/// maximum number of labels probed at the same time
+const LABEL_PROBE_CONCURRENCY: usize = 8;
+
+/// source of the generations of recovery loops, unique across all nexuses
+/// such that a loop never mistakes a recreated nexus for its own
+static RECOVERY_GENERATION: AtomicU64 = AtomicU64::new(1);
+
 impl Nexus {
     /// register children with the nexus, only allowed during the nexus init
     /// phase
     pub fn register_children(&mut self, dev_name: &[String]) {
         assert_eq!(self.state, NexusState::Init);
         self.child_count = dev_name.len() as u32;
-        dev_name
-            .iter()
-            .map(|c| {
-                debug!("{}: Adding child {}", self.name, c);
-                self.children.push(NexusChild::new(
-                    c.clone(),
-                    self.name.clone(),
-                    Bdev::lookup_by_name(c),
-                ))
-            })
-            .for_each(drop);
+        for c in dev_name {
+            debug!("{}: Adding child {}", self.name, c);
+            let child = self.new_child(c, Bdev::lookup_by_name(c));
+            self.children.push(child);
+        }
+    }
+
+    /// create a child of this nexus, which knows the uuid of the nexus for
+    /// the purpose of logging
+    fn new_child(&self, name: &str, bdev: Option<Bdev>) -> NexusChild {
+        let mut child =
+            NexusChild::new(name.to_string(), self.name.clone(), bdev);
+        child.parent_uuid = Some(self.bdev.uuid_as_string());
+        child
     }
 
     /// register a single child to nexus, only allowed during the nexus init
@@ -76,11 +103,8 @@ impl Nexus {
     ) -> Result<(), BdevCreateDestroy> {
         assert_eq!(self.state, NexusState::Init);
         let name = bdev_create(&uri).await?;
-        self.children.push(NexusChild::new(
-            uri.to_string(),
-            self.name.clone(),
-            Bdev::lookup_by_name(&name),
-        ));
+        let child = self.new_child(uri, Bdev::lookup_by_name(&name));
+        self.children.push(child);
 
         self.child_count += 1;
         Ok(())
@@ -101,7 +125,7 @@ impl Nexus {
 
         let child_bdev = match Bdev::lookup_by_name(&name) {
             Some(child) => {
-                if child.block_len() != self.bdev.block_len()
+                if child.block_len() != self.block_size()
                     || self.min_num_blocks() < child.num_blocks()
                 {
                     if let Err(err) = bdev_destroy(uri).await {
@@ -126,12 +150,8 @@ impl Nexus {
             }
         };
 
-        let mut child = NexusChild::new(
-            uri.to_owned(),
-            self.name.clone(),
-            Some(child_bdev),
-        );
-        match child.open(self.size) {
+        let mut child = self.new_child(uri, Some(child_bdev));
+        match child.open(self.size, self.block_size) {
             Ok(name) => {
                 // we have created the bdev, and created a nexusChild struct. To
                 // make use of the device itself the
@@ -142,7 +162,7 @@ impl Nexus {
 
                 // mark faulted so that it can never take part in the IO path of
                 // the nexus until brought online.
-                child.state = ChildState::Faulted;
+                child.set_state(ChildState::Faulted);
 
                 self.children.push(child);
                 self.child_count += 1;
@@ -187,17 +207,38 @@ impl Nexus {
     ) -> Result<Receiver<RebuildState>, Error> {
         trace!("{}: start rebuild request for {}", self.name, destination);
 
-        let source = match self
+        // an aborted rebuild is only gone once it has wound down
+        if self.rebuilds.iter().any(|t| t.destination == destination) {
+            return Err(Error::RebuildInProgress {
+                child: destination.to_string(),
+                name: self.name.clone(),
+            });
+        }
+
+        let source = if let Some(source) = self.rebuild_source.clone() {
+            // the source may have gone away since it was pinned
+            self.validate_rebuild_source(&source)?;
+            source
+        } else {
+            match self.children_in_state(ChildState::Open).first() {
+                Some(child) => child.name.clone(),
+                None => {
+                    return Err(Error::OpenChildNotFound {
+                        name: self.name.clone(),
+                    })
+                }
+            }
+        };
+
+        let start = self.data_ent_offset;
+        let end = self.bdev.num_blocks() + self.data_ent_offset;
+        let ranges = match self
             .children
-            .iter_mut()
-            .find(|c| c.state == ChildState::Open)
+            .iter()
+            .find(|c| c.name == destination && c.incremental)
         {
-            Some(child) => child.name.clone(),
-            None => {
-                return Err(Error::OpenChildNotFound {
-                    name: self.name.clone(),
-                })
-            }
+            Some(child) => self.dirty_block_ranges(child, start, end),
+            None => self.rebuild_ranges(&source, start, end),
         };
 
         if let Some(dst_child) =
@@ -208,8 +249,9 @@ impl Nexus {
                     self.name.clone(),
                     source,
                     destination.to_string(),
-                    self.data_ent_offset,
-                    self.bdev.num_blocks() + self.data_ent_offset,
+                    start,
+                    end,
+                    ranges,
                     |nexus, task| {
                         Reactors::current().send_future(async move {
                             Nexus::complete_rebuild(nexus, task).await;
@@ -244,6 +286,85 @@ impl Nexus {
         }
     }
 
+    /// returns the (start, end) block ranges within start..end of the source
+    /// child which hold data, which are all a rebuild from it has to copy
+    fn rebuild_ranges(
+        &self,
+        source: &str,
+        start: u64,
+        end: u64,
+    ) -> Vec<(u64, u64)> {
+        let block_size = u64::from(self.bdev.block_len());
+        match self.children.iter().find(|c| c.name == source) {
+            Some(child) => child
+                .ranges_to_copy(start * block_size, end * block_size)
+                .into_iter()
+                .map(|(offset, len)| {
+                    (
+                        offset / block_size,
+                        (offset + len + block_size - 1) / block_size,
+                    )
+                })
+                .collect(),
+            None => vec![(start, end)],
+        }
+    }
+
+    /// returns the dirty regions of the child, which are byte ranges of the
+    /// nexus, as (start, end) block ranges of the child within start..end
+    fn dirty_block_ranges(
+        &self,
+        child: &NexusChild,
+        start: u64,
+        end: u64,
+    ) -> Vec<(u64, u64)> {
+        let block_size = u64::from(self.bdev.block_len());
+        child
+            .dirty_ranges()
+            .into_iter()
+            .map(|(s, e)| {
+                (
+                    (start + s / block_size).min(end),
+                    (start + (e + block_size - 1) / block_size).min(end),
+                )
+            })
+            .filter(|(s, e)| s < e)
+            .collect()
+    }
+
+    /// Pin the child to read from when rebuilding other children. Only an
+    /// open child, which implies it has a valid label, can be the source.
+    pub fn set_rebuild_source(
+        &mut self,
+        child_name: &str,
+    ) -> Result<(), Error> {
+        self.validate_rebuild_source(child_name)?;
+        info!("{}: rebuild source set to {}", self.name, child_name);
+        self.rebuild_source = Some(child_name.to_string());
+        Ok(())
+    }
+
+    /// Return the pinned rebuild source, if any
+    pub fn rebuild_source(&self) -> Option<&str> {
+        self.rebuild_source.as_deref()
+    }
+
+    /// check that the named child exists and can be read from by a rebuild
+    fn validate_rebuild_source(&self, child_name: &str) -> Result<(), Error> {
+        match self.children.iter().find(|c| c.name == child_name) {
+            Some(child) if child.state == ChildState::Open => Ok(()),
+            Some(child) => Err(Error::InvalidRebuildSource {
+                child: child_name.to_string(),
+                name: self.name.clone(),
+                state: child.state.to_string(),
+            }),
+            None => Err(Error::ChildNotFound {
+                name: self.name.clone(),
+                child: child_name.to_string(),
+            }),
+        }
+    }
+
     /// Return rebuild task associated with the destination.
     /// Return error if no rebuild task associated with destination.
     fn get_rebuild_task(
@@ -273,6 +394,32 @@ impl Nexus {
         Ok(())
     }
 
+    /// Abort the rebuild of the destination child, which stops copying at the
+    /// next segment boundary. The child is left faulted, whatever the rebuild
+    /// copied so far, such that it never takes part in the IO path with
+    /// partial data. It is considered to be repairing until the task has
+    /// completed, such that no other rebuild of it is started meanwhile. A
+    /// rebuild which has already finished cannot be aborted.
+    pub fn abort_rebuild(&mut self, destination: &str) -> Result<(), Error> {
+        let name = self.name.clone();
+        let rt = self.get_rebuild_task(destination)?;
+        if !rt.abort() {
+            return Err(Error::AbortRebuild {
+                child: destination.to_string(),
+                name,
+                state: rt.state.to_string(),
+            });
+        }
+
+        if let Some(child) =
+            self.children.iter_mut().find(|c| c.name == destination)
+        {
+            child.set_state(ChildState::Faulted);
+        }
+        info!("{}: aborted rebuild of child {}", self.name, destination);
+        Ok(())
+    }
+
     /// Return the state of a rebuild task
     pub async fn get_rebuild_state(
         &mut self,
@@ -319,8 +466,13 @@ impl Nexus {
 
         recovered_child.repairing = false;
 
-        if task.state == RebuildState::Completed {
-            recovered_child.state = ChildState::Open;
+        if task.is_aborted() {
+            info!(
+                "Rebuild task for child {} of nexus {} has been aborted",
+                &task.destination, &self.name
+            );
+        } else if task.state == RebuildState::Completed {
+            recovered_child.set_state(ChildState::Open);
 
             // child can now be part of the IO path
             self.reconfigure(DREvent::ChildOnline).await;
@@ -338,6 +490,93 @@ impl Nexus {
         Ok(())
     }
 
+    /// set the policy for recovering faulted children automatically, or
+    /// disable it with None. A recovery loop is started on the current
+    /// reactor which runs until the policy is replaced or the nexus is gone.
+    /// A policy which would recover children without probing them, or
+    /// probe them without pause, is rejected.
+    pub fn set_recovery_policy(
+        &mut self,
+        policy: Option<RecoveryPolicy>,
+    ) -> Result<(), Error> {
+        let invalid = match policy {
+            Some(p) if p.success_threshold == 0 => {
+                Some("success_threshold must be at least 1")
+            }
+            Some(p) if p.probe_interval.as_nanos() == 0 => {
+                Some("probe_interval must not be zero")
+            }
+            _ => None,
+        };
+        if let Some(reason) = invalid {
+            return Err(Error::InvalidRecoveryPolicy {
+                name: self.name.clone(),
+                reason: reason.to_string(),
+            });
+        }
+
+        let generation = RECOVERY_GENERATION.fetch_add(1, Ordering::Relaxed);
+        self.recovery = policy;
+        self.recovery_generation = generation;
+        self.children.iter_mut().for_each(|c| c.probe_successes = 0);
+
+        let policy = match policy {
+            Some(policy) => policy,
+            None => {
+                info!("{}: automatic recovery disabled", self.name);
+                return Ok(());
+            }
+        };
+        info!("{}: automatic recovery enabled: {:?}", self.name, policy);
+
+        let name = self.name.clone();
+        Reactors::current().send_future(async move {
+            loop {
+                Delay::new(policy.probe_interval).await;
+                match nexus_lookup(&name) {
+                    Some(nexus) if nexus.recovery_generation == generation => {
+                        nexus.recover_faulted_children(&policy).await
+                    }
+                    _ => break,
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// probe the faulted children which are not being rebuilt already, and
+    /// start a rebuild of those which have been probed successfully often
+    /// enough in a row
+    async fn recover_faulted_children(&mut self, policy: &RecoveryPolicy) {
+        let mut recovered = Vec::new();
+
+        for child in self
+            .children_in_state_mut(ChildState::Faulted)
+            .into_iter()
+            .filter(|c| !c.repairing)
+        {
+            if child.probe_alive().await {
+                child.probe_successes += 1;
+            } else {
+                child.probe_successes = 0;
+            }
+            if child.probe_successes >= policy.success_threshold {
+                child.probe_successes = 0;
+                recovered.push(child.name.clone());
+            }
+        }
+
+        for name in recovered {
+            info!("{}: recovering faulted child {}", self.name, name);
+            if let Err(e) = self.start_rebuild(&name).await {
+                error!(
+                    "{}: failed to start rebuild of child {}: {}",
+                    self.name, name, e
+                );
+            }
+        }
+    }
+
     pub async fn complete_rebuild(nexus: String, task: String) {
         info!(
             "nexus {} received complete_rebuild from task {}",
@@ -396,6 +635,86 @@ impl Nexus {
         Ok(self.set_state(NexusState::Degraded))
     }
 
+    /// Fault the named children in one go, i.e. when the node they live on
+    /// has gone away. Either all children are found and faulted, or none are.
+    /// The IO channels are reconfigured and the state of the nexus is
+    /// determined once, after all children have been faulted, rather than
+    /// for each child. Children which are not open are left as they are.
+    pub async fn fault_children(
+        &mut self,
+        names: &[String],
+        reason: FaultReason,
+    ) -> Result<NexusState, Error> {
+        if let Some(missing) = names
+            .iter()
+            .find(|n| !self.children.iter().any(|c| &c.name == *n))
+        {
+            return Err(Error::ChildNotFound {
+                name: self.name.clone(),
+                child: missing.to_owned(),
+            });
+        }
+
+        let faulted = self
+            .children_in_state_mut(ChildState::Open)
+            .into_iter()
+            .filter(|c| names.contains(&c.name))
+            .map(|c| {
+                c.set_state(ChildState::Faulted);
+                c.name.clone()
+            })
+            .collect::<Vec<_>>();
+
+        if faulted.is_empty() {
+            return Ok(self.state);
+        }
+
+        warn!(
+            "{}: faulted {} children ({:?}): {:?}",
+            self.name,
+            faulted.len(),
+            reason,
+            faulted
+        );
+        self.reconfigure(DREvent::ChildFault).await;
+
+        if self.count_children_in_state(ChildState::Open) > 0 {
+            Ok(self.set_state(NexusState::Degraded))
+        } else {
+            Ok(self.set_state(NexusState::Faulted))
+        }
+    }
+
+    /// reconcile the state of the child with the device behind it, see
+    /// NexusChild::reconcile(), and reconfigure the IO channels if the child
+    /// has been closed as a result.
+    pub async fn reconcile_child(
+        &mut self,
+        name: &str,
+    ) -> Result<NexusState, Error> {
+        let child = match self.children.iter_mut().find(|c| c.name == name) {
+            Some(child) => child,
+            None => {
+                return Err(Error::ChildNotFound {
+                    name: self.name.clone(),
+                    child: name.to_owned(),
+                })
+            }
+        };
+
+        let before = child.state;
+        let after = child.reconcile().await.context(ReconcileChild {
+            child: name.to_owned(),
+            name: self.name.clone(),
+        })?;
+
+        if after != before {
+            self.reconfigure(DREvent::ChildOffline).await;
+            return Ok(self.set_state(NexusState::Degraded));
+        }
+        Ok(self.state)
+    }
+
     /// online a child and reconfigure the IO channels. The child is already
     /// registered, but simpy not opened. This can be required in case where
     /// a child is misbehaving.
@@ -412,7 +731,7 @@ impl Nexus {
                     child: name.to_owned(),
                 })
             } else {
-                child.open(self.size).context(OpenChild {
+                child.open(self.size, self.block_size).context(OpenChild {
                     child: name.to_owned(),
                     name: self.name.clone(),
                 })?;
@@ -427,6 +746,45 @@ impl Nexus {
             })
         }
     }
+
+    /// Replace the bdev behind a closed child with the existing bdev of the
+    /// given name, e.g. when the data of the child has been migrated to an
+    /// equivalent device, which must carry the same label and data. The
+    /// child keeps its name, is opened on the new bdev and takes part in the
+    /// IO path again. On failure the child is left on its old bdev.
+    pub async fn replace_child_bdev(
+        &mut self,
+        name: &str,
+        bdev_name: &str,
+    ) -> Result<NexusState, Error> {
+        trace!("{}: replace bdev of child {} request", self.name, name);
+
+        let bdev = Bdev::lookup_by_name(bdev_name).ok_or_else(|| {
+            Error::ChildMissing {
+                child: bdev_name.to_owned(),
+                name: self.name.clone(),
+            }
+        })?;
+
+        let (size, block_size) = (self.size, self.block_size);
+        if let Some(child) = self.children.iter_mut().find(|c| c.name == name) {
+            child.replace_bdev(bdev, size, block_size).context(
+                ReplaceChildBdev {
+                    child: name.to_owned(),
+                    name: self.name.clone(),
+                },
+            )?;
+        } else {
+            return Err(Error::ChildNotFound {
+                name: self.name.clone(),
+                child: name.to_owned(),
+            });
+        }
+
+        self.reconfigure(DREvent::ChildOnline).await;
+        Ok(self.set_state(NexusState::Degraded))
+    }
+
     /// destroy all children that are part of this nexus closes any child
     /// that might be open first
     pub(crate) async fn destroy_children(&mut self) {
@@ -462,7 +820,14 @@ impl Nexus {
             });
         }
 
-        let blk_size = self.children[0].bdev.as_ref().unwrap().block_len();
+        // the block size is taken from the first child when the nexus is
+        // created, after which it is fixed for the lifetime of the nexus
+        if self.block_size == 0 {
+            let blk_size = self.children[0].bdev.as_ref().unwrap().block_len();
+            self.set_block_size(blk_size);
+        }
+
+        let blk_size = self.block_size;
 
         if self
             .children
@@ -474,14 +839,33 @@ impl Nexus {
             });
         }
 
-        self.bdev.set_block_len(blk_size);
-
-        let size = self.size;
+        // the nexus can be no larger than what its smallest child can hold,
+        // which is also its size if none has been asked for
+        let size = match min_usable_size(&self.children) {
+            Some(usable) if self.size == 0 => usable,
+            Some(usable) if usable >= self.size => self.size,
+            usable => {
+                error!(
+                    "{}: children provide {:?} usable bytes, {} required",
+                    self.name, usable, self.size
+                );
+                return Err(Error::NexusIncomplete {
+                    name: self.name.clone(),
+                });
+            }
+        };
+        if size != self.size {
+            info!(
+                "{}: sized to {} bytes after its smallest child",
+                self.name, size
+            );
+            self.size = size;
+        }
 
         let (open, error): (Vec<_>, Vec<_>) = self
             .children
             .iter_mut()
-            .map(|c| c.open(size))
+            .map(|c| c.open(size, blk_size))
             .partition(Result::is_ok);
 
         // depending on IO consistency policies, we might be able to go online
@@ -533,14 +917,11 @@ impl Nexus {
     /// (2) if any label does not match the label of the first child
 
     pub async fn update_child_labels(&mut self) -> Result<NexusLabel, Error> {
-        let mut futures = Vec::new();
-        self.children
-            .iter_mut()
-            .map(|child| futures.push(child.probe_label()))
-            .for_each(drop);
-
-        let (ok_res, mut err_res): (Vec<_>, Vec<_>) =
-            join_all(futures).await.into_iter().partition(Result::is_ok);
+        let (ok_res, mut err_res): (Vec<_>, Vec<_>) = self
+            .probe_child_labels()
+            .await
+            .into_iter()
+            .partition(Result::is_ok);
         if let Some(Err(err)) = err_res.pop() {
             // pick the first error
             return Err(err).context(ReadLabel {
@@ -561,23 +942,102 @@ impl Nexus {
         Ok(ret.pop().unwrap())
     }
 
+    /// probe the labels of all children concurrently, returning the result
+    /// for each child in the same order as the children. Labels of children
+    /// which have not been written to since they were probed last are not
+    /// read again.
+    pub async fn probe_child_labels(
+        &mut self,
+    ) -> Vec<Result<NexusLabel, ChildError>> {
+        probe_labels(&mut self.children, LABEL_PROBE_CONCURRENCY, false).await
+    }
+
+    /// return all children which are currently in the given state
+    pub(crate) fn children_in_state(
+        &self,
+        state: ChildState,
+    ) -> Vec<&NexusChild> {
+        self.children.iter().filter(|c| c.state == state).collect()
+    }
+
+    /// same as children_in_state() but the children can be modified
+    pub(crate) fn children_in_state_mut(
+        &mut self,
+        state: ChildState,
+    ) -> Vec<&mut NexusChild> {
+        self.children
+            .iter_mut()
+            .filter(|c| c.state == state)
+            .collect()
+    }
+
+    /// return the number of children which are currently in the given state
+    pub(crate) fn count_children_in_state(&self, state: ChildState) -> usize {
+        self.children_in_state(state).len()
+    }
+
+    /// returns true if the nexus has at least one open child to serve IO
+    /// from. Children only become open once their label has been validated
+    /// against the others, so there is no need to check it here.
+    pub fn is_serviceable(&self) -> bool {
+        self.count_children_in_state(ChildState::Open) > 0
+    }
+
+    /// returns true if the nexus can serve IO but not all of its children
+    /// take part in it, because they are faulted or being rebuilt
+    pub fn degraded(&self) -> bool {
+        self.is_serviceable()
+            && (self.count_children_in_state(ChildState::Faulted) > 0
+                || self.children.iter().any(|c| c.repairing))
+    }
+
     /// The nexus is allowed to be smaller then the underlying child devices
     /// this function returns the smallest blockcnt of all online children as
     /// they MAY vary in size.
     pub(crate) fn min_num_blocks(&self) -> u64 {
-        let mut blockcnt = std::u64::MAX;
-        self.children
+        self.children_in_state(ChildState::Open)
             .iter()
-            .filter(|c| c.state == ChildState::Open)
             .map(|c| c.bdev.as_ref().unwrap().num_blocks())
-            .collect::<Vec<_>>()
+            .min()
+            .unwrap_or(std::u64::MAX)
+    }
+
+    /// return the capacity provided by the children of this nexus. Children
+    /// without a bdev cannot contribute any capacity and are not counted.
+    pub fn capacity(&self) -> NexusCapacity {
+        let children = self
+            .children
             .iter()
-            .map(|s| {
-                if *s < blockcnt {
-                    blockcnt = *s;
-                }
-            })
-            .for_each(drop);
-        blockcnt
+            .filter(|c| c.bdev.is_some())
+            .collect::<Vec<_>>();
+
+        NexusCapacity {
+            usable_bytes: children
+                .iter()
+                .filter_map(|c| c.usable_size())
+                .min()
+                .unwrap_or(0),
+            child_count: children.len() as u32,
+            smallest_child_bytes: children
+                .iter()
+                .map(|c| c.bdev.as_ref().unwrap().size_in_bytes())
+                .min()
+                .unwrap_or(0),
+        }
     }
 }
+
+/// return the smallest usable size of the given children which are, or are
+/// about to be, opened, i.e. the largest size a nexus built from them can
+/// have. Children which have no bdev, or have been given up on, do not count.
+/// None if there is no child to go by.
+pub(crate) fn min_usable_size(children: &[NexusChild]) -> Option<u64> {
+    children
+        .iter()
+        .filter(|c| match c.state {
+            ChildState::Init | ChildState::Closed | ChildState::Open => true,
+            _ => false,
+        })
+        .filter_map(NexusChild::usable_size)
+        .min()
+}
@@ -80,9 +80,8 @@ impl NexusChannelInner {
 
         // iterate to over all our children which are in the open state
         nexus
-            .children
-            .iter_mut()
-            .filter(|c| c.state == ChildState::Open)
+            .children_in_state(ChildState::Open)
+            .iter()
             .map(|c| {
                 self.ch.push(
                     BdevHandle::try_from(c.get_descriptor().unwrap()).unwrap(),
@@ -118,9 +117,8 @@ impl NexusChannel {
         });
 
         nexus
-            .children
-            .iter_mut()
-            .filter(|c| c.state == ChildState::Open)
+            .children_in_state(ChildState::Open)
+            .iter()
             .map(|c| {
                 channels.ch.push(
                     BdevHandle::try_from(c.get_descriptor().unwrap()).unwrap(),
@@ -1,15 +1,50 @@
-use std::{convert::TryFrom, fmt::Display, sync::Arc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt::Display,
+    ptr::NonNull,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use crc::crc32;
+use futures::{
+    channel::oneshot,
+    future::{self, Either},
+    stream::{self, StreamExt},
+};
+use futures_timer::Delay;
 use nix::errno::Errno;
 use serde::{export::Formatter, Serialize};
 use snafu::{ResultExt, Snafu};
+use tracing::{debug, debug_span, error, field, info, trace, warn, Span};
+use tracing_futures::Instrument;
 
-use spdk_sys::{spdk_bdev_module_release_bdev, spdk_io_channel};
+use spdk_sys::{
+    spdk_bdev_desc,
+    spdk_bdev_module_release_bdev,
+    spdk_io_channel,
+    SPDK_BDEV_IO_TYPE_NVME_IO,
+    SPDK_NVME_OPC_RESERVATION_ACQUIRE,
+    SPDK_NVME_OPC_RESERVATION_REGISTER,
+    SPDK_NVME_OPC_RESERVATION_RELEASE,
+};
 
 use crate::{
-    bdev::nexus::nexus_label::{GPTHeader, GptEntry, NexusLabel},
+    bdev::nexus::nexus_label::{
+        GPTHeader,
+        GptEntry,
+        LabelBytes,
+        LabelError,
+        NexusLabel,
+        Pmbr,
+        GPT_REVISION,
+        GPT_SIGNATURE,
+        MBR_PROTECTIVE_TYPE,
+    },
     core::{Bdev, BdevHandle, CoreError, Descriptor, DmaBuf, DmaError},
-    nexus_uri::{bdev_destroy, BdevCreateDestroy},
+    nexus_uri::{bdev_destroy, nexus_parse_uri, BdevCreateDestroy, BdevType},
 };
 
 #[derive(Debug, Snafu)]
@@ -17,11 +52,21 @@ pub enum ChildError {
     #[snafu(display("Child is not closed"))]
     ChildNotClosed {},
     #[snafu(display(
-        "Child is smaller than parent {} vs {}",
+        "Child usable size is smaller than parent {} vs {}",
         child_size,
         parent_size
     ))]
     ChildTooSmall { child_size: u64, parent_size: u64 },
+    #[snafu(display("Parent size of zero is invalid"))]
+    ZeroParentSize {},
+    #[snafu(display("Block size {} of new bdev does not match {}", new, old))]
+    IncompatibleBlockSize { old: u32, new: u32 },
+    #[snafu(display(
+        "Child block size {} does not match the parent block size {}",
+        child,
+        parent
+    ))]
+    BlockSizeMismatch { child: u32, parent: u32 },
     #[snafu(display("Open child"))]
     OpenChild { source: CoreError },
     #[snafu(display("Claim child"))]
@@ -30,12 +75,27 @@ pub enum ChildError {
     ChildReadOnly {},
     #[snafu(display("Invalid state of child"))]
     ChildInvalid {},
+    #[snafu(display(
+        "Child in state {} has handle: {}, has bdev: {}",
+        state,
+        has_handle,
+        has_bdev
+    ))]
+    InconsistentState {
+        state: String,
+        has_handle: bool,
+        has_bdev: bool,
+    },
+    #[snafu(display("Failed to allocate buffer to probe child"))]
+    ProbeAlloc { source: DmaError },
     #[snafu(display("Failed to allocate buffer for label"))]
     LabelAlloc { source: DmaError },
     #[snafu(display("Failed to read label from child"))]
     LabelRead { source: ChildIoError },
-    #[snafu(display("Primary and backup labels are invalid"))]
-    LabelInvalid {},
+    #[snafu(display("Invalid label: {}", reason))]
+    LabelInvalid { reason: String },
+    #[snafu(display("Child has no label"))]
+    LabelMissing {},
     #[snafu(display("Failed to allocate buffer for partition table"))]
     PartitionTableAlloc { source: DmaError },
     #[snafu(display("Failed to read partition table from child"))]
@@ -48,6 +108,27 @@ pub enum ChildError {
     OpenWithoutBdev {},
     #[snafu(display("Failed to create a BdevHandle for child"))]
     HandleCreate { source: CoreError },
+    #[snafu(display(
+        "Timed out after {:?} waiting for child to become {}, it is {}",
+        timeout,
+        target,
+        state
+    ))]
+    StateTimeout {
+        target: String,
+        state: String,
+        timeout: Duration,
+    },
+    #[snafu(display(
+        "Child has {} blocks, fewer than the {} blocks to grow the label to",
+        num_blocks,
+        requested
+    ))]
+    DeviceNotGrown { num_blocks: u64, requested: u64 },
+    #[snafu(display("Failed to grow the label of the child"))]
+    LabelGrow { source: LabelError },
+    #[snafu(display("Failed to write the label of the child"))]
+    LabelWrite { source: ChildIoError },
 }
 
 #[derive(Debug, Snafu)]
@@ -56,12 +137,98 @@ pub enum ChildIoError {
     WriteError { source: CoreError, name: String },
     #[snafu(display("Error reading from {}", name))]
     ReadError { source: CoreError, name: String },
+    #[snafu(display("Error flushing {}", name))]
+    FlushError { source: CoreError, name: String },
+    #[snafu(display("Failed to allocate buffer for {}", name))]
+    BufferAlloc { source: DmaError, name: String },
     #[snafu(display("Invalid descriptor for child bdev {}", name))]
     InvalidDescriptor { name: String },
+    #[snafu(display("Child {} is read-only", name))]
+    ChildReadOnly { name: String },
+    #[snafu(display("IO to child {} is quiesced", name))]
+    Quiesced { name: String },
+    #[snafu(display("Child {} is in an inconsistent state", name))]
+    InconsistentState { name: String },
+    #[snafu(display(
+        "Buffer length {} is not a multiple of the block size {} of {}",
+        len,
+        block_size,
+        name
+    ))]
+    UnalignedBuffer {
+        name: String,
+        len: usize,
+        block_size: u32,
+    },
+    #[snafu(display(
+        "Buffer of {} bytes is too small to read {} bytes from {}",
+        have,
+        need,
+        name
+    ))]
+    BufferTooSmall {
+        name: String,
+        have: usize,
+        need: usize,
+    },
+    #[snafu(display("Block size of {} does not match {}", src, dst))]
+    BlockSizeMismatch { src: String, dst: String },
+    #[snafu(display(
+        "Invalid range to copy to {}, offset {} length {}",
+        name,
+        offset,
+        len
+    ))]
+    InvalidCopyRange { name: String, offset: u64, len: u64 },
+    #[snafu(display(
+        "Checksum mismatch after copying to {} at offset {}",
+        name,
+        offset
+    ))]
+    CopyChecksum { name: String, offset: u64 },
+    #[snafu(display(
+        "Invalid range to stream on {}, offset {} length {} chunk size {}",
+        name,
+        offset,
+        len,
+        chunk_size
+    ))]
+    InvalidStreamRange {
+        name: String,
+        offset: u64,
+        len: u64,
+        chunk_size: u64,
+    },
+    #[snafu(display(
+        "Write to {} at offset {} length {} overlaps with a staged write",
+        name,
+        offset,
+        len
+    ))]
+    StagedOverlap { name: String, offset: u64, len: u64 },
+    #[snafu(display(
+        "Child {} with driver {} cannot report its allocated ranges",
+        name,
+        driver
+    ))]
+    AllocationUnsupported { name: String, driver: String },
+    #[snafu(display("Failed to get the allocated ranges of {}", name))]
+    AllocationQuery {
+        source: std::io::Error,
+        name: String,
+    },
+    #[snafu(display(
+        "Child {} with driver {} does not support reservations",
+        name,
+        driver
+    ))]
+    ReservationUnsupported { name: String, driver: String },
+    #[snafu(display("Reservation command failed on {}", name))]
+    ReservationError { source: CoreError, name: String },
 }
 
 #[derive(Debug, Clone, Copy, Serialize, PartialEq)]
-pub(crate) enum ChildState {
+pub enum ChildState {
     /// child has not been opened, but we are in the process of opening it
     Init,
     /// cannot add this bdev to the parent as its incompatible property wise
@@ -88,10 +255,237 @@ impl ToString for ChildState {
     }
 }
 
+/// The label scheme of a child, see NexusChild::detect_label_type()
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum LabelType {
+    /// GUID partition table, which is what we write
+    Gpt,
+    /// an MBR partition table without GPT
+    LegacyMbr,
+    /// no label we know of, i.e. a fresh device
+    Unknown,
+}
+
+/// What probing the label of a child found, see NexusChild::probe_label()
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum LabelStatus {
+    /// the label has not been probed since the child was last written to,
+    /// or it could not be read
+    Unknown,
+    /// the child has a valid label
+    Valid,
+    /// the child has no label at all, i.e. it is a fresh device
+    Missing,
+    /// the child has a label which is damaged or not usable by us
+    Invalid,
+}
+
+impl From<&Result<NexusLabel, ChildError>> for LabelStatus {
+    fn from(result: &Result<NexusLabel, ChildError>) -> Self {
+        match result {
+            Ok(_) => LabelStatus::Valid,
+            Err(ChildError::LabelMissing {}) => LabelStatus::Missing,
+            Err(ChildError::LabelInvalid {
+                ..
+            })
+            | Err(ChildError::InvalidPartitionTable {})
+            | Err(ChildError::PartitionTableChecksum {}) => {
+                LabelStatus::Invalid
+            }
+            Err(_) => LabelStatus::Unknown,
+        }
+    }
+}
+
+/// Why children are faulted, as reported when faulting them
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum FaultReason {
+    /// the device failed IO
+    IoError,
+    /// the device, or the node it lives on, has gone away
+    Missing,
+    /// faulted on request of the control plane
+    Admin,
+}
+
+/// Whether reads should be served from a child rather than from the others,
+/// i.e. to favour a child on fast storage when the others are slower.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ReadPreference {
+    /// read from the child as much as from any other
+    Normal,
+    /// read from the child whenever it is available
+    Preferred,
+    /// only read from the child if no other child can be read from
+    Avoid,
+}
+
+impl Default for ReadPreference {
+    fn default() -> Self {
+        ReadPreference::Normal
+    }
+}
+
+/// Type of a persistent reservation, the values are those used by NVMe.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ReservationType {
+    WriteExclusive = 1,
+    ExclusiveAccess = 2,
+    WriteExclusiveRegistrantsOnly = 3,
+    ExclusiveAccessRegistrantsOnly = 4,
+    WriteExclusiveAllRegistrants = 5,
+    ExclusiveAccessAllRegistrants = 6,
+}
+
+/// reservation register action (RREGA) to register a new key
+const RESV_REGISTER_KEY: u32 = 0;
+/// reservation register action (RREGA) to unregister the current key
+const RESV_UNREGISTER_KEY: u32 = 1;
+/// ignore existing key (IEKEY) bit of the reservation commands
+const RESV_IGNORE_KEY: u32 = 1 << 3;
+
+/// maximum number of blocks kept by the label cache
+const LABEL_CACHE_ENTRIES: usize = 8;
+
+/// Short lived cache of the regions read while probing the label, such that
+/// the repeated probes done during assembly do not hit the device each time.
+/// Any write overlapping a cached region invalidates it.
+#[derive(Debug, Default)]
+pub(crate) struct LabelCache {
+    entries: Vec<(u64, Vec<u8>)>,
+}
+
+impl LabelCache {
+    /// return the cached data for the exact region, if any
+    fn get(&self, offset: u64, len: usize) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(o, data)| *o == offset && data.len() == len)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    /// cache the region evicting the oldest entry when full
+    fn insert(&mut self, offset: u64, data: &[u8]) {
+        self.entries.retain(|(o, _)| *o != offset);
+        if self.entries.len() == LABEL_CACHE_ENTRIES {
+            self.entries.remove(0);
+        }
+        self.entries.push((offset, data.to_vec()));
+    }
+
+    /// drop all cached regions overlapping with the given range
+    fn invalidate(&mut self, offset: u64, len: u64) {
+        self.entries.retain(|(o, data)| {
+            offset + len <= *o || *o + data.len() as u64 <= offset
+        });
+    }
+}
+
+/// number of bits of a latency kept below its most significant bit, i.e.
+/// each power of two is split into 8 buckets, bounding the error to 12.5%
+const LATENCY_SUB_BITS: u32 = 3;
+const LATENCY_SUB_BUCKETS: usize = 1 << LATENCY_SUB_BITS;
+/// enough buckets to cover every u64 latency
+const LATENCY_BUCKETS: usize =
+    (64 - LATENCY_SUB_BITS as usize + 1) * LATENCY_SUB_BUCKETS;
+
+/// Latency distribution of the IO completed by a child, in microseconds. As
+/// with HdrHistogram, the buckets are linear within each power of two, such
+/// that recording a latency is an increment of a bucket found with a few bit
+/// operations.
+#[derive(Debug)]
+pub(crate) struct LatencyHistogram {
+    buckets: Vec<u64>,
+    count: u64,
+    max: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; LATENCY_BUCKETS],
+            count: 0,
+            max: 0,
+        }
+    }
+}
+
+impl LatencyHistogram {
+    fn bucket(us: u64) -> usize {
+        if us < LATENCY_SUB_BUCKETS as u64 {
+            return us as usize;
+        }
+        let msb = 63 - us.leading_zeros();
+        let shift = msb - LATENCY_SUB_BITS;
+        (shift as usize + 1) * LATENCY_SUB_BUCKETS
+            + (us >> shift) as usize % LATENCY_SUB_BUCKETS
+    }
+
+    /// the highest latency which falls in the bucket
+    fn bucket_limit(bucket: usize) -> u64 {
+        if bucket < LATENCY_SUB_BUCKETS {
+            return bucket as u64;
+        }
+        let shift = (bucket / LATENCY_SUB_BUCKETS - 1) as u32;
+        let sub = (bucket % LATENCY_SUB_BUCKETS) as u64;
+        ((LATENCY_SUB_BUCKETS as u64 + sub + 1) << shift).wrapping_sub(1)
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        self.buckets[Self::bucket(us)] += 1;
+        self.count += 1;
+        self.max = self.max.max(us);
+    }
+
+    /// the upper limit of the bucket holding the given percentile, capped
+    /// at the highest latency seen
+    fn percentile(&self, percentile: u64) -> u64 {
+        let rank = ((self.count * percentile + 99) / 100).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Self::bucket_limit(bucket).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    fn stats(&self) -> LatencyStats {
+        if self.count == 0 {
+            return LatencyStats::default();
+        }
+        LatencyStats {
+            p50: self.percentile(50),
+            p95: self.percentile(95),
+            p99: self.percentile(99),
+            max: self.max,
+            count: self.count,
+        }
+    }
+}
+
+/// Latency percentiles of the IO completed by a child, in microseconds,
+/// see NexusChild::latency_percentiles(). Percentiles are accurate to
+/// within 12.5%, all values are zero until the child has completed any IO.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+pub struct LatencyStats {
+    pub p50: u64,
+    pub p95: u64,
+    pub p99: u64,
+    pub max: u64,
+    /// number of IOs the percentiles are based on
+    pub count: u64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct NexusChild {
     /// name of the parent this child belongs too
     pub(crate) parent: String,
+    /// uuid of the parent, if known, see child_span()
+    #[serde(skip_serializing)]
+    pub(crate) parent_uuid: Option<String>,
     /// Name of the child is the URI used to create it.
     /// Note that bdev name can differ from it!
     pub(crate) name: String,
@@ -99,16 +493,90 @@ pub struct NexusChild {
     /// the bdev wrapped in Bdev
     pub(crate) bdev: Option<Bdev>,
     #[serde(skip_serializing)]
-    /// channel on which we submit the IO
-    pub(crate) ch: *mut spdk_io_channel,
+    /// channel on which we submit the IO, null while there is none, use
+    /// io_channel() to get at it
+    ch: *mut spdk_io_channel,
     #[serde(skip_serializing)]
     pub(crate) desc: Option<Arc<Descriptor>>,
     /// current state of the child
     pub(crate) state: ChildState,
     pub(crate) repairing: bool,
+    /// number of consecutive successful probes of the child while faulted,
+    /// see probe_alive()
+    #[serde(skip_serializing)]
+    pub(crate) probe_successes: u32,
+    /// the child has been opened without write access
+    #[serde(skip_serializing)]
+    pub(crate) read_only: bool,
     /// descriptor obtained after opening a device
     #[serde(skip_serializing)]
     pub(crate) bdev_handle: Option<BdevHandle>,
+    /// cache for label reads, only present when enabled
+    #[serde(skip_serializing)]
+    label_cache: RefCell<Option<LabelCache>>,
+    /// the label found by the last probe, dropped on any write to the child
+    #[serde(skip_serializing)]
+    probed_label: RefCell<Option<NexusLabel>>,
+    /// what the last probe found, reset on any write to the child
+    #[serde(skip_serializing)]
+    label_status: Cell<LabelStatus>,
+    /// number of IOs submitted through read_at() and write_at() which have
+    /// not completed yet
+    #[serde(skip_serializing)]
+    inflight: Cell<usize>,
+    /// new IO through read_at() and write_at() is rejected while set
+    #[serde(skip_serializing)]
+    quiesced: bool,
+    /// type of the reservation we hold on the child, if any
+    reservation: Cell<Option<ReservationType>>,
+    /// preference for reading from this child over its siblings
+    read_preference: ReadPreference,
+    /// notified of the new state by set_state(), see wait_for_state()
+    #[serde(skip_serializing)]
+    state_waiters: RefCell<Vec<oneshot::Sender<ChildState>>>,
+    /// byte ranges of the nexus written while the child was faulted, kept
+    /// sorted and merged, see mark_dirty()
+    #[serde(skip_serializing)]
+    dirty: RefCell<Vec<(u64, u64)>>,
+    /// the child was in sync with its siblings when it started missing
+    /// writes, such that the dirty regions are all it lacks and a rebuild
+    /// only has to copy those
+    #[serde(skip_serializing)]
+    pub(crate) incremental: bool,
+    /// latencies of the IO completed by the child, see record_latency()
+    #[serde(skip_serializing)]
+    latency: RefCell<LatencyHistogram>,
+}
+
+/// Counts an IO as in flight for as long as it is alive, such that IOs whose
+/// future is dropped before completion are accounted for as well.
+struct InflightGuard<'a>(&'a Cell<usize>);
+
+impl<'a> InflightGuard<'a> {
+    fn new(inflight: &'a Cell<usize>) -> Self {
+        inflight.set(inflight.get() + 1);
+        Self(inflight)
+    }
+}
+
+impl Drop for InflightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+impl Drop for NexusChild {
+    /// a child which is dropped without having been closed would leak its
+    /// claim on the bdev and its descriptors, so close it here
+    fn drop(&mut self) {
+        if self.desc.is_some() || self.bdev_handle.is_some() {
+            warn!(
+                "{}: child {} in state {:?} was not closed before drop",
+                self.parent, self.name, self.state
+            );
+            self.close();
+        }
+    }
 }
 
 impl Display for NexusChild {
@@ -134,13 +602,48 @@ impl NexusChild {
     /// is already opened by someone else (i.e one of the targets) it will
     /// error out.
     ///
-    /// only devices in the closed or Init state can be opened.
+    /// only devices in the closed or Init state can be opened, and only if
+    /// their block size matches the block size of the parent.
     pub(crate) fn open(
         &mut self,
         parent_size: u64,
+        parent_block_size: u32,
+    ) -> Result<String, ChildError> {
+        self.open_with_access(parent_size, parent_block_size, true)
+    }
+
+    /// Open the child for observers such as monitoring or backup, which only
+    /// read from it. The child is opened read only and without a claim, so it
+    /// can be shared with whoever else has the bdev open, and writes to it
+    /// are refused with ChildReadOnly.
+    pub fn open_shared(
+        &mut self,
+        parent_size: u64,
+        parent_block_size: u32,
+    ) -> Result<String, ChildError> {
+        self.open_with_access(parent_size, parent_block_size, false)
+    }
+
+    /// Same as open() but the child is opened read only, and thus cannot be
+    /// written to, unless write is set. A read only child does not prevent
+    /// others from writing to the bdev.
+    pub(crate) fn open_with_access(
+        &mut self,
+        parent_size: u64,
+        parent_block_size: u32,
+        write: bool,
     ) -> Result<String, ChildError> {
         trace!("{}: Opening child device {}", self.parent, self.name);
 
+        // a nexus which cannot hold any data is a bug of the caller
+        if parent_size == 0 {
+            error!(
+                "{}: open of child {} with parent size 0",
+                self.parent, self.name
+            );
+            return Err(ChildError::ZeroParentSize {});
+        }
+
         if self.state != ChildState::Closed && self.state != ChildState::Init {
             return Err(ChildError::ChildNotClosed {});
         }
@@ -151,28 +654,47 @@ impl NexusChild {
 
         let bdev = self.bdev.as_ref().unwrap();
 
+        if bdev.block_len() != parent_block_size {
+            error!(
+                "{}: child {} has block size {} but the parent uses {}",
+                self.parent,
+                self.name,
+                bdev.block_len(),
+                parent_block_size
+            );
+            self.set_state(ChildState::ConfigInvalid);
+            return Err(ChildError::BlockSizeMismatch {
+                child: bdev.block_len(),
+                parent: parent_block_size,
+            });
+        }
+
+        // once labeled, only the data partition is available to the parent
+        // so that is what we must compare against rather than the raw size
         let child_size = bdev.size_in_bytes();
-        if parent_size > child_size {
+        let usable_size = self.usable_size().unwrap();
+        if parent_size > usable_size {
             error!(
-                "{}: child to small parent size: {} child size: {}",
-                self.name, parent_size, child_size
+                "{}: child to small parent size: {} child size: {} usable size: {}",
+                self.name, parent_size, child_size, usable_size
             );
-            self.state = ChildState::ConfigInvalid;
+            self.set_state(ChildState::ConfigInvalid);
             return Err(ChildError::ChildTooSmall {
                 parent_size,
-                child_size,
+                child_size: usable_size,
             });
         }
 
         self.desc = Some(Arc::new(
-            Bdev::open_by_name(&bdev.name(), true).context(OpenChild {})?,
+            Bdev::open_by_name(&bdev.name(), write).context(OpenChild {})?,
         ));
+        self.read_only = !write;
 
         self.bdev_handle = Some(
             BdevHandle::try_from(self.desc.as_ref().unwrap().clone()).unwrap(),
         );
 
-        self.state = ChildState::Open;
+        self.set_state(ChildState::Open);
 
         debug!("{}: child {} opened successfully", self.parent, self.name);
 
@@ -190,11 +712,170 @@ impl NexusChild {
         }
     }
 
+    /// return the raw spdk descriptor of this child, or None if the child is
+    /// not open. This is meant for integrating with other spdk subsystems
+    /// only.
+    ///
+    /// # Safety
+    /// The pointer is owned by the child and becomes dangling once the child
+    /// is closed. The caller must not close it, nor use it after the child
+    /// has been closed.
+    pub unsafe fn raw_descriptor(&self) -> Option<*mut spdk_bdev_desc> {
+        self.desc.as_ref().map(|d| d.as_ptr())
+    }
+
+    /// number of IOs submitted through read_at() and write_at() which are
+    /// outstanding on this child
+    pub fn inflight(&self) -> usize {
+        self.inflight.get()
+    }
+
+    /// latency percentiles of the IO which completed successfully since the
+    /// child was created or the stats were reset
+    pub fn latency_percentiles(&self) -> LatencyStats {
+        self.latency.borrow().stats()
+    }
+
+    /// record the latency of an IO completed by the child, both the IO of
+    /// the nexus it takes part in and that of read_at() and write_at()
+    pub(crate) fn record_latency(&self, latency: Duration) {
+        self.latency.borrow_mut().record(latency);
+    }
+
+    /// start collecting latencies afresh, e.g. once a slow device has been
+    /// dealt with
+    pub fn reset_latency_stats(&self) {
+        self.latency.replace(LatencyHistogram::default());
+    }
+
+    /// reject new IO through read_at() and write_at() with Quiesced, without
+    /// closing the child. IO which has been submitted already is not
+    /// affected, wait for inflight() to drop to zero for that to complete.
+    pub fn quiesce(&mut self) {
+        debug!("{}: quiescing child {}", self.parent, self.name);
+        self.quiesced = true;
+    }
+
+    /// accept new IO again after quiesce()
+    pub fn resume(&mut self) {
+        debug!("{}: resuming child {}", self.parent, self.name);
+        self.quiesced = false;
+    }
+
+    /// returns the channel on which IO is submitted to this child, or None if
+    /// the child has no channel, e.g. because it has been closed
+    pub fn io_channel(&self) -> Option<NonNull<spdk_io_channel>> {
+        NonNull::new(self.ch)
+    }
+
+    /// returns the preference for reading from this child
+    pub fn read_preference(&self) -> ReadPreference {
+        self.read_preference
+    }
+
+    /// set the preference for reading from this child, which is kept when
+    /// the child is closed and reopened
+    pub fn set_read_preference(&mut self, preference: ReadPreference) {
+        debug!(
+            "{}: read preference of child {} set to {:?}",
+            self.parent, self.name, preference
+        );
+        self.read_preference = preference;
+    }
+
+    /// returns true if new IO is rejected because of quiesce()
+    pub fn is_quiesced(&self) -> bool {
+        self.quiesced
+    }
+
+    /// set the state of the child, waking up anyone waiting for the state to
+    /// change in wait_for_state()
+    pub(crate) fn set_state(&mut self, state: ChildState) -> ChildState {
+        if self.state != state {
+            trace!(
+                "{}: child {} transitioned from {} to {}",
+                self.parent,
+                self.name,
+                self.state.to_string(),
+                state.to_string()
+            );
+        }
+        // an open child is in sync with its siblings again, one which is
+        // faulted while open misses only what is written from now on, and
+        // writes to a closed one are not tracked at all
+        match state {
+            ChildState::Open => {
+                self.dirty.borrow_mut().clear();
+                self.incremental = false;
+            }
+            ChildState::Faulted if self.state == ChildState::Open => {
+                self.incremental = true;
+            }
+            ChildState::Closed => self.incremental = false,
+            _ => {}
+        }
+        self.state = state;
+        for waiter in self.state_waiters.borrow_mut().drain(..) {
+            // the waiter may have timed out already
+            let _ = waiter.send(state);
+        }
+        state
+    }
+
+    /// wait until the child is in the target state, which it might be
+    /// already, failing with StateTimeout if it does not get there in time
+    pub async fn wait_for_state(
+        &self,
+        target: ChildState,
+        timeout: Duration,
+    ) -> Result<(), ChildError> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if self.state == target {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ChildError::StateTimeout {
+                    target: target.to_string(),
+                    state: self.state.to_string(),
+                    timeout,
+                });
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            {
+                let mut waiters = self.state_waiters.borrow_mut();
+                waiters.retain(|w| !w.is_canceled());
+                waiters.push(sender);
+            }
+            // either way the state is checked again
+            if let Either::Right(_) =
+                future::select(receiver, Delay::new(deadline - now)).await
+            {
+                trace!(
+                    "{}: timed out waiting for child {} to become {}",
+                    self.parent,
+                    self.name,
+                    target.to_string()
+                );
+            }
+        }
+    }
+
     /// close the bdev -- we have no means of determining if this succeeds
     pub(crate) fn close(&mut self) -> ChildState {
         trace!("{}: Closing child {}", self.parent, self.name);
 
-        if let Some(bdev) = self.bdev.as_ref() {
+        // a read only child never holds the claim, which then belongs to
+        // someone else sharing the bdev and is not ours to release, and
+        // neither does a child which has never been opened
+        if let Some(bdev) = self
+            .bdev
+            .as_ref()
+            .filter(|_| !self.read_only && self.desc.is_some())
+        {
             unsafe {
                 if !(*bdev.as_ptr()).internal.claim_module.is_null() {
                     spdk_bdev_module_release_bdev(bdev.as_ptr());
@@ -202,6 +883,13 @@ impl NexusChild {
             }
         }
 
+        self.label_cache.replace(None);
+        self.probed_label.replace(None);
+        self.label_status.set(LabelStatus::Unknown);
+
+        // the channel does not outlive the descriptors it belongs to
+        self.ch = std::ptr::null_mut();
+
         // just to be explicit
         let hdl = self.bdev_handle.take();
         let desc = self.desc.take();
@@ -209,8 +897,7 @@ impl NexusChild {
         drop(desc);
 
         // we leave the child structure around for when we want reopen it
-        self.state = ChildState::Closed;
-        self.state
+        self.set_state(ChildState::Closed)
     }
 
     /// create a new nexus child
@@ -219,17 +906,102 @@ impl NexusChild {
             name,
             bdev,
             parent,
+            parent_uuid: None,
             desc: None,
             ch: std::ptr::null_mut(),
             state: ChildState::Init,
             bdev_handle: None,
             repairing: false,
+            probe_successes: 0,
+            read_only: false,
+            label_cache: RefCell::new(None),
+            probed_label: RefCell::new(None),
+            label_status: Cell::new(LabelStatus::Unknown),
+            inflight: Cell::new(0),
+            quiesced: false,
+            read_preference: ReadPreference::default(),
+            reservation: Cell::new(None),
+            state_waiters: RefCell::new(Vec::new()),
+            dirty: RefCell::new(Vec::new()),
+            incremental: false,
+            latency: RefCell::new(LatencyHistogram::default()),
+        }
+    }
+
+    /// replace the bdev backing this child with an equivalent one and open
+    /// the child again. The child must be closed, such that the bdev is never
+    /// swapped under IO. If the new bdev cannot be opened, the old one is
+    /// put back.
+    pub(crate) fn replace_bdev(
+        &mut self,
+        new: Bdev,
+        parent_size: u64,
+        parent_block_size: u32,
+    ) -> Result<(), ChildError> {
+        if self.state != ChildState::Closed {
+            return Err(ChildError::ChildNotClosed {});
+        }
+
+        if let Some(old) = self.bdev.as_ref() {
+            if old.block_len() != new.block_len() {
+                return Err(ChildError::IncompatibleBlockSize {
+                    old: old.block_len(),
+                    new: new.block_len(),
+                });
+            }
+        }
+
+        info!(
+            "{}: replacing bdev of child {} with {}",
+            self.parent,
+            self.name,
+            new.name()
+        );
+
+        let old = self.bdev.replace(new);
+        if let Err(e) = self.open(parent_size, parent_block_size) {
+            self.bdev = old;
+            self.set_state(ChildState::Closed);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// return the product name of the bdev backing the child, which tells
+    /// what kind of device it is, e.g. "NVMe disk" or "AIO disk", or None if
+    /// there is no bdev
+    pub fn driver_name(&self) -> Option<String> {
+        self.bdev.as_ref().map(|bdev| bdev.product_name())
+    }
+
+    /// return the number of bytes available to the parent once the child is
+    /// labeled, or None if there is no bdev to determine it from
+    pub(crate) fn usable_size(&self) -> Option<u64> {
+        self.bdev.as_ref().map(|bdev| {
+            NexusLabel::usable_size(bdev.block_len(), bdev.num_blocks())
+        })
+    }
+
+    /// serve repeated label reads from memory until the child is closed or
+    /// the cache is disabled again. The data path is not affected.
+    pub(crate) fn enable_label_cache(&self) {
+        if self.label_cache.borrow().is_none() {
+            self.label_cache.replace(Some(LabelCache::default()));
         }
     }
 
-    /// destroy the child bdev
+    /// disable the label cache dropping anything cached so far
+    pub(crate) fn disable_label_cache(&self) {
+        self.label_cache.replace(None);
+    }
+
+    /// destroy the child bdev, closing the child first if needed, such as a
+    /// child which failed to open and was left in the Init or ConfigInvalid
+    /// state
     pub(crate) async fn destroy(&mut self) -> Result<(), BdevCreateDestroy> {
-        assert_eq!(self.state, ChildState::Closed);
+        if self.state != ChildState::Closed {
+            self.close();
+        }
         if let Some(_bdev) = &self.bdev {
             bdev_destroy(&self.name).await
         } else {
@@ -238,105 +1010,536 @@ impl NexusChild {
         }
     }
 
-    /// returns if a child can be written too
-    pub fn can_rw(&self) -> bool {
-        self.state == ChildState::Open || self.state == ChildState::Faulted
+    /// check that the child is still usable after a device event, such as a
+    /// hot unplug, by reading its first block. If the read fails in a way
+    /// which implicates the device, the now stale descriptors are dropped
+    /// and the child is closed. The resulting state is returned, which is
+    /// unchanged if the child was not open or the failure was transient.
+    /// A quiesced child is left alone, as it cannot be probed until resumed.
+    pub(crate) async fn reconcile(&mut self) -> Result<ChildState, ChildError> {
+        if !self.can_rw() || self.quiesced {
+            return Ok(self.state);
+        }
+
+        let mut buf = match self.bdev_handle.as_ref() {
+            Some(handle) => handle
+                .dma_malloc(handle.get_bdev().block_len() as usize)
+                .context(ProbeAlloc {})?,
+            None => {
+                warn!(
+                    "{}: child {} in state {:?} has no handle, closing it",
+                    self.parent, self.name, self.state
+                );
+                return Ok(self.close());
+            }
+        };
+
+        match self.read_at(0, &mut buf).await {
+            Ok(_) => Ok(self.state),
+            Err(ChildIoError::ReadError {
+                source, ..
+            }) if !source.should_fault() => {
+                warn!(
+                    "{}: transient failure probing child {}: {}",
+                    self.parent, self.name, source
+                );
+                Ok(self.state)
+            }
+            Err(e) => {
+                error!(
+                    "{}: child {} failed to respond, closing it: {}",
+                    self.parent, self.name, e
+                );
+                Ok(self.close())
+            }
+        }
     }
 
-    pub async fn probe_label(&mut self) -> Result<NexusLabel, ChildError> {
-        if !self.can_rw() {
-            info!(
-                "{}: Trying to read from closed child: {}",
-                self.parent, self.name
-            );
-            return Err(ChildError::ChildReadOnly {});
+    /// record that the given byte range of the nexus has been written
+    /// without reaching this child. This is a no-op unless the child is
+    /// faulted.
+    pub(crate) fn mark_dirty(&self, offset: u64, len: u64) {
+        if self.state == ChildState::Faulted {
+            self.add_dirty(offset, len);
         }
+    }
 
-        let bdev = self.bdev.as_ref();
-        let desc = self.bdev_handle.as_ref();
+    /// merge the byte range into the dirty region log
+    fn add_dirty(&self, offset: u64, len: u64) {
+        if len == 0 {
+            return;
+        }
 
-        if bdev.is_none() || desc.is_none() {
-            return Err(ChildError::ChildInvalid {});
+        let mut dirty = self.dirty.borrow_mut();
+        let (mut start, mut end) = (offset, offset + len);
+
+        // absorb all ranges which overlap or touch the new one
+        let first = dirty.iter().position(|&(_, e)| e >= start);
+        if let Some(first) = first {
+            let last = dirty[first ..]
+                .iter()
+                .position(|&(s, _)| s > end)
+                .map_or(dirty.len(), |i| first + i);
+            if first < last {
+                start = start.min(dirty[first].0);
+                end = end.max(dirty[last - 1].1);
+            }
+            dirty.splice(first .. last, std::iter::once((start, end)));
+        } else {
+            dirty.push((start, end));
         }
+    }
 
-        let bdev = bdev.unwrap();
-        let desc = desc.unwrap();
+    /// number of bytes written to the nexus which did not reach the child
+    /// while it was faulted. A child which never held the data, like one
+    /// that has just been added, needs a full rebuild regardless.
+    pub fn dirty_bytes(&self) -> u64 {
+        self.dirty.borrow().iter().map(|(s, e)| e - s).sum()
+    }
 
-        let block_size = bdev.block_len();
+    /// returns the dirty region log as sorted (start, end) byte ranges
+    pub(crate) fn dirty_ranges(&self) -> Vec<(u64, u64)> {
+        self.dirty.borrow().clone()
+    }
 
-        let primary = u64::from(block_size);
-        let secondary = bdev.num_blocks() - 1;
+    /// merge ranges of a persisted dirty region log into the current one,
+    /// whatever state the child is in, as it is not known yet at assembly
+    pub(crate) fn restore_dirty(&self, ranges: &[(u64, u64)]) {
+        ranges
+            .iter()
+            .filter(|(start, end)| end > start)
+            .for_each(|&(start, end)| self.add_dirty(start, end - start));
+    }
 
-        let mut buf = desc
-            .dma_malloc(block_size as usize)
-            .context(LabelAlloc {})?;
+    /// returns if a child can be written too
+    pub fn can_rw(&self) -> bool {
+        self.state == ChildState::Open || self.state == ChildState::Faulted
+    }
 
-        self.read_at(primary, &mut buf)
-            .await
-            .context(LabelRead {})?;
+    /// Returns a span carrying the parent, the child and, if known, the uuid
+    /// of the volume as fields. Operations on the child run within it, such
+    /// that everything logged on their behalf can be attributed to the
+    /// volume and child without interpolating them into each message.
+    pub fn child_span(&self) -> Span {
+        let span = debug_span!(
+            "child",
+            parent = %self.parent,
+            child = %self.name,
+            uuid = field::Empty
+        );
+        if let Some(uuid) = self.parent_uuid.as_ref() {
+            span.record("uuid", &field::display(uuid));
+        }
+        span
+    }
 
-        let mut label = GPTHeader::from_slice(buf.as_slice());
-        if label.is_err() {
-            warn!(
-                "{}: {}: The primary label is invalid!",
-                self.parent, self.name
-            );
-            self.read_at(secondary, &mut buf)
-                .await
-                .context(LabelRead {})?;
-            label = GPTHeader::from_slice(buf.as_slice());
+    /// read and validate the label of the child. Unless forced, the label
+    /// found by the previous probe is returned without doing any IO if the
+    /// child has not been written to since.
+    pub async fn probe_label(
+        &mut self,
+        force: bool,
+    ) -> Result<NexusLabel, ChildError> {
+        self.check_consistent()?;
+        if !force {
+            if let Some(label) = self.probed_label.borrow().as_ref() {
+                return Ok(label.clone());
+            }
         }
 
-        let label = match label {
-            Ok(label) => label,
-            Err(_) => return Err(ChildError::LabelInvalid {}),
-        };
+        let span = self.child_span();
+        let result = self.read_label().instrument(span).await;
+        self.label_status.set(LabelStatus::from(&result));
+        let label = result?;
+        self.probed_label.replace(Some(label.clone()));
+        Ok(label)
+    }
 
-        // determine number of blocks we need to read from the partition table
-        let num_blocks =
-            ((label.entry_size * label.num_entries) / block_size) + 1;
+    /// Classify the label scheme of the child from its first two blocks,
+    /// without validating the label. A legacy MBR is recognised by a
+    /// partition type other than the protective one, a GPT label by the
+    /// signature of its primary header or, if that is gone, a protective MBR.
+    /// Use probe_label() to find out whether a GPT label is actually valid.
+    pub async fn detect_label_type(&self) -> Result<LabelType, ChildIoError> {
+        let block_size = self.handle_bdev()?.block_len() as usize;
+        let blocks = self.read_blocks(0, 2).await?;
+        let (mbr, header) = blocks.split_at(block_size);
+
+        let pmbr = Pmbr::from_slice(mbr).ok();
+        if pmbr.as_ref().map_or(false, |p| !p.is_protective()) {
+            return Ok(LabelType::LegacyMbr);
+        }
+        if header.starts_with(&GPT_SIGNATURE) || pmbr.is_some() {
+            return Ok(LabelType::Gpt);
+        }
+        Ok(LabelType::Unknown)
+    }
+
+    /// returns what the last probe of the label found, see probe_label()
+    pub fn label_status(&self) -> LabelStatus {
+        self.label_status.get()
+    }
+
+    /// returns true if the last probe found the child to have no label or
+    /// an invalid one, such that a label must be written to it. A child
+    /// which has not been probed, or whose probe failed for other reasons
+    /// than its label, might well have a label and does not need one.
+    pub fn needs_label(&self) -> bool {
+        match self.label_status() {
+            LabelStatus::Missing | LabelStatus::Invalid => true,
+            LabelStatus::Unknown | LabelStatus::Valid => false,
+        }
+    }
+
+    /// Grow the label of the child after the device has been expanded to
+    /// new_num_blocks, such that the backup label is found in the last block
+    /// again and the space gained becomes usable. The backup label is written
+    /// at its new location before the primary header refers to it, and only
+    /// then is the old backup header wiped, so that a valid label is found on
+    /// the device at any time. The label is never shrunk.
+    pub async fn grow_label(
+        &mut self,
+        new_num_blocks: u64,
+    ) -> Result<NexusLabel, ChildError> {
+        let bdev = self.handle_bdev().context(LabelRead {})?;
+        if new_num_blocks > bdev.num_blocks() {
+            return Err(ChildError::DeviceNotGrown {
+                num_blocks: bdev.num_blocks(),
+                requested: new_num_blocks,
+            });
+        }
+
+        let block_size = u64::from(bdev.block_len());
+        let mut label = self.probe_label(true).await?;
+        let old_alt = label.primary.lba_alt;
+        label.grow(new_num_blocks).context(LabelGrow {})?;
+
+        info!(
+            "{}: growing the label of child {} from {} to {} blocks",
+            self.parent,
+            self.name,
+            old_alt + 1,
+            new_num_blocks
+        );
+
+        let backup =
+            label.backup_bytes(bdev.block_len()).context(LabelGrow {})?;
+        self.write_label_bytes(
+            (label.primary.lba_end + 1) * block_size,
+            &backup,
+        )
+        .await?;
+
+        let primary = label
+            .primary_bytes(bdev.block_len())
+            .context(LabelGrow {})?;
+        self.write_label_bytes(block_size, &primary).await?;
+
+        // the protective partition covers the whole of the device
+        let mut mbr = self.alloc_buf(block_size).context(LabelWrite {})?;
+        self.read_at(0, &mut mbr).await.context(LabelRead {})?;
+        if let Ok(mut pmbr) = Pmbr::from_slice(mbr.as_slice()) {
+            pmbr.set_num_sectors(Pmbr::protective_sectors(new_num_blocks));
+            pmbr.write_into(mbr.as_mut_slice()).context(LabelGrow {})?;
+            self.write_label_bytes(0, mbr.as_slice()).await?;
+        }
+
+        // the old backup header now lies within the usable range
+        let zero = vec![0u8; block_size as usize];
+        self.write_label_bytes(old_alt * block_size, &zero).await?;
+
+        self.probe_label(true).await
+    }
+
+    /// write the bytes to the child and flush it, such that the parts of a
+    /// label are durable in the order they are written
+    async fn write_label_bytes(
+        &self,
+        offset: u64,
+        bytes: &[u8],
+    ) -> Result<(), ChildError> {
+        let mut buf =
+            self.alloc_buf(bytes.len() as u64).context(LabelWrite {})?;
+        buf.as_mut_slice().copy_from_slice(bytes);
+        self.write_at(offset, &buf).await.context(LabelWrite {})?;
+        self.flush().await.context(LabelWrite {})
+    }
+
+    async fn read_label(&mut self) -> Result<NexusLabel, ChildError> {
+        if !self.can_rw() {
+            info!(
+                state = %self.state.to_string(),
+                "trying to read from closed child"
+            );
+            return Err(ChildError::ChildReadOnly {});
+        }
+
+        // an open child must always have both, so this is a bug rather than
+        // a child which is not ready yet
+        self.check_consistent()?;
+        let bdev = self.bdev.as_ref();
+        let desc = self.bdev_handle.as_ref();
+        if bdev.is_none() || desc.is_none() {
+            return Err(ChildError::ChildInvalid {});
+        }
+
+        let bdev = bdev.unwrap();
+        let desc = desc.unwrap();
+
+        let block_size = bdev.block_len();
+
+        // byte offsets of the primary header at LBA 1 and the backup header
+        // in the last LBA, in units of whatever the logical block size is
+        let primary = u64::from(block_size);
+        let secondary = (bdev.num_blocks() - 1) * u64::from(block_size);
 
         let mut buf = desc
-            .dma_malloc((num_blocks * block_size) as usize)
-            .context(PartitionTableAlloc {})?;
+            .dma_malloc(block_size as usize)
+            .context(LabelAlloc {})?;
 
-        self.read_at(label.lba_table * u64::from(block_size), &mut buf)
+        // a GPT label comes with a protective MBR, a disk without one might
+        // have been labeled by a tool which does not write it, but a disk
+        // with a partition of another type is partitioned the legacy way
+        self.read_label_at(0, &mut buf)
             .await
-            .context(PartitionTableRead {})?;
+            .context(LabelRead {})?;
+        let pmbr = Pmbr::from_slice(buf.as_slice()).ok();
+        if let Some(pmbr) = pmbr.as_ref() {
+            if !pmbr.is_protective() {
+                return Err(ChildError::LabelInvalid {
+                    reason: format!(
+                        "legacy MBR partition type {:#04x}, expected {:#04x}",
+                        pmbr.partition_type(),
+                        MBR_PROTECTIVE_TYPE
+                    ),
+                });
+            }
+        }
+
+        self.read_label_at(primary, &mut buf)
+            .await
+            .context(LabelRead {})?;
+
+        // the backup header, if the label is read from the primary header and
+        // the backup agrees with it
+        let mut backup = None;
+        let mut label = GPTHeader::from_slice(buf.as_slice());
+        if let Ok(primary_hdr) = label.as_ref() {
+            // when both headers are readable, they must agree on how the
+            // partition table is to be interpreted
+            self.read_label_at(secondary, &mut buf)
+                .await
+                .context(LabelRead {})?;
+            if let Ok(secondary_hdr) = GPTHeader::from_slice(buf.as_slice()) {
+                if !primary_hdr.agrees_with(&secondary_hdr) {
+                    warn!(
+                        primary = ?primary_hdr,
+                        backup = ?secondary_hdr,
+                        "primary and backup labels disagree"
+                    );
+                    return Err(ChildError::LabelInvalid {
+                        reason: "primary and backup labels disagree".into(),
+                    });
+                }
+                backup = Some(secondary_hdr);
+            }
+        } else {
+            warn!("the primary label is invalid");
+            self.read_label_at(secondary, &mut buf)
+                .await
+                .context(LabelRead {})?;
+            let backup = GPTHeader::from_slice(buf.as_slice());
+
+            // without a GPT signature in either place, there is no label
+            // rather than a damaged one
+            if let (
+                Err(LabelError::HeaderSize {}),
+                Err(LabelError::HeaderSize {}),
+            ) = (&label, &backup)
+            {
+                return Err(ChildError::LabelMissing {});
+            }
+            label = backup;
+        }
+
+        let label = match label {
+            Ok(label) => label,
+            Err(_) => {
+                return Err(ChildError::LabelInvalid {
+                    reason: "primary and backup labels are invalid".into(),
+                })
+            }
+        };
+
+        // the protective partition ends where the backup header lives
+        if let Some(pmbr) = pmbr {
+            if !Pmbr::is_consistent(pmbr.num_sectors(), label.lba_alt) {
+                return Err(ChildError::LabelInvalid {
+                    reason: format!(
+                        "protective MBR of {} sectors, backup header at LBA {}",
+                        pmbr.num_sectors(),
+                        label.lba_alt
+                    ),
+                });
+            }
+        }
 
         let mut partitions =
-            match GptEntry::from_slice(&buf.as_slice(), label.num_entries) {
-                Ok(parts) => parts,
-                Err(_) => return Err(ChildError::InvalidPartitionTable {}),
-            };
+            self.read_partition_table(desc, &label, block_size).await?;
 
+        // a corrupt primary table is recovered from the backup table, if
+        // that one is intact, and flagged such that it gets rewritten
+        let mut primary_table_corrupt = false;
         if GptEntry::checksum(&partitions) != label.table_crc {
-            return Err(ChildError::PartitionTableChecksum {});
+            let backup = match backup {
+                Some(backup) => backup,
+                None => return Err(ChildError::PartitionTableChecksum {}),
+            };
+            warn!("primary partition table is corrupt, using backup");
+            partitions =
+                self.read_partition_table(desc, &backup, block_size).await?;
+            if GptEntry::checksum(&partitions) != backup.table_crc {
+                return Err(ChildError::PartitionTableChecksum {});
+            }
+            primary_table_corrupt = true;
         }
 
         // some tools write 128 partition entries, even though only two are
         // created, in any case we are only ever interested in the first two
-        // partitions, so we drain the others.
-        let parts = partitions.drain(.. 2).collect::<Vec<_>>();
+        // partitions. The others are kept aside so that they survive when
+        // the label is written back.
+        let mut nl = NexusLabel::from_table(label, partitions);
+        nl.primary_table_corrupt = primary_table_corrupt;
+        if nl.partitions.len() < 2 {
+            return Err(ChildError::InvalidPartitionTable {});
+        }
 
-        let nl = NexusLabel {
-            primary: label,
-            partitions: parts,
-        };
+        // a partition must not overlap with the headers or partition tables
+        // or writes to it would clobber the label
+        if nl.partitions.iter().any(|p| {
+            p.ent_start < nl.primary.lba_start || p.ent_end > nl.primary.lba_end
+        }) {
+            return Err(ChildError::InvalidPartitionTable {});
+        }
+
+        // a label of another revision is used as is, but we let it be known
+        nl.revision_mismatch = nl.primary.revision_mismatch();
+        if nl.revision_mismatch {
+            warn!(
+                "{}: {}: label has GPT revision {:?} rather than {:?}",
+                self.parent, self.name, nl.primary.revision, GPT_REVISION
+            );
+        }
 
         Ok(nl)
     }
 
+    /// read and parse the partition table the header points to, without
+    /// validating its checksum
+    async fn read_partition_table(
+        &self,
+        desc: &BdevHandle,
+        hdr: &GPTHeader,
+        block_size: u32,
+    ) -> Result<Vec<GptEntry>, ChildError> {
+        // determine number of blocks we need to read from the partition table
+        let table_len = u64::from(hdr.entry_size) * u64::from(hdr.num_entries);
+        let num_blocks = (table_len / u64::from(block_size)) + 1;
+
+        let mut buf = desc
+            .dma_malloc((num_blocks * u64::from(block_size)) as usize)
+            .context(PartitionTableAlloc {})?;
+
+        self.check_read_len(&buf, table_len as usize)
+            .context(PartitionTableRead {})?;
+        self.read_label_at(hdr.lba_table * u64::from(block_size), &mut buf)
+            .await
+            .context(PartitionTableRead {})?;
+
+        match GptEntry::from_slice(&buf.as_slice(), hdr.num_entries) {
+            Ok(parts) => Ok(parts),
+            Err(_) => Err(ChildError::InvalidPartitionTable {}),
+        }
+    }
+
+    /// read part of the label, using the label cache when enabled
+    async fn read_label_at(
+        &self,
+        offset: u64,
+        buf: &mut DmaBuf,
+    ) -> Result<usize, ChildIoError> {
+        if let Some(cache) = self.label_cache.borrow().as_ref() {
+            if let Some(data) = cache.get(offset, buf.len()) {
+                buf.as_mut_slice().copy_from_slice(data);
+                return Ok(buf.len());
+            }
+        }
+
+        let len = self.read_at(offset, buf).await?;
+
+        if let Some(cache) = self.label_cache.borrow_mut().as_mut() {
+            cache.insert(offset, buf.as_slice());
+        }
+        Ok(len)
+    }
+
     /// write the contents of the buffer to this child
     pub async fn write_at(
         &self,
         offset: u64,
         buf: &DmaBuf,
     ) -> Result<usize, ChildIoError> {
+        let span = self.child_span();
+        let len = buf.len();
+        async {
+            let result = self.write_buf(offset, buf).await;
+            if let Err(
+                e @ ChildIoError::WriteError {
+                    ..
+                },
+            ) = &result
+            {
+                warn!(offset, len, error = %e, "write failed");
+            }
+            result
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn write_buf(
+        &self,
+        offset: u64,
+        buf: &DmaBuf,
+    ) -> Result<usize, ChildIoError> {
+        if self.read_only {
+            return Err(ChildIoError::ChildReadOnly {
+                name: self.name.clone(),
+            });
+        }
+        self.check_io_consistent()?;
+        self.check_quiesced()?;
+
+        if let Some(cache) = self.label_cache.borrow_mut().as_mut() {
+            cache.invalidate(offset, buf.len() as u64);
+        }
+        // the label is cheap to probe again compared to working out whether
+        // the write touched any part of it
+        self.probed_label.replace(None);
+        self.label_status.set(LabelStatus::Unknown);
+
         if let Some(desc) = self.bdev_handle.as_ref() {
-            Ok(desc.write_at(offset, buf).await.context(WriteError {
+            if buf.len() == 0 {
+                return Ok(0);
+            }
+            self.check_buf_len(desc, buf.len())?;
+            let _guard = InflightGuard::new(&self.inflight);
+            let start = Instant::now();
+            let len = desc.write_at(offset, buf).await.context(WriteError {
                 name: self.name.clone(),
-            })?)
+            })?;
+            self.record_latency(start.elapsed());
+            Ok(len)
         } else {
             Err(ChildIoError::InvalidDescriptor {
                 name: self.name.clone(),
@@ -350,14 +1553,673 @@ impl NexusChild {
         offset: u64,
         buf: &mut DmaBuf,
     ) -> Result<usize, ChildIoError> {
+        let span = self.child_span();
+        let len = buf.len();
+        async {
+            let result = self.read_buf(offset, buf).await;
+            if let Err(
+                e @ ChildIoError::ReadError {
+                    ..
+                },
+            ) = &result
+            {
+                warn!(offset, len, error = %e, "read failed");
+            }
+            result
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// read len bytes from this child into the given buffer, which must be
+    /// large enough to hold them. Unlike read_at(), which fills whatever
+    /// buffer it is given, a buffer too small for what the caller meant to
+    /// read is rejected with BufferTooSmall before any IO is issued.
+    pub async fn read_exact_at(
+        &self,
+        offset: u64,
+        buf: &mut DmaBuf,
+        len: usize,
+    ) -> Result<usize, ChildIoError> {
+        self.check_read_len(buf, len)?;
+        self.read_at(offset, buf).await
+    }
+
+    async fn read_buf(
+        &self,
+        offset: u64,
+        buf: &mut DmaBuf,
+    ) -> Result<usize, ChildIoError> {
+        self.check_io_consistent()?;
+        self.check_quiesced()?;
+        if let Some(desc) = self.bdev_handle.as_ref() {
+            if buf.len() == 0 {
+                return Ok(0);
+            }
+            self.check_buf_len(desc, buf.len())?;
+            let _guard = InflightGuard::new(&self.inflight);
+            let start = Instant::now();
+            let len = desc.read_at(offset, buf).await.context(ReadError {
+                name: self.name.clone(),
+            })?;
+            self.record_latency(start.elapsed());
+            Ok(len)
+        } else {
+            Err(ChildIoError::InvalidDescriptor {
+                name: self.name.clone(),
+            })
+        }
+    }
+
+    /// the buffer must be able to hold the number of bytes to be read
+    fn check_read_len(
+        &self,
+        buf: &DmaBuf,
+        need: usize,
+    ) -> Result<(), ChildIoError> {
+        if buf.len() < need {
+            return Err(ChildIoError::BufferTooSmall {
+                name: self.name.clone(),
+                have: buf.len(),
+                need,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_quiesced(&self) -> Result<(), ChildIoError> {
+        if self.quiesced {
+            return Err(ChildIoError::Quiesced {
+                name: self.name.clone(),
+            });
+        }
+        Ok(())
+    }
+
+    /// IO is done in whole blocks only, reject buffers which are not
+    fn check_buf_len(
+        &self,
+        desc: &BdevHandle,
+        len: usize,
+    ) -> Result<(), ChildIoError> {
+        let block_size = desc.get_bdev().block_len();
+        if len % block_size as usize != 0 {
+            return Err(ChildIoError::UnalignedBuffer {
+                name: self.name.clone(),
+                len,
+                block_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// copy len bytes at offset from the src child to the same offset of this
+    /// child, one buffer worth at a time. The range must be block aligned,
+    /// a multiple of the buffer size and fit within both children.
+    ///
+    /// With verify set, each chunk is read back after it has been written and
+    /// its crc32 compared to that of the source, which costs an extra read
+    /// but catches silent corruption.
+    pub async fn copy_from(
+        &self,
+        src: &NexusChild,
+        offset: u64,
+        len: u64,
+        buf: &mut DmaBuf,
+        verify: bool,
+    ) -> Result<(), ChildIoError> {
+        let src_bdev = src.handle_bdev()?;
+        let dst_bdev = self.handle_bdev()?;
+
+        if src_bdev.block_len() != dst_bdev.block_len() {
+            return Err(ChildIoError::BlockSizeMismatch {
+                src: src.name.clone(),
+                dst: self.name.clone(),
+            });
+        }
+
+        let block_size = u64::from(dst_bdev.block_len());
+        let chunk = buf.len() as u64;
+        let end = offset + len;
+
+        if chunk == 0
+            || chunk % block_size != 0
+            || offset % block_size != 0
+            || len % chunk != 0
+            || end > src_bdev.size_in_bytes()
+            || end > dst_bdev.size_in_bytes()
+        {
+            return Err(ChildIoError::InvalidCopyRange {
+                name: self.name.clone(),
+                offset,
+                len,
+            });
+        }
+
+        for offset in (offset .. end).step_by(chunk as usize) {
+            src.read_at(offset, buf).await?;
+            self.write_at(offset, buf).await?;
+
+            if verify {
+                let expected = crc32::checksum_ieee(buf.as_slice());
+                self.read_at(offset, buf).await?;
+                if crc32::checksum_ieee(buf.as_slice()) != expected {
+                    return Err(ChildIoError::CopyChecksum {
+                        name: self.name.clone(),
+                        offset,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// read total_len bytes at offset, chunk_size bytes at a time, passing
+    /// each chunk to f as it has been read. A single buffer is used for all
+    /// chunks such that whole devices can be scanned without allocating
+    /// memory for all of it. The offset and both lengths must be block
+    /// aligned, the last chunk is shorter if total_len is not a multiple of
+    /// chunk_size.
+    pub async fn stream_read(
+        &self,
+        offset: u64,
+        total_len: u64,
+        chunk_size: u64,
+        mut f: impl FnMut(&DmaBuf),
+    ) -> Result<(), ChildIoError> {
+        if total_len == 0 {
+            return Ok(());
+        }
+        let mut buf = self.stream_buf(offset, total_len, chunk_size)?;
+        let end = offset + total_len;
+
+        for offset in (offset .. end).step_by(chunk_size as usize) {
+            let len = std::cmp::min(chunk_size, end - offset);
+            if len != buf.len() as u64 {
+                buf = self.alloc_buf(len)?;
+            }
+            self.read_at(offset, &mut buf).await?;
+            f(&buf);
+        }
+        Ok(())
+    }
+
+    /// the counterpart of stream_read(), f fills the buffer with the data
+    /// for each chunk, which is then written to the child at its offset
+    pub async fn stream_write(
+        &self,
+        offset: u64,
+        total_len: u64,
+        chunk_size: u64,
+        mut f: impl FnMut(&mut DmaBuf),
+    ) -> Result<(), ChildIoError> {
+        if total_len == 0 {
+            return Ok(());
+        }
+        let mut buf = self.stream_buf(offset, total_len, chunk_size)?;
+        let end = offset + total_len;
+
+        for offset in (offset .. end).step_by(chunk_size as usize) {
+            let len = std::cmp::min(chunk_size, end - offset);
+            if len != buf.len() as u64 {
+                buf = self.alloc_buf(len)?;
+            }
+            f(&mut buf);
+            self.write_at(offset, &buf).await?;
+        }
+        Ok(())
+    }
+
+    /// validate the range to stream and allocate the buffer used for it
+    fn stream_buf(
+        &self,
+        offset: u64,
+        total_len: u64,
+        chunk_size: u64,
+    ) -> Result<DmaBuf, ChildIoError> {
+        let bdev = self.handle_bdev()?;
+        let block_size = u64::from(bdev.block_len());
+
+        if chunk_size == 0
+            || chunk_size % block_size != 0
+            || offset % block_size != 0
+            || total_len % block_size != 0
+            || offset + total_len > bdev.size_in_bytes()
+        {
+            return Err(ChildIoError::InvalidStreamRange {
+                name: self.name.clone(),
+                offset,
+                len: total_len,
+                chunk_size,
+            });
+        }
+
+        self.alloc_buf(std::cmp::min(chunk_size, total_len))
+    }
+
+    fn alloc_buf(&self, len: u64) -> Result<DmaBuf, ChildIoError> {
+        let desc = self.bdev_handle.as_ref().ok_or_else(|| {
+            ChildIoError::InvalidDescriptor {
+                name: self.name.clone(),
+            }
+        })?;
+        desc.dma_malloc(len as usize).context(BufferAlloc {
+            name: self.name.clone(),
+        })
+    }
+
+    /// return the extents, as (offset, length) in bytes, of this child which
+    /// have been written to. Only aio children, whose files are sparse, can
+    /// report this; all others fail with AllocationUnsupported.
+    pub fn allocated_ranges(&self) -> Result<Vec<(u64, u64)>, ChildIoError> {
+        let bdev = self.handle_bdev()?;
+        let aio = match nexus_parse_uri(&self.name) {
+            Ok(BdevType::Aio(aio)) => aio,
+            _ => {
+                return Err(ChildIoError::AllocationUnsupported {
+                    name: self.name.clone(),
+                    driver: bdev.driver(),
+                })
+            }
+        };
+
+        // the tail of the file which does not make up a whole block is not
+        // part of the bdev
+        let size = bdev.size_in_bytes();
+        let ranges = aio.allocated_ranges().context(AllocationQuery {
+            name: self.name.clone(),
+        })?;
+        Ok(ranges
+            .into_iter()
+            .filter(|(offset, _)| *offset < size)
+            .map(|(offset, len)| (offset, std::cmp::min(len, size - offset)))
+            .collect())
+    }
+
+    /// return the extents within start..end which must be copied to rebuild
+    /// another child from this one. If the allocated ranges are unknown the
+    /// whole range is assumed to be allocated. What is not copied is left as
+    /// it is on the other child, which reads as zeroes only if that child
+    /// has not been written to there either.
+    pub fn ranges_to_copy(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        match self.allocated_ranges() {
+            Ok(ranges) => ranges
+                .into_iter()
+                .filter_map(|(offset, len)| {
+                    let from = std::cmp::max(offset, start);
+                    let to = std::cmp::min(offset + len, end);
+                    if from < to {
+                        Some((from, to - from))
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+            Err(e) => {
+                debug!("{}: {}, copying all of it", self.name, e);
+                vec![(start, end - start)]
+            }
+        }
+    }
+
+    /// Fail with InconsistentState if the handle and the bdev of the child
+    /// do not go together: a handle without a bdev, a handle for a bdev other
+    /// than that of the child, or an open child without a handle. A bdev
+    /// without a handle is merely a child which is not open. In debug builds
+    /// this is considered a bug and panics, see assert_consistent().
+    pub(crate) fn check_consistent(&self) -> Result<(), ChildError> {
+        if self.is_consistent() {
+            return Ok(());
+        }
+
+        error!(
+            state = %self.state.to_string(),
+            has_handle = self.bdev_handle.is_some(),
+            has_bdev = self.bdev.is_some(),
+            "child bdev and handle are inconsistent"
+        );
+        self.assert_consistent();
+        Err(ChildError::InconsistentState {
+            state: self.state.to_string(),
+            has_handle: self.bdev_handle.is_some(),
+            has_bdev: self.bdev.is_some(),
+        })
+    }
+
+    fn is_consistent(&self) -> bool {
+        match (self.bdev.as_ref(), self.bdev_handle.as_ref()) {
+            (None, Some(_)) => false,
+            (Some(bdev), Some(hdl)) => hdl.get_bdev().name() == bdev.name(),
+            (_, None) => self.state != ChildState::Open,
+        }
+    }
+
+    /// panic in debug builds if the handle and the bdev of the child do not
+    /// go together, see check_consistent()
+    fn assert_consistent(&self) {
+        debug_assert!(
+            self.is_consistent(),
+            "{}: child {} in state {} has handle: {}, has bdev: {}",
+            self.parent,
+            self.name,
+            self.state.to_string(),
+            self.bdev_handle.is_some(),
+            self.bdev.is_some()
+        );
+    }
+
+    /// check_consistent() for the IO methods
+    fn check_io_consistent(&self) -> Result<(), ChildIoError> {
+        self.check_consistent()
+            .map_err(|_| ChildIoError::InconsistentState {
+                name: self.name.clone(),
+            })
+    }
+
+    /// return the bdev of the handle used for IO to this child
+    fn handle_bdev(&self) -> Result<Bdev, ChildIoError> {
+        match self.bdev_handle.as_ref() {
+            Some(desc) => Ok(desc.get_bdev()),
+            None => Err(ChildIoError::InvalidDescriptor {
+                name: self.name.clone(),
+            }),
+        }
+    }
+
+    /// flush this child device, making all completed writes durable
+    pub async fn flush(&self) -> Result<(), ChildIoError> {
+        self.check_io_consistent()?;
         if let Some(desc) = self.bdev_handle.as_ref() {
-            Ok(desc.read_at(offset, buf).await.context(ReadError {
+            let len = desc.get_bdev().size_in_bytes();
+            desc.flush(0, len).await.context(FlushError {
                 name: self.name.clone(),
-            })?)
+            })
         } else {
             Err(ChildIoError::InvalidDescriptor {
                 name: self.name.clone(),
             })
         }
     }
+
+    /// register the key with the device and acquire a persistent reservation
+    /// of the given type with it, fencing off other hosts. Only children
+    /// backed by NVMe namespaces support this, others fail with
+    /// ReservationUnsupported.
+    pub async fn reserve(
+        &self,
+        key: u64,
+        rtype: ReservationType,
+    ) -> Result<(), ChildIoError> {
+        let desc = self.reservation_handle()?;
+        let mut buf = self.reservation_buf(desc)?;
+
+        // register our key, replacing any key we might have registered before
+        buf.as_mut_slice()[8 .. 16].copy_from_slice(&key.to_le_bytes());
+        self.reservation_cmd(
+            desc,
+            SPDK_NVME_OPC_RESERVATION_REGISTER,
+            RESV_REGISTER_KEY | RESV_IGNORE_KEY,
+            &mut buf,
+        )
+        .await?;
+
+        buf.fill(0);
+        buf.as_mut_slice()[0 .. 8].copy_from_slice(&key.to_le_bytes());
+        self.reservation_cmd(
+            desc,
+            SPDK_NVME_OPC_RESERVATION_ACQUIRE,
+            (rtype as u32) << 8,
+            &mut buf,
+        )
+        .await?;
+
+        self.reservation.set(Some(rtype));
+        info!(
+            "{}: acquired {:?} reservation on {}",
+            self.parent, rtype, self.name
+        );
+        Ok(())
+    }
+
+    /// release the reservation acquired by reserve() with the same key, and
+    /// unregister the key from the device
+    pub async fn release(&self, key: u64) -> Result<(), ChildIoError> {
+        let desc = self.reservation_handle()?;
+        let mut buf = self.reservation_buf(desc)?;
+        buf.as_mut_slice()[0 .. 8].copy_from_slice(&key.to_le_bytes());
+
+        // the release action (RRELA) is 0, but the type must match the one
+        // of the reservation being released
+        if let Some(rtype) = self.reservation.get() {
+            self.reservation_cmd(
+                desc,
+                SPDK_NVME_OPC_RESERVATION_RELEASE,
+                (rtype as u32) << 8,
+                &mut buf,
+            )
+            .await?;
+            self.reservation.set(None);
+        }
+
+        self.reservation_cmd(
+            desc,
+            SPDK_NVME_OPC_RESERVATION_REGISTER,
+            RESV_UNREGISTER_KEY,
+            &mut buf,
+        )
+        .await?;
+
+        info!("{}: released reservation on {}", self.parent, self.name);
+        Ok(())
+    }
+
+    /// return the handle to issue reservation commands through
+    fn reservation_handle(&self) -> Result<&BdevHandle, ChildIoError> {
+        let desc = self.bdev_handle.as_ref().ok_or_else(|| {
+            ChildIoError::InvalidDescriptor {
+                name: self.name.clone(),
+            }
+        })?;
+        let bdev = desc.get_bdev();
+        if !bdev.io_type_supported(SPDK_BDEV_IO_TYPE_NVME_IO) {
+            return Err(ChildIoError::ReservationUnsupported {
+                name: self.name.clone(),
+                driver: bdev.driver(),
+            });
+        }
+        Ok(desc)
+    }
+
+    /// the data of the reservation commands is at most two keys
+    fn reservation_buf(
+        &self,
+        desc: &BdevHandle,
+    ) -> Result<DmaBuf, ChildIoError> {
+        let mut buf = desc.dma_malloc(16).context(BufferAlloc {
+            name: self.name.clone(),
+        })?;
+        buf.fill(0);
+        Ok(buf)
+    }
+
+    async fn reservation_cmd(
+        &self,
+        desc: &BdevHandle,
+        opcode: u32,
+        cdw10: u32,
+        buf: &mut DmaBuf,
+    ) -> Result<(), ChildIoError> {
+        desc.nvme_io_passthru(opcode as u8, cdw10, Some(buf))
+            .await
+            .context(ReservationError {
+                name: self.name.clone(),
+            })
+    }
+
+    /// read the blocks holding the label, i.e. the protective MBR and both
+    /// headers and partition tables, as they are on disk. As nothing is
+    /// interpreted, this succeeds regardless of whether the label is valid.
+    pub async fn dump_label_region(&self) -> Result<LabelBytes, ChildIoError> {
+        let bdev = self.handle_bdev()?;
+
+        let block_size = u64::from(bdev.block_len());
+        let num_blocks = bdev.num_blocks();
+        let table_blocks = std::cmp::max((1 << 14) / block_size, 1);
+
+        Ok(LabelBytes {
+            mbr: self.read_blocks(0, 1).await?,
+            primary_header: self.read_blocks(1, 1).await?,
+            primary_table: self.read_blocks(2, table_blocks).await?,
+            secondary_table: self
+                .read_blocks(num_blocks - 1 - table_blocks, table_blocks)
+                .await?,
+            secondary_header: self.read_blocks(num_blocks - 1, 1).await?,
+        })
+    }
+
+    /// returns true if the child serves IO, which is checked by reading its
+    /// first block
+    pub async fn probe_alive(&self) -> bool {
+        match self.read_blocks(0, 1).await {
+            Ok(_) => true,
+            Err(e) => {
+                debug!(
+                    "{}: child {} is not alive: {}",
+                    self.parent, self.name, e
+                );
+                false
+            }
+        }
+    }
+
+    /// read count blocks starting at the given block into an owned buffer
+    async fn read_blocks(
+        &self,
+        block: u64,
+        count: u64,
+    ) -> Result<Vec<u8>, ChildIoError> {
+        let block_size = u64::from(self.handle_bdev()?.block_len());
+        let len = count * block_size;
+        let mut buf = self.alloc_buf(len)?;
+        self.read_exact_at(block * block_size, &mut buf, len as usize)
+            .await?;
+        Ok(buf.as_slice().to_vec())
+    }
+}
+
+/// Probe the labels of the given children concurrently, with at most
+/// max_concurrent probes in flight, and return the results in the order of
+/// the children. Each probe only uses the handle and buffers of its own
+/// child. Unless forced, labels probed before are reused, see probe_label().
+pub(crate) async fn probe_labels(
+    children: &mut [NexusChild],
+    max_concurrent: usize,
+    force: bool,
+) -> Vec<Result<NexusLabel, ChildError>> {
+    stream::iter(children.iter_mut().map(|child| child.probe_label(force)))
+        .buffered(std::cmp::max(max_concurrent, 1))
+        .collect()
+        .await
+}
+
+/// Stages small writes to a child in memory, such that metadata can be built
+/// up piece by piece, and issues them as few block aligned writes as possible
+/// on commit(). Nothing is written to the child before that.
+pub struct ChildWriter<'a> {
+    child: &'a NexusChild,
+    /// staged writes keyed by their offset, these never overlap
+    staged: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<'a> ChildWriter<'a> {
+    pub fn new(child: &'a NexusChild) -> Self {
+        Self {
+            child,
+            staged: BTreeMap::new(),
+        }
+    }
+
+    /// stage data to be written at offset, which must not overlap with any
+    /// of the writes staged before
+    pub fn write(
+        &mut self,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), ChildIoError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let end = offset + data.len() as u64;
+        let overlaps_prev = self
+            .staged
+            .range(.. offset)
+            .next_back()
+            .map_or(false, |(o, d)| o + d.len() as u64 > offset);
+        let overlaps_next = self
+            .staged
+            .range(offset ..)
+            .next()
+            .map_or(false, |(o, _)| *o < end);
+
+        if overlaps_prev || overlaps_next {
+            return Err(ChildIoError::StagedOverlap {
+                name: self.child.name.clone(),
+                offset,
+                len: data.len() as u64,
+            });
+        }
+
+        self.staged.insert(offset, data.to_vec());
+        Ok(())
+    }
+
+    /// write out everything staged, merging writes which touch the same or
+    /// adjacent blocks. Blocks which are only partially covered are read
+    /// first such that the remainder is preserved. Returns the number of
+    /// writes issued.
+    pub async fn commit(self) -> Result<usize, ChildIoError> {
+        let bdev = self.child.handle_bdev()?;
+        let block_size = u64::from(bdev.block_len());
+        let align_down = |o: u64| o - o % block_size;
+        let align_up = |o: u64| align_down(o + block_size - 1);
+
+        // group the staged writes into extents of whole blocks
+        let mut extents: Vec<(u64, u64, Vec<(u64, Vec<u8>)>)> = Vec::new();
+        for (offset, data) in self.staged {
+            let start = align_down(offset);
+            let end = align_up(offset + data.len() as u64);
+            match extents.last_mut() {
+                Some(extent) if start <= extent.1 => {
+                    extent.1 = std::cmp::max(extent.1, end);
+                    extent.2.push((offset, data));
+                }
+                _ => extents.push((start, end, vec![(offset, data)])),
+            }
+        }
+
+        let mut writes = 0;
+        for (start, end, staged) in extents {
+            let mut buf = DmaBuf::new((end - start) as usize, bdev.alignment())
+                .context(BufferAlloc {
+                    name: self.child.name.clone(),
+                })?;
+
+            let covered: u64 = staged.iter().map(|(_, d)| d.len() as u64).sum();
+            if covered != end - start {
+                self.child.read_at(start, &mut buf).await?;
+            }
+
+            for (offset, data) in staged {
+                let pos = (offset - start) as usize;
+                buf.as_mut_slice()[pos .. pos + data.len()]
+                    .copy_from_slice(&data);
+            }
+
+            self.child.write_at(start, &buf).await?;
+            writes += 1;
+        }
+
+        Ok(writes)
+    }
 }
@@ -0,0 +1,362 @@
+//! Persistence of the dirty region logs of the children of a nexus, such
+//! that a rebuild after a restart only has to copy what has been written
+//! while a child was faulted rather than the whole of it.
+//!
+//! The logs of all children are stored together as a single record at the
+//! start of the meta partition of every open child, of which it may take up
+//! all but the last block, the key marker of an encrypted share (see
+//! ShareOptions::record_key). The record consists of a header block, holding
+//! a magic marker, the length and the crc32 of the payload, followed by the
+//! bincode encoded payload:
+//!
+//! ```text
+//! | magic (8) | payload len (8) | payload crc32 (4) | ... | payload ... |
+//! ```
+//!
+//! The payload carries the uuid of the nexus, such that a log left behind
+//! by another nexus on a reused device is ignored, and a sequence number,
+//! such that the most recent log wins when the children disagree.
+//!
+//! `set_dirty_log_interval` starts persisting the logs periodically, and
+//! `load_dirty_log` restores them when the nexus is opened. The children
+//! with a restored log are faulted, as they lack what has been written to
+//! their siblings, and those which were in sync with their siblings before
+//! they started missing writes are rebuilt incrementally, copying only the
+//! dirty regions. Writes since the log has last been persisted are not
+//! covered by it, so the interval bounds what such a rebuild can miss
+//! after a crash.
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use bincode::{deserialize, serialize, Error};
+use crc::crc32;
+use futures_timer::Delay;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+
+use crate::{
+    bdev::nexus::{
+        nexus_bdev::{nexus_lookup, Nexus, NexusState},
+        nexus_child::{ChildIoError, ChildState, NexusChild},
+    },
+    core::{DmaBuf, DmaError, Reactors},
+};
+
+/// marks the start of a persisted dirty region log
+const DIRTY_LOG_MAGIC: [u8; 8] = *b"MAYADLOG";
+
+/// size of the encoded header preceding the payload
+const DIRTY_LOG_HEADER_LEN: usize = 20;
+
+/// source of the generations of the loops persisting the logs, unique
+/// across all nexuses such that a loop never mistakes a recreated nexus for
+/// its own
+static DIRTY_LOG_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Snafu)]
+pub enum DirtyLogError {
+    #[snafu(display("Dirty region log serialization error"))]
+    SerializeLog { source: Error },
+    #[snafu(display("Dirty region log deserialization error"))]
+    DeserializeLog { source: Error },
+    #[snafu(display("Dirty region log crc mismatch"))]
+    LogCrcMismatch {},
+    #[snafu(display(
+        "Dirty region log of {} bytes exceeds the meta partition of {} bytes",
+        size,
+        max
+    ))]
+    LogTooLarge { size: u64, max: u64 },
+    #[snafu(display("The nexus {} has no meta partition", name))]
+    NoMetaPartition { name: String },
+    #[snafu(display("Failed to allocate dirty region log buffer"))]
+    LogAlloc { source: DmaError },
+    #[snafu(display("Failed to write dirty region log"))]
+    WriteLog { source: ChildIoError },
+    #[snafu(display("Failed to read dirty region log"))]
+    ReadLog { source: ChildIoError },
+}
+
+/// Precedes the payload of a persisted dirty region log
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+struct DirtyLogHeader {
+    /// DIRTY_LOG_MAGIC
+    magic: [u8; 8],
+    /// length of the payload in bytes
+    len: u64,
+    /// crc32 of the payload
+    crc: u32,
+}
+
+/// The dirty region logs of the children of a nexus as persisted
+#[derive(Debug, Deserialize, Serialize, PartialEq, Default, Clone)]
+pub struct DirtyLog {
+    /// uuid of the nexus the log belongs to
+    pub nexus_uuid: String,
+    /// increases with every log persisted by the nexus
+    pub sequence: u64,
+    /// the dirty (start, end) byte ranges by child name, only children with
+    /// dirty regions are included
+    pub children: Vec<(String, Vec<(u64, u64)>)>,
+    /// names of the children whose dirty regions are all they lack, see
+    /// NexusChild::incremental
+    pub incremental: Vec<String>,
+}
+
+impl DirtyLog {
+    /// encode the log, header included, padded to a multiple of block_size
+    pub fn to_bytes(&self, block_size: u64) -> Result<Vec<u8>, DirtyLogError> {
+        let payload = serialize(self).context(SerializeLog {})?;
+        let len = (DIRTY_LOG_HEADER_LEN + payload.len()) as u64;
+        let mut buf = vec![
+            0u8;
+            ((len + block_size - 1) / block_size * block_size)
+                as usize
+        ];
+
+        let header = serialize(&DirtyLogHeader {
+            magic: DIRTY_LOG_MAGIC,
+            len: payload.len() as u64,
+            crc: crc32::checksum_ieee(&payload),
+        })
+        .context(SerializeLog {})?;
+
+        buf[.. DIRTY_LOG_HEADER_LEN].copy_from_slice(&header);
+        buf[DIRTY_LOG_HEADER_LEN .. len as usize].copy_from_slice(&payload);
+        Ok(buf)
+    }
+
+    /// returns the header the buffer starts with, if any
+    fn header(buf: &[u8]) -> Option<DirtyLogHeader> {
+        if buf.len() < DIRTY_LOG_HEADER_LEN {
+            return None;
+        }
+        deserialize::<DirtyLogHeader>(&buf[.. DIRTY_LOG_HEADER_LEN])
+            .ok()
+            .filter(|h| h.magic == DIRTY_LOG_MAGIC)
+    }
+
+    /// returns the length of the encoded log, header included, or None if
+    /// the buffer does not start with a log
+    pub fn encoded_len(buf: &[u8]) -> Option<u64> {
+        Self::header(buf).map(|h| DIRTY_LOG_HEADER_LEN as u64 + h.len)
+    }
+
+    /// decode a log encoded by to_bytes(), None if the buffer does not start
+    /// with a log at all
+    pub fn from_bytes(buf: &[u8]) -> Result<Option<Self>, DirtyLogError> {
+        let header = match Self::header(buf) {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let len = DIRTY_LOG_HEADER_LEN as u64 + header.len;
+        if len > buf.len() as u64 {
+            return Err(DirtyLogError::LogCrcMismatch {});
+        }
+
+        let payload = &buf[DIRTY_LOG_HEADER_LEN .. len as usize];
+        if crc32::checksum_ieee(payload) != header.crc {
+            return Err(DirtyLogError::LogCrcMismatch {});
+        }
+        Ok(Some(deserialize(payload).context(DeserializeLog {})?))
+    }
+}
+
+impl Nexus {
+    /// returns the dirty region logs of the children
+    pub fn dirty_log(&self) -> DirtyLog {
+        DirtyLog {
+            nexus_uuid: self.bdev.uuid_as_string(),
+            sequence: self.dirty_log_sequence,
+            children: self
+                .children
+                .iter()
+                .filter(|c| c.dirty_bytes() > 0)
+                .map(|c| (c.name.clone(), c.dirty_ranges()))
+                .collect(),
+            incremental: self
+                .children
+                .iter()
+                .filter(|c| c.dirty_bytes() > 0 && c.incremental)
+                .map(|c| c.name.clone())
+                .collect(),
+        }
+    }
+
+    /// the number of blocks of the meta partition the log may take up, all
+    /// but the last one which holds the key marker
+    fn dirty_log_blocks(&self) -> u64 {
+        self.meta_ent_blocks - 1
+    }
+
+    /// write the dirty region logs to the meta partition of all open
+    /// children. An empty log is written as well, such that no stale log
+    /// outlives the rebuild of a child.
+    pub async fn persist_dirty_log(&mut self) -> Result<(), DirtyLogError> {
+        if self.meta_ent_blocks == 0 {
+            return Err(DirtyLogError::NoMetaPartition {
+                name: self.name.clone(),
+            });
+        }
+
+        self.dirty_log_sequence += 1;
+        let block_size = u64::from(self.bdev.block_len());
+        let bytes = self.dirty_log().to_bytes(block_size)?;
+        let max = self.dirty_log_blocks() * block_size;
+        if bytes.len() as u64 > max {
+            return Err(DirtyLogError::LogTooLarge {
+                size: bytes.len() as u64,
+                max,
+            });
+        }
+
+        let mut buf = DmaBuf::new(bytes.len(), self.bdev.alignment())
+            .context(LogAlloc {})?;
+        buf.as_mut_slice().copy_from_slice(&bytes);
+
+        let offset = self.meta_ent_offset * block_size;
+        for child in self.children_in_state(ChildState::Open) {
+            child.write_at(offset, &buf).await.context(WriteLog {})?;
+        }
+        Ok(())
+    }
+
+    /// read the dirty region logs from the meta partition of the open
+    /// children and restore the most recent one found, returning the number
+    /// of children with dirty regions. Children which cannot be read or hold
+    /// no valid log are skipped. While the nexus is being assembled, open
+    /// children with dirty regions are faulted, such that they are not read
+    /// from before they have been rebuilt.
+    pub async fn load_dirty_log(&mut self) -> Result<usize, DirtyLogError> {
+        if self.meta_ent_blocks == 0 {
+            return Err(DirtyLogError::NoMetaPartition {
+                name: self.name.clone(),
+            });
+        }
+
+        let block_size = u64::from(self.bdev.block_len());
+        let offset = self.meta_ent_offset * block_size;
+        let uuid = self.bdev.uuid_as_string();
+        let mut latest: Option<DirtyLog> = None;
+
+        for child in self.children_in_state(ChildState::Open) {
+            match self.read_dirty_log(child, offset).await {
+                Ok(Some(log)) if log.nexus_uuid == uuid => {
+                    if latest
+                        .as_ref()
+                        .map_or(true, |l| log.sequence > l.sequence)
+                    {
+                        latest = Some(log);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!(
+                    "{}: no dirty region log on child {}: {}",
+                    self.name, child.name, e
+                ),
+            }
+        }
+
+        let log = match latest {
+            Some(log) => log,
+            None => return Ok(0),
+        };
+
+        self.dirty_log_sequence = log.sequence;
+        let assembling = self.state == NexusState::Init;
+        let mut restored = 0;
+        for (name, ranges) in &log.children {
+            if let Some(child) =
+                self.children.iter_mut().find(|c| &c.name == name)
+            {
+                child.restore_dirty(ranges);
+                if assembling && child.state == ChildState::Open {
+                    child.set_state(ChildState::Faulted);
+                }
+                child.incremental = log.incremental.contains(name);
+                info!(
+                    "{}: child {} has {} dirty bytes",
+                    self.name,
+                    name,
+                    child.dirty_bytes()
+                );
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    /// read the log from the meta partition of the child, the header block
+    /// first to learn how much to read
+    async fn read_dirty_log(
+        &self,
+        child: &NexusChild,
+        offset: u64,
+    ) -> Result<Option<DirtyLog>, DirtyLogError> {
+        let block_size = u64::from(self.bdev.block_len());
+        let max = self.dirty_log_blocks() * block_size;
+
+        let mut buf = DmaBuf::new(block_size as usize, self.bdev.alignment())
+            .context(LogAlloc {})?;
+        child.read_at(offset, &mut buf).await.context(ReadLog {})?;
+
+        let len = match DirtyLog::encoded_len(buf.as_slice()) {
+            Some(len) if len > max => {
+                return Err(DirtyLogError::LogTooLarge {
+                    size: len,
+                    max,
+                })
+            }
+            Some(len) if len > block_size => len,
+            Some(_) => return DirtyLog::from_bytes(buf.as_slice()),
+            None => return Ok(None),
+        };
+
+        let len = (len + block_size - 1) / block_size * block_size;
+        let mut buf = DmaBuf::new(len as usize, self.bdev.alignment())
+            .context(LogAlloc {})?;
+        child.read_at(offset, &mut buf).await.context(ReadLog {})?;
+        DirtyLog::from_bytes(buf.as_slice())
+    }
+
+    /// persist the dirty region logs at the given interval, or stop doing so
+    /// with None. A loop is started on the current reactor which runs until
+    /// the interval is replaced or the nexus is gone.
+    pub fn set_dirty_log_interval(&mut self, interval: Option<Duration>) {
+        let generation = DIRTY_LOG_GENERATION.fetch_add(1, Ordering::Relaxed);
+        self.dirty_log_generation = generation;
+
+        let interval = match interval {
+            Some(interval) => interval,
+            None => {
+                info!("{}: dirty region log persistence disabled", self.name);
+                return;
+            }
+        };
+        info!(
+            "{}: persisting the dirty region log every {:?}",
+            self.name, interval
+        );
+
+        let name = self.name.clone();
+        Reactors::current().send_future(async move {
+            loop {
+                Delay::new(interval).await;
+                match nexus_lookup(&name) {
+                    Some(nexus) if nexus.dirty_log_generation == generation => {
+                        if let Err(e) = nexus.persist_dirty_log().await {
+                            warn!(
+                                "{}: failed to persist the dirty region log: {}",
+                                name, e
+                            );
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        });
+    }
+}
@@ -7,7 +7,16 @@ use snafu::Snafu;
 use crate::{
     core::Bdev,
     target::{
-        iscsi::{create_uri, share, target_name, unshare},
+        iscsi::{
+            create_uri,
+            get_uri,
+            share_with_lun_id,
+            target_lun_id,
+            target_name,
+            unshare,
+            ShareHandle,
+            ISCSI_QUEUE_DEPTH,
+        },
         Side,
     },
 };
@@ -22,17 +31,46 @@ pub enum NexusIscsiError {
         err
     ))]
     CreateTargetFailed { dev: String, err: String },
+    #[snafu(display(
+        "Existing iscsi target for bdev {} exports LUN{}, not LUN{}",
+        dev,
+        existing,
+        lun_id
+    ))]
+    LunIdMismatch {
+        dev: String,
+        lun_id: u32,
+        existing: u32,
+    },
 }
 
 /// Iscsi target representation.
 pub struct NexusIscsiTarget {
     bdev_name: String, /* logically we might store a spdk_iscsi_tgt_node here but ATM the bdev name is all we actually need */
+    /// role of the portal the target is exported through
+    side: Side,
+    lun_id: u32,
+    /// what has been created by share, None if an existing target is used
+    handle: Option<ShareHandle>,
 }
 
 impl NexusIscsiTarget {
     /// Allocate iscsi device for the bdev and start it.
     /// When the function returns the iscsi target is ready for IO.
-    pub fn create(bdev_name: &str) -> Result<Self, NexusIscsiError> {
+    /// The default queue depth is used unless one is given, likewise the bdev
+    /// is exported as LUN0 unless another LUN id is given. The target is
+    /// exported through the portal group of the given side.
+    ///
+    /// If the target of the bdev exists already, i.e. when retrying after a
+    /// failure further down the share path, it is taken as is rather than
+    /// attempting to create it again, provided it exports the bdev as the
+    /// LUN asked for.
+    pub fn create(
+        bdev_name: &str,
+        side: Side,
+        queue_depth: Option<u32>,
+        lun_id: Option<u32>,
+    ) -> Result<Self, NexusIscsiError> {
         let bdev = match Bdev::lookup_by_name(bdev_name) {
             None => {
                 return Err(NexusIscsiError::BdevNotFound {
@@ -42,9 +80,38 @@ impl NexusIscsiTarget {
             Some(bd) => bd,
         };
 
-        match share(bdev_name, &bdev, Side::Nexus) {
-            Ok(_) => Ok(Self {
+        if get_uri(side, bdev_name).is_some() {
+            let existing = target_lun_id(bdev_name).unwrap_or(0);
+            match lun_id {
+                Some(lun_id) if lun_id != existing => {
+                    return Err(NexusIscsiError::LunIdMismatch {
+                        dev: bdev_name.to_string(),
+                        lun_id,
+                        existing,
+                    })
+                }
+                _ => (),
+            }
+            info!("Using existing iscsi target for {}", bdev_name);
+            return Ok(Self {
+                bdev_name: bdev_name.to_string(),
+                side,
+                lun_id: existing,
+                handle: None,
+            });
+        }
+
+        let lun_id = lun_id.unwrap_or(0);
+
+        let queue_depth =
+            queue_depth.map_or(ISCSI_QUEUE_DEPTH, |depth| depth as i32);
+
+        match share_with_lun_id(bdev_name, &bdev, side, queue_depth, lun_id) {
+            Ok(handle) => Ok(Self {
                 bdev_name: bdev_name.to_string(),
+                side,
+                lun_id,
+                handle: Some(handle),
             }),
             Err(e) => Err(NexusIscsiError::CreateTargetFailed {
                 dev: bdev_name.to_string(),
@@ -55,7 +122,11 @@ impl NexusIscsiTarget {
 
     pub async fn destroy(self) {
         info!("Destroying iscsi frontend target");
-        match unshare(&self.bdev_name).await {
+        let result = match self.handle {
+            Some(handle) => handle.unshare().await,
+            None => unshare(&self.bdev_name).await,
+        };
+        match result {
             Ok(()) => (),
             Err(e) => {
                 error!("Failed to destroy iscsi frontend target, error {}", e)
@@ -63,8 +134,15 @@ impl NexusIscsiTarget {
         }
     }
 
+    /// The LUN id is only part of the URI when it is not LUN0, which is
+    /// what is assumed when it is left out.
     pub fn as_uri(&self) -> String {
-        create_uri(Side::Nexus, &target_name(&self.bdev_name))
+        let uri = create_uri(self.side, &target_name(&self.bdev_name));
+        if self.lun_id == 0 {
+            uri
+        } else {
+            format!("{}/{}", uri, self.lun_id)
+        }
     }
 }
 
@@ -55,6 +55,7 @@
 use std::{
     fmt::{self, Display},
     io::{Cursor, Seek, SeekFrom},
+    ops::Range,
     str::FromStr,
 };
 
@@ -83,76 +84,96 @@ pub enum LabelError {
     SerializeError { source: Error },
     #[snafu(display("Label deserialization error"))]
     DeserializeError { source: Error },
+    #[snafu(display("Failed to allocate label buffer"))]
+    LabelAlloc { source: DmaError },
     #[snafu(display("Write label error"))]
     WriteError { source: ChildIoError },
+    #[snafu(display("Flush label error"))]
+    FlushError { source: ChildIoError },
     #[snafu(display("Label probe error"))]
     ProbeError { source: ChildError },
     #[snafu(display("GPT header size is invalid"))]
     HeaderSize {},
     #[snafu(display("GPT label crc mismatch"))]
     CrcMismatch {},
+    #[snafu(display("Invalid protective MBR signature"))]
+    PmbrSignature {},
+    #[snafu(display("Protective MBR needs 512 bytes, got {}", len))]
+    PmbrSize { len: usize },
+    #[snafu(display(
+        "Invalid device geometry of {} blocks of {} bytes",
+        num_blocks,
+        block_size
+    ))]
+    InvalidGeometry { num_blocks: u64, block_size: u64 },
+    #[snafu(display(
+        "Invalid data partition size {}, at most {} bytes are usable",
+        size,
+        usable
+    ))]
+    DataPartitionSize { size: u64, usable: u64 },
+    #[snafu(display(
+        "Invalid alignment {}, it must be a power of two multiple of the block size {}",
+        alignment,
+        block_size
+    ))]
+    InvalidAlignment { alignment: u64, block_size: u64 },
+    #[snafu(display(
+        "Cannot grow the label of a device of {} blocks to {} blocks",
+        current,
+        requested
+    ))]
+    LabelShrink { current: u64, requested: u64 },
+    #[snafu(display(
+        "Partition {} does not fit the block size {} without moving its data",
+        index,
+        block_size
+    ))]
+    PartitionMisaligned { index: usize, block_size: u64 },
 }
 
 impl Nexus {
+    /// returns the names of all children
+    fn child_names(&self) -> Vec<String> {
+        self.children.iter().map(|c| c.name.clone()).collect()
+    }
+
     /// generate a new nexus label based on the nexus configuration. The meta
     /// partition is fixed in size and aligned to a 1MB boundary
     pub(crate) fn generate_label(&mut self) -> NexusLabel {
-        let mut hdr = GPTHeader::new(
+        let hdr = GPTHeader::new(
             self.bdev.block_len(),
             self.min_num_blocks(),
             Uuid::from_bytes(self.bdev.uuid().as_bytes()),
         );
-
-        let mut entries = vec![GptEntry::default(); hdr.num_entries as usize];
-
-        entries[0] = GptEntry {
-            ent_type: GptGuid::from_str("27663382-e5e6-11e9-81b4-ca5ca5ca5ca5")
-                .unwrap(),
-            ent_guid: GptGuid::new_random(),
-            // 1MB aligned
-            ent_start: hdr.lba_start,
-            // 4MB
-            ent_end: hdr.lba_start
-                + u64::from((4 << 20) / self.bdev.block_len())
-                - 1,
-            ent_attr: 0,
-            ent_name: GptName {
-                name: "MayaMeta".into(),
-            },
-        };
-
-        entries[1] = GptEntry {
-            ent_type: GptGuid::from_str("27663382-e5e6-11e9-81b4-ca5ca5ca5ca5")
-                .unwrap(),
-            ent_guid: GptGuid::new_random(),
-            ent_start: entries[0].ent_end + 1,
-            ent_end: hdr.lba_end,
-            ent_attr: 0,
-            ent_name: GptName {
-                name: "MayaData".into(),
-            },
-        };
-
-        hdr.table_crc = GptEntry::checksum(&entries);
-
-        NexusLabel {
-            primary: hdr,
-            partitions: entries,
-        }
+        // the data partition takes up everything after the meta partition
+        let data_start = NexusLabel::data_start(&hdr, self.bdev.block_len());
+        NexusLabel::with_partitions(
+            hdr,
+            self.bdev.block_len(),
+            data_start,
+            hdr.lba_end + 1 - data_start,
+        )
     }
 
-    /// write the protective MBR to all children.
+    /// write the protective MBR to all children. It covers as many blocks as
+    /// the label does, which is the size of the smallest child, see
+    /// generate_label().
     pub async fn write_pmbr(&mut self) -> Result<(), LabelError> {
+        let all = self.child_names();
+        self.write_pmbr_to(&all).await
+    }
+
+    /// same as write_pmbr() but only to the named children
+    pub(crate) async fn write_pmbr_to(
+        &mut self,
+        names: &[String],
+    ) -> Result<(), LabelError> {
         let mut pmbr = Pmbr::default();
         let mut buf =
             DmaBuf::new(self.bdev.block_len() as usize, self.bdev.alignment())
                 .context(WritePmbrAlloc {})?;
 
-        // the max size with MBR is 2GB, if we are smaller however, we must
-        // ensure that we reflect that in the MBR as well even though its not
-        // used.
-
-        let size = self.bdev.num_blocks() * self.bdev.block_len() as u64;
         pmbr.entries[0].attributes = 0x00;
         //
         pmbr.entries[0].chs_start = [0x00, 0x02, 0x00];
@@ -161,39 +182,58 @@ impl Nexus {
         // this indicated that we are "protective MBR", saying we use all
         // use all storage on this device.
 
-        pmbr.entries[0].ent_type = 0xee;
-        pmbr.entries[0].num_sectors = if size < u32::max_value().into() {
-            size as u32
-        } else {
-            u32::max_value()
-        };
+        pmbr.entries[0].ent_type = MBR_PROTECTIVE_TYPE;
+        pmbr.entries[0].lba_start = 1;
+        pmbr.entries[0].num_sectors =
+            Pmbr::protective_sectors(self.min_num_blocks());
 
         pmbr.signature = [0x55, 0xaa];
 
-        let mut writer = Cursor::new(buf.as_mut_slice());
-        // we seek 440 into the buffer here, this makes serialisation a little
-        // easier.
-
-        writer.seek(SeekFrom::Start(440)).unwrap();
-        serialize_into(&mut writer, &pmbr).context(SerializeError {})?;
+        // the remainder of the block is left zeroed
+        buf.fill(0);
+        pmbr.write_into(buf.as_mut_slice())?;
 
-        for child in &mut self.children {
+        for child in
+            self.children.iter_mut().filter(|c| names.contains(&c.name))
+        {
             child.write_at(0, &buf).await.context(WriteError {})?;
         }
 
         Ok(())
     }
 
-    /// write the gpt label to all the children.
+    /// write the gpt label to all the children. With WriteOrder::Strict the
+    /// partition table is written and flushed before the header referring to
+    /// it, such that a crash never leaves a durable header pointing at a
+    /// partially written table. The children are not probed, as the half of
+    /// the label not written yet may still disagree, see probe_labels_of().
     pub async fn write_label(
         &mut self,
         buf: &mut DmaBuf,
         label: &mut NexusLabel,
         primary: bool,
+        order: WriteOrder,
+    ) -> Result<(), LabelError> {
+        let all = self.child_names();
+        self.write_label_to(buf, label, primary, order, &all).await
+    }
+
+    /// same as write_label() but only to the named children
+    pub(crate) async fn write_label_to(
+        &mut self,
+        buf: &mut DmaBuf,
+        label: &mut NexusLabel,
+        primary: bool,
+        order: WriteOrder,
+        names: &[String],
     ) -> Result<(), LabelError> {
         let blk_size = self.bdev.block_len();
+        let len = buf.len();
         let mut writer = Cursor::new(buf.as_mut_slice());
-        if primary {
+
+        // the offset on the device the buffer is written to, and the regions
+        // within the buffer that hold the header and the partition table
+        let (offset, header, table) = if primary {
             label.primary.checksum();
 
             serialize_into(&mut writer, &label.primary)
@@ -201,24 +241,22 @@ impl Nexus {
 
             writer.seek(SeekFrom::Start(u64::from(blk_size))).unwrap();
 
-            for p in &label.partitions {
+            for p in label.partition_table() {
                 serialize_into(&mut writer, &p).context(SerializeError {})?;
             }
 
-            for child in &mut self.children {
-                child
-                    .write_at(u64::from(blk_size), &buf)
-                    .await
-                    .context(WriteError {})?;
-                child.probe_label().await.context(ProbeError {})?;
-            }
+            (
+                u64::from(blk_size),
+                0 .. blk_size as usize,
+                blk_size as usize .. len,
+            )
         } else {
             // now, write the backup label
             writer.seek(SeekFrom::Start(0)).unwrap();
             let mut backup = label.primary.to_backup();
             backup.checksum();
 
-            for p in &label.partitions {
+            for p in label.partition_table() {
                 serialize_into(&mut writer, &p).context(SerializeError {})?;
             }
 
@@ -226,17 +264,123 @@ impl Nexus {
 
             serialize_into(&mut writer, &backup).context(SerializeError {})?;
 
-            for child in &mut self.children {
-                child
-                    .write_at(u64::from(blk_size) * (backup.lba_end + 1), &buf)
-                    .await
-                    .context(WriteError {})?;
-                child.probe_label().await.context(ProbeError {})?;
+            (
+                u64::from(blk_size) * (backup.lba_end + 1),
+                (1 << 14) .. (1 << 14) + blk_size as usize,
+                0 .. (1 << 14),
+            )
+        };
+
+        match order {
+            WriteOrder::Relaxed => {
+                for child in
+                    self.children.iter_mut().filter(|c| names.contains(&c.name))
+                {
+                    child
+                        .write_at(offset, &buf)
+                        .await
+                        .context(WriteError {})?;
+                }
+            }
+            WriteOrder::Strict => {
+                let table_buf = self.label_region(buf, &table)?;
+                let header_buf = self.label_region(buf, &header)?;
+
+                for child in
+                    self.children.iter_mut().filter(|c| names.contains(&c.name))
+                {
+                    child
+                        .write_at(offset + table.start as u64, &table_buf)
+                        .await
+                        .context(WriteError {})?;
+                    child.flush().await.context(FlushError {})?;
+                    child
+                        .write_at(offset + header.start as u64, &header_buf)
+                        .await
+                        .context(WriteError {})?;
+                    child.flush().await.context(FlushError {})?;
+                }
             }
         }
 
         Ok(())
     }
+
+    /// write only the primary header and partition table to all children and
+    /// flush them, leaving the backup untouched. Used to repair a damaged
+    /// primary label.
+    pub async fn write_primary_label(
+        &mut self,
+        label: &mut NexusLabel,
+    ) -> Result<(), LabelError> {
+        let mut buf = self.label_buf()?;
+        self.write_label(&mut buf, label, true, WriteOrder::Strict)
+            .await?;
+        let all = self.child_names();
+        self.probe_labels_of(&all).await
+    }
+
+    /// write only the backup partition table and header to all children and
+    /// flush them, leaving the primary untouched. Used to repair a damaged
+    /// backup label.
+    pub async fn write_secondary_label(
+        &mut self,
+        label: &mut NexusLabel,
+    ) -> Result<(), LabelError> {
+        let mut buf = self.label_buf()?;
+        self.write_label(&mut buf, label, false, WriteOrder::Strict)
+            .await?;
+        let all = self.child_names();
+        self.probe_labels_of(&all).await
+    }
+
+    /// probe the labels of the named children afresh, which fails unless
+    /// both halves of their label are valid and agree with each other. Done
+    /// once the whole label has been written.
+    pub(crate) async fn probe_labels_of(
+        &mut self,
+        names: &[String],
+    ) -> Result<(), LabelError> {
+        for child in
+            self.children.iter_mut().filter(|c| names.contains(&c.name))
+        {
+            child.probe_label(true).await.context(ProbeError {})?;
+        }
+        Ok(())
+    }
+
+    /// allocate a buffer large enough to hold a header and partition table
+    fn label_buf(&self) -> Result<DmaBuf, LabelError> {
+        let blk_size = self.bdev.block_len();
+        DmaBuf::new(
+            (blk_size * (((1 << 14) / blk_size) + 1)) as usize,
+            self.bdev.alignment(),
+        )
+        .context(LabelAlloc {})
+    }
+
+    /// copy a region of the label buffer into a buffer of its own, so that it
+    /// can be written separately
+    fn label_region(
+        &self,
+        buf: &DmaBuf,
+        region: &Range<usize>,
+    ) -> Result<DmaBuf, LabelError> {
+        let mut out = DmaBuf::new(region.len(), self.bdev.alignment())
+            .context(LabelAlloc {})?;
+        out.as_mut_slice()
+            .copy_from_slice(&buf.as_slice()[region.clone()]);
+        Ok(out)
+    }
+}
+
+/// The order in which the parts of a label are made durable
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteOrder {
+    /// header and partition table are written with a single IO
+    Relaxed,
+    /// the partition table is written and flushed before its header
+    Strict,
 }
 
 #[derive(Debug, Deserialize, PartialEq, Default, Serialize, Clone, Copy)]
@@ -324,16 +468,24 @@ pub struct GPTHeader {
     pub table_crc: u32,
 }
 
+/// signature a GPT header starts with, "EFI PART"
+pub const GPT_SIGNATURE: [u8; 8] =
+    [0x45, 0x46, 0x49, 0x20, 0x50, 0x41, 0x52, 0x54];
+
+/// GPT revision written by us (1.0), which is what all versions of the UEFI
+/// spec up til 2.17 define
+pub const GPT_REVISION: [u8; 4] = [0x00, 0x00, 0x01, 0x00];
+
 impl GPTHeader {
     /// converts a slice into a gpt header and verifies the validity of the data
+    ///
+    /// The revision is not validated as a header of another revision may still
+    /// be usable, see revision_mismatch().
     pub fn from_slice(slice: &[u8]) -> Result<GPTHeader, LabelError> {
         let mut reader = Cursor::new(slice);
         let mut gpt: GPTHeader = deserialize_from(&mut reader).unwrap();
 
-        if gpt.header_size != 92
-            || gpt.signature != [0x45, 0x46, 0x49, 0x20, 0x50, 0x41, 0x52, 0x54]
-            || gpt.revision != [0x00, 0x00, 0x01, 0x00]
-        {
+        if gpt.header_size != 92 || gpt.signature != GPT_SIGNATURE {
             return Err(LabelError::HeaderSize {});
         }
 
@@ -353,6 +505,24 @@ impl GPTHeader {
         Ok(gpt)
     }
 
+    /// returns true if the header has been written with a revision other than
+    /// the one we write
+    pub fn revision_mismatch(&self) -> bool {
+        self.revision != GPT_REVISION
+    }
+
+    /// returns true if both headers describe the same partition table, such
+    /// that it is interpreted the same way no matter which of the two is
+    /// used. Any difference means one of them is corrupt.
+    pub fn agrees_with(&self, other: &GPTHeader) -> bool {
+        self.guid == other.guid
+            && self.lba_start == other.lba_start
+            && self.lba_end == other.lba_end
+            && self.table_crc == other.table_crc
+            && self.entry_size == other.entry_size
+            && self.num_entries == other.num_entries
+    }
+
     /// checksum the header with the checksum field itself set 0
     pub fn checksum(&mut self) -> u32 {
         self.self_checksum = 0;
@@ -363,8 +533,8 @@ impl GPTHeader {
     pub fn new(blk_size: u32, num_blocks: u64, guid: uuid::Uuid) -> Self {
         let fields = guid.as_fields();
         GPTHeader {
-            signature: [0x45, 0x46, 0x49, 0x20, 0x50, 0x41, 0x52, 0x54],
-            revision: [0x00, 0x00, 0x01, 0x00],
+            signature: GPT_SIGNATURE,
+            revision: GPT_REVISION,
             header_size: 92,
             self_checksum: 0,
             reserved: [0; 4],
@@ -427,6 +597,13 @@ impl GptEntry {
         Ok(part_vec)
     }
 
+    /// returns the entries which are in use, that is, those with a type
+    /// GUID other than all zeroes, skipping the empty entries tools pad the
+    /// partition table with
+    pub fn non_empty(entries: &[GptEntry]) -> impl Iterator<Item = &GptEntry> {
+        entries.iter().filter(|e| e.ent_type != GptGuid::default())
+    }
+
     /// calculate the checksum over the partitions table
     pub fn checksum(parts: &[GptEntry]) -> u32 {
         let mut digest = crc32::Digest::new(crc32::IEEE);
@@ -448,9 +625,266 @@ pub struct NexusLabel {
     pub primary: GPTHeader,
     /// Vector of GPT entries where the first element is considered to be ours
     pub partitions: Vec<GptEntry>,
+    /// the remaining entries of the partition table, which are not ours but
+    /// are written back as they were, such that rewriting the label of a
+    /// disk does not destroy partitions created by others
+    pub extra_partitions: Vec<GptEntry>,
+    /// the label has been written with a GPT revision other than ours
+    pub revision_mismatch: bool,
+    /// the primary partition table is corrupt and the partitions have been
+    /// read from the backup table instead, the primary table must be
+    /// rewritten
+    pub primary_table_corrupt: bool,
 }
 
 impl NexusLabel {
+    /// create a label from the header and the full partition table, the
+    /// first two entries of which are the meta and data partitions
+    pub fn from_table(primary: GPTHeader, mut table: Vec<GptEntry>) -> Self {
+        let extra_partitions = table.split_off(std::cmp::min(2, table.len()));
+        NexusLabel {
+            primary,
+            partitions: table,
+            extra_partitions,
+            revision_mismatch: false,
+            primary_table_corrupt: false,
+        }
+    }
+
+    /// Create the label of a new device with the given geometry, with a data
+    /// partition of data_partition_size bytes following the meta partition.
+    /// The label gets a random GUID and is checksummed, ready to be written.
+    ///
+    /// If an alignment in bytes is given, the start of the data partition is
+    /// rounded up to it, leaving the blocks in between unused, see
+    /// reserved_ranges(). Otherwise the data partition immediately follows
+    /// the meta partition.
+    pub fn new_for_device(
+        num_blocks: u64,
+        block_size: u64,
+        data_partition_size: u64,
+        alignment: Option<u64>,
+    ) -> Result<NexusLabel, LabelError> {
+        if block_size < 512
+            || block_size > (1 << 14)
+            || !block_size.is_power_of_two()
+            || Self::usable_size(block_size as u32, num_blocks) == 0
+        {
+            return Err(LabelError::InvalidGeometry {
+                num_blocks,
+                block_size,
+            });
+        }
+
+        if let Some(alignment) = alignment {
+            if alignment < block_size || !alignment.is_power_of_two() {
+                return Err(LabelError::InvalidAlignment {
+                    alignment,
+                    block_size,
+                });
+            }
+        }
+
+        let hdr = GPTHeader::new(block_size as u32, num_blocks, Uuid::new_v4());
+        let data_start = Self::data_start(&hdr, block_size as u32);
+        let aligned_start = match alignment {
+            Some(alignment) => {
+                let blocks = alignment / block_size;
+                (data_start + blocks - 1) / blocks * blocks
+            }
+            None => data_start,
+        };
+
+        // whatever is skipped to align the data partition is not usable
+        let usable = Self::usable_size(block_size as u32, num_blocks)
+            .saturating_sub((aligned_start - data_start) * block_size);
+        if data_partition_size == 0
+            || data_partition_size % block_size != 0
+            || data_partition_size > usable
+        {
+            return Err(LabelError::DataPartitionSize {
+                size: data_partition_size,
+                usable,
+            });
+        }
+
+        let mut label = Self::with_partitions(
+            hdr,
+            block_size as u32,
+            aligned_start,
+            data_partition_size / block_size,
+        );
+        label.primary.checksum();
+        Ok(label)
+    }
+
+    /// returns the first lba of the data partition, which follows the 1MB
+    /// aligned meta partition of 4MB
+    fn data_start(hdr: &GPTHeader, block_size: u32) -> u64 {
+        hdr.lba_start + u64::from((4 << 20) / block_size)
+    }
+
+    /// create a label with our meta and data partitions, the latter starting
+    /// at data_start, which must not be before data_start(), and being
+    /// data_blocks in size, and the partition table checksummed
+    fn with_partitions(
+        mut hdr: GPTHeader,
+        block_size: u32,
+        data_start: u64,
+        data_blocks: u64,
+    ) -> Self {
+        let mut entries = vec![GptEntry::default(); hdr.num_entries as usize];
+        let meta_end = Self::data_start(&hdr, block_size) - 1;
+
+        entries[0] = GptEntry {
+            ent_type: GptGuid::from_str("27663382-e5e6-11e9-81b4-ca5ca5ca5ca5")
+                .unwrap(),
+            ent_guid: GptGuid::new_random(),
+            // 1MB aligned
+            ent_start: hdr.lba_start,
+            // 4MB
+            ent_end: meta_end,
+            ent_attr: 0,
+            ent_name: GptName {
+                name: "MayaMeta".into(),
+            },
+        };
+
+        entries[1] = GptEntry {
+            ent_type: GptGuid::from_str("27663382-e5e6-11e9-81b4-ca5ca5ca5ca5")
+                .unwrap(),
+            ent_guid: GptGuid::new_random(),
+            ent_start: data_start,
+            ent_end: data_start + data_blocks - 1,
+            ent_attr: 0,
+            ent_name: GptName {
+                name: "MayaData".into(),
+            },
+        };
+
+        hdr.table_crc = GptEntry::checksum(&entries);
+
+        NexusLabel::from_table(hdr, entries)
+    }
+
+    /// returns the full partition table, in on disk order
+    pub fn partition_table(&self) -> impl Iterator<Item = &GptEntry> {
+        self.partitions.iter().chain(self.extra_partitions.iter())
+    }
+
+    /// returns the number of bytes available to the data partition of a
+    /// device with the given geometry, that is, what remains after the
+    /// label and the meta partition have been accounted for.
+    pub(crate) fn usable_size(block_size: u32, num_blocks: u64) -> u64 {
+        let block_size = u64::from(block_size);
+        // 1MB aligned start followed by the 4MB meta partition
+        let data_start = (1 << 20) / block_size + (4 << 20) / block_size;
+        // the backup partition table and header live at the end
+        let data_end = num_blocks.saturating_sub((1 << 14) / block_size + 2);
+        data_end.saturating_sub(data_start) * block_size
+    }
+
+    /// returns the (offset, length) in bytes of the regions reserved for the
+    /// label, in on disk order: the protective MBR, the primary header, the
+    /// primary partition table, the padding between the meta and data
+    /// partitions if the latter has been aligned, the backup partition table
+    /// and the backup header. Nothing but the label may be written to these.
+    pub fn reserved_ranges(&self, block_size: u32) -> Vec<(u64, u64)> {
+        let block_size = u64::from(block_size);
+        let table_len = u64::from(self.primary.num_entries)
+            * u64::from(self.primary.entry_size);
+        // the tables take up whole blocks
+        let table_blocks = (table_len + block_size - 1) / block_size;
+
+        let mut ranges = vec![
+            (0, block_size),
+            (self.primary.lba_self * block_size, block_size),
+            (
+                (self.primary.lba_self + 1) * block_size,
+                table_blocks * block_size,
+            ),
+        ];
+
+        if let [meta, data] = &self.partitions[..] {
+            if data.ent_start > meta.ent_end + 1 {
+                ranges.push((
+                    (meta.ent_end + 1) * block_size,
+                    (data.ent_start - meta.ent_end - 1) * block_size,
+                ));
+            }
+        }
+
+        ranges.push((
+            (self.primary.lba_end + 1) * block_size,
+            table_blocks * block_size,
+        ));
+        ranges.push((self.primary.lba_alt * block_size, block_size));
+        ranges
+    }
+
+    /// returns the differences between this label, as found on disk, and
+    /// the desired label, field by field. Only our own partitions are
+    /// compared, as the others are written back unmodified anyway. No
+    /// differences means there is no need to rewrite the label.
+    pub fn diff(&self, desired: &NexusLabel) -> Vec<LabelDiff> {
+        let mut diffs = Vec::new();
+
+        if self.primary.guid != desired.primary.guid {
+            diffs.push(LabelDiff::DiskGuid {
+                current: self.primary.guid,
+                desired: desired.primary.guid,
+            });
+        }
+
+        let current = (self.primary.lba_start, self.primary.lba_end);
+        let wanted = (desired.primary.lba_start, desired.primary.lba_end);
+        if current != wanted {
+            diffs.push(LabelDiff::UsableRange {
+                current,
+                desired: wanted,
+            });
+        }
+
+        if self.partitions.len() != desired.partitions.len() {
+            diffs.push(LabelDiff::PartitionCount {
+                current: self.partitions.len(),
+                desired: desired.partitions.len(),
+            });
+        }
+
+        for (index, (have, want)) in self
+            .partitions
+            .iter()
+            .zip(desired.partitions.iter())
+            .enumerate()
+        {
+            if have.ent_start != want.ent_start || have.ent_end != want.ent_end
+            {
+                diffs.push(LabelDiff::PartitionRange {
+                    index,
+                    current: (have.ent_start, have.ent_end),
+                    desired: (want.ent_start, want.ent_end),
+                });
+            }
+            if have.ent_type != want.ent_type {
+                diffs.push(LabelDiff::PartitionType {
+                    index,
+                    current: have.ent_type,
+                    desired: want.ent_type,
+                });
+            }
+            if have.ent_guid != want.ent_guid {
+                diffs.push(LabelDiff::PartitionGuid {
+                    index,
+                    current: have.ent_guid,
+                    desired: want.ent_guid,
+                });
+            }
+        }
+
+        diffs
+    }
+
     /// returns the offset to the first data segment
     pub(crate) fn offset(&self) -> u64 {
         self.partitions[1].ent_start
@@ -460,6 +894,252 @@ impl NexusLabel {
     pub(crate) fn get_block_count(&self) -> u64 {
         self.partitions[1].ent_end - self.partitions[1].ent_start
     }
+
+    /// Move the backup label to the end of a device which has grown to
+    /// num_blocks, extending the range usable by partitions accordingly. The
+    /// partitions themselves are left as they are. The primary header is
+    /// checksummed again, the partition table is unchanged.
+    pub fn grow(&mut self, num_blocks: u64) -> Result<(), LabelError> {
+        let current = self.primary.lba_alt + 1;
+        if num_blocks <= current {
+            return Err(LabelError::LabelShrink {
+                current,
+                requested: num_blocks,
+            });
+        }
+
+        // the backup table keeps its size, whatever it was written with
+        let table_blocks = self.primary.lba_alt - self.primary.lba_end - 1;
+        self.primary.lba_alt = num_blocks - 1;
+        self.primary.lba_end = self.primary.lba_alt - table_blocks - 1;
+        self.primary.checksum();
+        Ok(())
+    }
+
+    /// Return the label as it is to be written to the same device once it has
+    /// been reformatted from blocks of old_bs to blocks of new_bs bytes. All
+    /// LBAs are recomputed such that the partitions cover the same bytes as
+    /// before, hence keep their data, while the disk GUID and the type and
+    /// GUID of each partition are preserved. The headers and the partition
+    /// tables keep their size in bytes, rounded up to whole blocks.
+    ///
+    /// Fails with PartitionMisaligned if a partition does not start and end
+    /// on a boundary of the new block size, as it cannot be relocated without
+    /// moving its data.
+    pub fn reblock(
+        &self,
+        old_bs: u64,
+        new_bs: u64,
+    ) -> Result<NexusLabel, LabelError> {
+        let size = (self.primary.lba_alt + 1) * old_bs;
+        for bs in &[old_bs, new_bs] {
+            if *bs < 512 || *bs > (1 << 14) || !bs.is_power_of_two() {
+                return Err(LabelError::InvalidGeometry {
+                    num_blocks: size / bs,
+                    block_size: *bs,
+                });
+            }
+        }
+        let num_blocks = size / new_bs;
+        let geometry = || LabelError::InvalidGeometry {
+            num_blocks,
+            block_size: new_bs,
+        };
+        if size % new_bs != 0 {
+            return Err(geometry());
+        }
+
+        // tables keep their size in bytes
+        let to_blocks = |bytes: u64| (bytes + new_bs - 1) / new_bs;
+        let table_blocks = to_blocks(
+            u64::from(self.primary.num_entries)
+                * u64::from(self.primary.entry_size),
+        );
+        let backup_blocks = to_blocks(
+            (self.primary.lba_alt - self.primary.lba_end - 1) * old_bs,
+        );
+
+        let mut primary = self.primary;
+        primary.lba_self = 1;
+        primary.lba_table = 2;
+        primary.lba_alt = num_blocks - 1;
+        primary.lba_start = to_blocks(self.primary.lba_start * old_bs)
+            .max(primary.lba_table + table_blocks);
+        primary.lba_end = std::cmp::min(
+            (self.primary.lba_end + 1) * old_bs / new_bs,
+            primary.lba_alt - backup_blocks,
+        ) - 1;
+        if primary.lba_start > primary.lba_end {
+            return Err(geometry());
+        }
+
+        let mut table = self.partition_table().cloned().collect::<Vec<_>>();
+        for (index, entry) in table.iter_mut().enumerate() {
+            if entry.ent_type == GptGuid::default() {
+                continue;
+            }
+            let start = entry.ent_start * old_bs;
+            let end = (entry.ent_end + 1) * old_bs;
+            if start % new_bs != 0
+                || end % new_bs != 0
+                || start / new_bs < primary.lba_start
+                || end / new_bs - 1 > primary.lba_end
+            {
+                return Err(LabelError::PartitionMisaligned {
+                    index,
+                    block_size: new_bs,
+                });
+            }
+            entry.ent_start = start / new_bs;
+            entry.ent_end = end / new_bs - 1;
+        }
+
+        primary.table_crc = GptEntry::checksum(&table);
+        primary.checksum();
+
+        let mut label = NexusLabel::from_table(primary, table);
+        label.revision_mismatch = self.revision_mismatch;
+        Ok(label)
+    }
+
+    /// returns the primary header serialized into a block of its own
+    pub fn primary_bytes(
+        &self,
+        block_size: u32,
+    ) -> Result<Vec<u8>, LabelError> {
+        let mut buf = vec![0u8; block_size as usize];
+        serialize_into(&mut Cursor::new(&mut buf[..]), &self.primary)
+            .context(SerializeError {})?;
+        Ok(buf)
+    }
+
+    /// returns the backup partition table followed by the backup header as
+    /// they are laid out on disk, starting at the block after lba_end
+    pub fn backup_bytes(&self, block_size: u32) -> Result<Vec<u8>, LabelError> {
+        let block_size = u64::from(block_size);
+        let table_len =
+            (self.primary.lba_alt - self.primary.lba_end - 1) * block_size;
+        let mut buf = vec![0u8; (table_len + block_size) as usize];
+
+        let mut backup = self.primary.to_backup();
+        backup.checksum();
+
+        let mut writer = Cursor::new(&mut buf[..]);
+        for p in self.partition_table() {
+            serialize_into(&mut writer, &p).context(SerializeError {})?;
+        }
+        writer.seek(SeekFrom::Start(table_len)).unwrap();
+        serialize_into(&mut writer, &backup).context(SerializeError {})?;
+        Ok(buf)
+    }
+
+    /// returns the offset to the meta partition
+    pub(crate) fn meta_offset(&self) -> u64 {
+        self.partitions[0].ent_start
+    }
+
+    /// returns the number of blocks of the meta partition
+    pub(crate) fn meta_block_count(&self) -> u64 {
+        self.partitions[0].ent_end - self.partitions[0].ent_start + 1
+    }
+}
+
+/// A single difference between two labels as returned by NexusLabel::diff().
+/// Ranges are given as (first lba, last lba) and partitions by their index
+/// in the partition table.
+#[derive(Debug, PartialEq, Serialize, Clone)]
+pub enum LabelDiff {
+    /// the GUID of the disk differs
+    DiskGuid { current: GptGuid, desired: GptGuid },
+    /// the range of blocks usable by partitions differs
+    UsableRange {
+        current: (u64, u64),
+        desired: (u64, u64),
+    },
+    /// the number of our partitions differs
+    PartitionCount { current: usize, desired: usize },
+    /// the blocks taken up by the partition differ
+    PartitionRange {
+        index: usize,
+        current: (u64, u64),
+        desired: (u64, u64),
+    },
+    /// the type GUID of the partition differs
+    PartitionType {
+        index: usize,
+        current: GptGuid,
+        desired: GptGuid,
+    },
+    /// the GUID of the partition differs
+    PartitionGuid {
+        index: usize,
+        current: GptGuid,
+        desired: GptGuid,
+    },
+}
+
+impl Display for LabelDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LabelDiff::DiskGuid {
+                current,
+                desired,
+            } => write!(f, "disk GUID {} -> {}", current, desired),
+            LabelDiff::UsableRange {
+                current,
+                desired,
+            } => write!(
+                f,
+                "usable lbas {}..={} -> {}..={}",
+                current.0, current.1, desired.0, desired.1
+            ),
+            LabelDiff::PartitionCount {
+                current,
+                desired,
+            } => write!(f, "partition count {} -> {}", current, desired),
+            LabelDiff::PartitionRange {
+                index,
+                current,
+                desired,
+            } => write!(
+                f,
+                "partition {} lbas {}..={} -> {}..={}",
+                index, current.0, current.1, desired.0, desired.1
+            ),
+            LabelDiff::PartitionType {
+                index,
+                current,
+                desired,
+            } => write!(
+                f,
+                "partition {} type GUID {} -> {}",
+                index, current, desired
+            ),
+            LabelDiff::PartitionGuid {
+                index,
+                current,
+                desired,
+            } => {
+                write!(f, "partition {} GUID {} -> {}", index, current, desired)
+            }
+        }
+    }
+}
+
+/// The raw, uninterpreted, blocks of a device which make up its label. These
+/// are kept as plain bytes such that they can be stored and analysed offline.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct LabelBytes {
+    /// the protective MBR
+    pub mbr: Vec<u8>,
+    /// the primary GPT header
+    pub primary_header: Vec<u8>,
+    /// the primary partition table
+    pub primary_table: Vec<u8>,
+    /// the backup partition table
+    pub secondary_table: Vec<u8>,
+    /// the backup GPT header
+    pub secondary_header: Vec<u8>,
 }
 
 impl Display for NexusLabel {
@@ -568,7 +1248,7 @@ pub struct GptName {
 /// The struct should have a 440 byte code section here as well, this is
 /// omitted to make serialisation a bit easier.
 #[derive(Serialize, Deserialize)]
-struct Pmbr {
+pub struct Pmbr {
     /// signature to uniquely ID the disk we do not use this
     pub disk_signature: u32,
     pub reserved: u16,
@@ -596,6 +1276,98 @@ pub struct MbrEntry {
     num_sectors: u32,
 }
 
+/// size of the MBR, which is 512 bytes on devices of any block size
+pub const MBR_SIZE: usize = 512;
+/// partition type of the single partition of a protective MBR
+pub const MBR_PROTECTIVE_TYPE: u8 = 0xee;
+/// offset of the disk signature, i.e. the part of the MBR we (de)serialize,
+/// within the MBR
+const MBR_OFFSET: u64 = 440;
+
+impl Pmbr {
+    /// converts a slice holding the first block of a device into a protective
+    /// MBR, validating its signature only. The block may be larger than 512
+    /// bytes, i.e. on 4Kn devices, the MBR is always in its first 512 bytes.
+    pub fn from_slice(slice: &[u8]) -> Result<Pmbr, LabelError> {
+        if slice.len() < MBR_SIZE {
+            return Err(LabelError::PmbrSize {
+                len: slice.len(),
+            });
+        }
+        let mut reader = Cursor::new(&slice[.. MBR_SIZE]);
+        reader.seek(SeekFrom::Start(MBR_OFFSET)).unwrap();
+        let pmbr: Pmbr =
+            deserialize_from(&mut reader).context(DeserializeError {})?;
+
+        if pmbr.signature != [0x55, 0xaa] {
+            return Err(LabelError::PmbrSignature {});
+        }
+        Ok(pmbr)
+    }
+
+    /// serializes the MBR into the first 512 bytes of the slice, which is
+    /// the first block of a device, leaving the code section before it and
+    /// whatever follows it untouched
+    pub fn write_into(&self, slice: &mut [u8]) -> Result<(), LabelError> {
+        if slice.len() < MBR_SIZE {
+            return Err(LabelError::PmbrSize {
+                len: slice.len(),
+            });
+        }
+        let mut writer = Cursor::new(&mut slice[.. MBR_SIZE]);
+        // we seek 440 into the buffer here, this makes serialisation a little
+        // easier.
+        writer.seek(SeekFrom::Start(MBR_OFFSET)).unwrap();
+        serialize_into(&mut writer, self).context(SerializeError {})
+    }
+
+    /// number of sectors covered by the protective partition
+    pub fn num_sectors(&self) -> u32 {
+        self.entries[0].num_sectors
+    }
+
+    /// set the number of sectors covered by the protective partition
+    pub fn set_num_sectors(&mut self, num_sectors: u32) {
+        self.entries[0].num_sectors = num_sectors;
+    }
+
+    /// partition type of the first partition, which is MBR_PROTECTIVE_TYPE
+    /// for a protective MBR and something else for a legacy MBR
+    pub fn partition_type(&self) -> u8 {
+        self.entries[0].ent_type
+    }
+
+    /// returns true if this is a protective MBR rather than the partition
+    /// table of a disk which is not GPT partitioned
+    pub fn is_protective(&self) -> bool {
+        self.partition_type() == MBR_PROTECTIVE_TYPE
+    }
+
+    /// The protective partition starts at LBA 1 and covers the remainder of
+    /// the device, so the number of sectors equals the LBA of the last block,
+    /// which is where the backup GPT header lives (lba_alt). Devices with
+    /// more blocks than fit in 32 bits, i.e. 2TiB and up with 512 byte
+    /// blocks, are covered using the 0xffff_ffff sentinel instead.
+    pub fn protective_sectors(num_blocks: u64) -> u32 {
+        let last_lba = num_blocks.saturating_sub(1);
+        if last_lba < u64::from(u32::max_value()) {
+            last_lba as u32
+        } else {
+            u32::max_value()
+        }
+    }
+
+    /// returns true if the number of sectors of the protective partition is
+    /// consistent with the lba_alt of the GPT header of the same device
+    pub fn is_consistent(num_sectors: u32, lba_alt: u64) -> bool {
+        if num_sectors == u32::max_value() {
+            lba_alt >= u64::from(u32::max_value())
+        } else {
+            u64::from(num_sectors) == lba_alt
+        }
+    }
+}
+
 impl Default for Pmbr {
     fn default() -> Self {
         Pmbr {
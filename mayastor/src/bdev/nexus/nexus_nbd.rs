@@ -9,7 +9,7 @@ use std::{
     io,
     os::unix::io::AsRawFd,
     path::Path,
-    sync::{atomic::AtomicBool, Arc},
+    sync::{atomic::AtomicBool, mpsc, Arc},
     thread,
     time::Duration,
 };
@@ -39,8 +39,18 @@ const SET_TIMEOUT: u32 = io!(0xab, 9);
 pub enum NbdError {
     #[snafu(display("No free NBD devices available (is NBD kmod loaded?)"))]
     Unavailable {},
+    #[snafu(display("NBD device {} is busy", dev))]
+    Busy { dev: String },
+    #[snafu(display(
+        "Invalid NBD device index {}, the module provides {} devices",
+        index,
+        max
+    ))]
+    InvalidIndex { index: u32, max: u32 },
     #[snafu(display("Failed to start NBD on {}", dev))]
     StartNbd { source: Errno, dev: String },
+    #[snafu(display("Failed to flush NBD device {}", dev))]
+    FlushNbd { source: io::Error, dev: String },
 }
 
 extern "C" {
@@ -97,46 +107,67 @@ pub(crate) fn wait_until_ready(path: &str) -> Result<(), ()> {
     Ok(())
 }
 
-/// Return first unused nbd device in /dev.
+/// Number of nbd devices provided by the nbd kernel module.
+fn nbd_max() -> u32 {
+    parse_value(Path::new("/sys/class/modules/nbd/parameters"), "nbds_max")
+        .unwrap_or(16)
+}
+
+/// Return the device path of nbd device with given index if it is not in use.
 ///
 /// NOTE: We do a couple of syscalls in this function which by normal
 /// circumstances do not block.
-pub fn find_unused() -> Result<String, NbdError> {
-    let nbd_max =
-        parse_value(Path::new("/sys/class/modules/nbd/parameters"), "nbds_max")
-            .unwrap_or(16);
-
-    for i in 0 .. nbd_max {
-        let name = format!("nbd{}", i);
-        match parse_value::<u32>(
-            Path::new(&format!("/sys/class/block/{}", name)),
-            "pid",
-        ) {
-            // if we find a pid file the device is in use
-            Ok(_) => continue,
-            Err(e) => match e.kind() {
-                io::ErrorKind::NotFound => {
-                    // No PID file is found, which implies it is free to used.
-                    // The kernel needs time to construct the device
-                    // so we need to make sure we are not using it internally
-                    // already.
-                    let nbd_device =
-                        CString::new(format!("/dev/{}", name)).unwrap();
-                    let ptr = unsafe {
-                        spdk_nbd_disk_find_by_nbd_path(nbd_device.as_ptr())
-                    };
-
-                    if ptr.is_null() {
-                        return Ok(nbd_device.into_string().unwrap());
-                    }
-                    continue;
+fn unused_path(index: u32) -> Option<String> {
+    let name = format!("nbd{}", index);
+    match parse_value::<u32>(
+        Path::new(&format!("/sys/class/block/{}", name)),
+        "pid",
+    ) {
+        // if we find a pid file the device is in use
+        Ok(_) => None,
+        Err(e) => match e.kind() {
+            io::ErrorKind::NotFound => {
+                // No PID file is found, which implies it is free to used.
+                // The kernel needs time to construct the device
+                // so we need to make sure we are not using it internally
+                // already.
+                let nbd_device =
+                    CString::new(format!("/dev/{}", name)).unwrap();
+                let ptr = unsafe {
+                    spdk_nbd_disk_find_by_nbd_path(nbd_device.as_ptr())
+                };
+
+                if ptr.is_null() {
+                    Some(nbd_device.into_string().unwrap())
+                } else {
+                    None
                 }
-                _ => continue,
-            },
-        }
+            }
+            _ => None,
+        },
     }
+}
 
-    Err(NbdError::Unavailable {})
+/// Return first unused nbd device in /dev.
+pub fn find_unused() -> Result<String, NbdError> {
+    (0 .. nbd_max())
+        .find_map(unused_path)
+        .ok_or(NbdError::Unavailable {})
+}
+
+/// Return the path of /dev/nbd<index> or an error if it is in use or does
+/// not exist.
+pub fn find_by_index(index: u32) -> Result<String, NbdError> {
+    let max = nbd_max();
+    if index >= max {
+        return Err(NbdError::InvalidIndex {
+            index,
+            max,
+        });
+    }
+    unused_path(index).ok_or_else(|| NbdError::Busy {
+        dev: format!("/dev/nbd{}", index),
+    })
 }
 
 /// Callback for spdk_nbd_start().
@@ -192,13 +223,30 @@ impl NbdDisk {
     pub async fn create(bdev_name: &str) -> Result<Self, NbdError> {
         // find a NBD device which is available
         let device_path = find_unused()?;
-        let nbd_ptr = start(bdev_name, &device_path).await?;
+        Self::start_on(bdev_name, &device_path).await
+    }
+
+    /// Same as create() but uses /dev/nbd<nbd_index> rather than the first
+    /// free device, failing if that device is busy.
+    pub async fn create_on(
+        bdev_name: &str,
+        nbd_index: u32,
+    ) -> Result<Self, NbdError> {
+        let device_path = find_by_index(nbd_index)?;
+        Self::start_on(bdev_name, &device_path).await
+    }
+
+    async fn start_on(
+        bdev_name: &str,
+        device_path: &str,
+    ) -> Result<Self, NbdError> {
+        let nbd_ptr = start(bdev_name, device_path).await?;
 
         // this should not be needed but for some unknown reason, we end up with
         // stale NBD devices. Setting this to non zero, prevents that from
         // happening (although we dont actually timeout).
 
-        let f = OpenOptions::new().read(true).open(Path::new(&device_path));
+        let f = OpenOptions::new().read(true).open(Path::new(device_path));
         unsafe {
             convert_ioctl_res!(libc::ioctl(
                 f.unwrap().as_raw_fd(),
@@ -211,7 +259,7 @@ impl NbdDisk {
         // we wait for the dev to come up online because
         // otherwise the mount done too early would fail.
         // If it times out, continue anyway and let the mount fail.
-        wait_until_ready(&device_path).unwrap();
+        wait_until_ready(device_path).unwrap();
         info!("Started nbd disk {} for {}", device_path, bdev_name);
 
         Ok(Self {
@@ -219,6 +267,48 @@ impl NbdDisk {
         })
     }
 
+    /// Write back the data the kernel buffers for the device and wait for the
+    /// device to flush it to the bdev, such that no write acknowledged to the
+    /// user of the device is lost when it is disconnected.
+    pub fn flush(&self) -> Result<(), NbdError> {
+        let path = self.get_path();
+        let (s, r) = mpsc::channel::<io::Result<()>>();
+
+        // the flush is served by the reactor of this thread, so it has to
+        // be issued from another thread while we keep polling
+        let dev = path.clone();
+        thread::spawn(move || {
+            // fsync of a block device writes back its page cache and sends
+            // a flush to the device, NBD_CMD_FLUSH in the case of nbd
+            let result = OpenOptions::new()
+                .write(true)
+                .open(Path::new(&dev))
+                .and_then(|f| f.sync_all());
+            let _ = s.send(result);
+        });
+
+        let result = loop {
+            match r.try_recv() {
+                Ok(result) => break result,
+                Err(mpsc::TryRecvError::Empty) => {
+                    Reactors::current().poll_once()
+                }
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    break Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "flush thread exited",
+                    ))
+                }
+            }
+        };
+
+        result.context(FlushNbd {
+            dev: path.clone(),
+        })?;
+        debug!("NBD device {} flushed", path);
+        Ok(())
+    }
+
     /// Stop and release nbd device.
     pub fn destroy(self) {
         let started = Arc::new(AtomicBool::new(false));
@@ -0,0 +1,89 @@
+//! Utility functions and wrappers for working with nvmf targets in SPDK.
+
+use std::fmt;
+
+use snafu::Snafu;
+
+use crate::{
+    core::Bdev,
+    target::nvmf::{get_uri, share_with_ns_uuid, unshare},
+};
+
+#[derive(Debug, Snafu)]
+pub enum NexusNvmfError {
+    #[snafu(display("Bdev not found {}", dev))]
+    BdevNotFound { dev: String },
+    #[snafu(display(
+        "Failed to create nvmf target for bdev {}, error {}",
+        dev,
+        err
+    ))]
+    CreateTargetFailed { dev: String, err: String },
+}
+
+/// Nvmf target representation.
+pub struct NexusNvmfTarget {
+    bdev_name: String,
+    /// uuid of the namespace backed by the bdev
+    ns_uuid: String,
+}
+
+impl NexusNvmfTarget {
+    /// Create a nvmf subsystem for the bdev, with one namespace which has the
+    /// given uuid. When the function returns the target is ready for IO.
+    pub async fn create(
+        bdev_name: &str,
+        ns_uuid: &str,
+    ) -> Result<Self, NexusNvmfError> {
+        let bdev = match Bdev::lookup_by_name(bdev_name) {
+            None => {
+                return Err(NexusNvmfError::BdevNotFound {
+                    dev: bdev_name.to_string(),
+                })
+            }
+            Some(bd) => bd,
+        };
+
+        match share_with_ns_uuid(bdev_name, &bdev, Some(ns_uuid)).await {
+            Ok(_) => Ok(Self {
+                bdev_name: bdev_name.to_string(),
+                ns_uuid: ns_uuid.to_string(),
+            }),
+            Err(e) => Err(NexusNvmfError::CreateTargetFailed {
+                dev: bdev_name.to_string(),
+                err: e.to_string(),
+            }),
+        }
+    }
+
+    pub async fn destroy(self) {
+        info!("Destroying nvmf frontend target");
+        match unshare(&self.bdev_name).await {
+            Ok(()) => (),
+            Err(e) => {
+                error!("Failed to destroy nvmf frontend target, error {}", e)
+            }
+        }
+    }
+
+    pub fn as_uri(&self) -> String {
+        get_uri(&self.bdev_name).unwrap_or_default()
+    }
+
+    /// uuid of the namespace as seen by initiators
+    pub fn ns_uuid(&self) -> &str {
+        &self.ns_uuid
+    }
+}
+
+impl fmt::Debug for NexusNvmfTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{:?}", self.as_uri(), self.bdev_name)
+    }
+}
+
+impl fmt::Display for NexusNvmfTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_uri())
+    }
+}
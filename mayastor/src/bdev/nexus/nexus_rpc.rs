@@ -112,8 +112,7 @@ pub(crate) fn register_rpc_methods() {
         |args: DestroyNexusRequest| {
             let fut = async move {
                 let nexus = nexus_lookup(&args.uuid)?;
-                nexus.destroy().await;
-                Ok(())
+                nexus.destroy().await
             };
             fut.boxed_local()
         },
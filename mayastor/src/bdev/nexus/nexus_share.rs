@@ -1,26 +1,48 @@
-use std::ffi::CString;
+use std::{
+    ffi::{CStr, CString},
+    fmt,
+    future::Future,
+    os::raw::c_char,
+    pin::Pin,
+};
 
-use futures::channel::oneshot;
+use futures::{
+    channel::oneshot,
+    stream::{self, StreamExt},
+};
+use serde::Serialize;
 use snafu::ResultExt;
 
 use spdk_sys::create_crypto_disk;
 
 use crate::{
     bdev::nexus::{
+        instances,
         nexus_bdev::{
+            nexus_lookup,
+            AllocKeyMarker,
             CreateCryptoBdev,
             DestroyCryptoBdev,
             Error,
+            KeyMarkerIo,
             Nexus,
-            NexusTarget,
             ShareIscsiNexus,
             ShareNbdNexus,
+            ShareNvmfNexus,
+            ShareVhostNexus,
+            UnshareNbdNexus,
+            UnshareVhostNexus,
+            VerifyCryptoKey,
         },
+        nexus_child::{ChildState, NexusChild},
         nexus_iscsi::NexusIscsiTarget,
         nexus_nbd::NbdDisk,
+        nexus_nvmf::NexusNvmfTarget,
+        nexus_vhost::NexusVhostTarget,
     },
-    core::Bdev,
+    core::{Bdev, BdevHandle, CoreError, DmaBuf},
     ffihelper::{cb_arg, done_errno_cb, errno_result_from_i32, ErrnoResult},
+    target::Side,
 };
 
 use rpc::mayastor::ShareProtocolNexus;
@@ -28,6 +50,594 @@ use rpc::mayastor::ShareProtocolNexus;
 /// we are using the multi buffer encryption implementation using CBC as the
 /// algorithm
 const CRYPTO_FLAVOUR: &str = "crypto_aesni_mb";
+/// name of the bdev module of crypto bdevs
+const CRYPTO_MODULE: &str = "crypto";
+/// crypto bdevs are named after their nexus, with this prefix
+const CRYPTO_PREFIX: &str = "crypto-";
+/// size in bytes of the keys of the cipher used by crypto bdevs (AES-CBC 128)
+const CRYPTO_KEY_LEN: usize = 16;
+/// known plaintext of the key marker, followed by the uuid of the nexus, see
+/// Nexus::verify_crypto_key
+const CRYPTO_KEY_MARKER: &[u8] = b"MAYASTOR-CRYPTO-KEY-MARKER-V1";
+/// the crypto bdevs through which the key markers of the children of a nexus
+/// are accessed are named after the nexus, with this suffix
+const CRYPTO_MARKER_SUFFIX: &str = "-key-marker";
+
+extern "C" {
+    // part of DPDK rather than SPDK, so these are not in our bindings
+    fn rte_cryptodev_driver_name_get(driver_id: u8) -> *const c_char;
+    fn rte_cryptodev_device_count_by_driver(driver_id: u8) -> u8;
+}
+
+/// Returns the names of the crypto drivers for which DPDK has at least one
+/// device, which are the flavours a crypto bdev can be created with on this
+/// host. Drivers which are compiled in but found no hardware (e.g. AES-NI on
+/// a CPU without it) are not included.
+pub fn available_crypto_drivers() -> Vec<String> {
+    let mut drivers = Vec::new();
+    // driver ids are handed out in order of registration, starting at 0
+    for driver_id in 0 ..= u8::max_value() {
+        let name = unsafe { rte_cryptodev_driver_name_get(driver_id) };
+        if name.is_null() {
+            break;
+        }
+        if unsafe { rte_cryptodev_device_count_by_driver(driver_id) } > 0 {
+            drivers.push(
+                unsafe { CStr::from_ptr(name) }
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+        }
+    }
+    drivers
+}
+
+/// create a crypto bdev named name on top of the bdev base, which encrypts
+/// and decrypts with the given key
+fn create_crypto_bdev(
+    base: &str,
+    name: &str,
+    key: &[u8],
+) -> ErrnoResult<String> {
+    // constant
+    let flavour = CString::new(CRYPTO_FLAVOUR).unwrap();
+    // name of the crypto device
+    let cname = CString::new(name).unwrap();
+    // the device below it
+    let base = CString::new(base).unwrap();
+    // the keys to the castle, free of NUL bytes as decoded
+    let key = CString::new(key).unwrap();
+
+    let errno = unsafe {
+        create_crypto_disk(
+            base.as_ptr(),
+            cname.as_ptr(),
+            flavour.as_ptr(),
+            key.as_ptr(),
+        )
+    };
+    errno_result_from_i32(name.to_owned(), errno)
+}
+
+/// read the block at offset of the bdev into buf, or write buf to it if
+/// write is set
+async fn bdev_block_io(
+    name: &str,
+    offset: u64,
+    buf: &mut DmaBuf,
+    write: bool,
+) -> Result<(), CoreError> {
+    let handle = BdevHandle::open(name, write, false)?;
+    let result = if write {
+        match handle.write_at(offset, buf).await {
+            Ok(_) => handle.flush(offset, buf.len() as u64).await,
+            Err(e) => Err(e),
+        }
+    } else {
+        handle.read_at(offset, buf).await.map(|_| ())
+    };
+    handle.close();
+    result
+}
+
+/// delete the crypto bdev, which leaves the bdev below it as it was
+async fn destroy_crypto_bdev(bdev: &Bdev) -> ErrnoResult<()> {
+    let (s, r) = oneshot::channel::<ErrnoResult<()>>();
+    unsafe {
+        spdk_sys::delete_crypto_disk(
+            bdev.as_ptr(),
+            Some(done_errno_cb),
+            cb_arg(s),
+        );
+    }
+    r.await.expect("crypto delete sender is gone")
+}
+
+/// Delete the crypto bdevs created by share() for nexuses which no longer
+/// exist or are not in known_nexuses, such as those left behind after an
+/// unclean shutdown, which would otherwise collide with the next share of
+/// the nexus with a key. A failure to delete one bdev is logged and does not
+/// stop the others from being deleted. Returns the names of the bdevs which
+/// have been deleted.
+pub async fn cleanup_orphan_crypto_bdevs(
+    known_nexuses: &[String],
+) -> Vec<String> {
+    let is_orphan = |bdev: &Bdev| {
+        let name = bdev.name();
+        if bdev.driver() != CRYPTO_MODULE || !name.starts_with(CRYPTO_PREFIX) {
+            return false;
+        }
+        let nexus = &name[CRYPTO_PREFIX.len() ..];
+        !known_nexuses.iter().any(|n| n == nexus)
+            || nexus_lookup(nexus).is_none()
+    };
+
+    // collect them first, as the bdev list changes with each deletion
+    let mut orphans = Vec::new();
+    let mut next = Bdev::bdev_first();
+    while let Some(mut bdev) = next {
+        next = bdev.next();
+        if is_orphan(&bdev) {
+            orphans.push(bdev);
+        }
+    }
+
+    let mut deleted = Vec::new();
+    for bdev in orphans {
+        let name = bdev.name();
+        match destroy_crypto_bdev(&bdev).await {
+            Ok(()) => {
+                info!("Deleted orphaned crypto bdev {}", name);
+                deleted.push(name);
+            }
+            Err(e) => {
+                error!("Failed to delete orphaned crypto bdev {}: {}", name, e)
+            }
+        }
+    }
+    deleted
+}
+
+/// A boxed future as returned by the methods of ShareTransport
+pub type TransportFuture<'a, T> =
+    Pin<Box<dyn Future<Output = Result<T, Error>> + 'a>>;
+
+/// A transport through which a nexus is published. Adding a transport is a
+/// matter of implementing this trait and returning it from transport_for().
+pub trait ShareTransport: fmt::Debug {
+    /// protocol implemented by the transport
+    fn protocol(&self) -> ShareProtocolNexus;
+    /// publish the bdev and return the uri, or device path, under which it
+    /// can be reached
+    fn publish<'a>(
+        &'a mut self,
+        bdev_name: &'a str,
+    ) -> TransportFuture<'a, String>;
+    /// undo publish()
+    fn unpublish(&mut self) -> TransportFuture<'_, ()>;
+    /// uri under which the bdev is published, None if it is not published
+    fn uri(&self) -> Option<String>;
+    /// uuid of the namespace as seen by initiators, for protocols which have
+    /// namespaces
+    fn ns_uuid(&self) -> Option<String> {
+        None
+    }
+    /// true if the transport can publish the bdev such that it cannot be
+    /// written to, see ShareOptions::read_only
+    fn supports_read_only(&self) -> bool {
+        false
+    }
+}
+
+/// Publishes a nexus as a local /dev/nbd device
+#[derive(Debug)]
+pub struct NbdTransport {
+    nexus: String,
+    nbd_index: Option<u32>,
+    disk: Option<NbdDisk>,
+}
+
+impl ShareTransport for NbdTransport {
+    fn protocol(&self) -> ShareProtocolNexus {
+        ShareProtocolNexus::NexusNbd
+    }
+
+    fn publish<'a>(
+        &'a mut self,
+        bdev_name: &'a str,
+    ) -> TransportFuture<'a, String> {
+        Box::pin(async move {
+            let disk = match self.nbd_index {
+                Some(index) => NbdDisk::create_on(bdev_name, index).await,
+                None => NbdDisk::create(bdev_name).await,
+            }
+            .context(ShareNbdNexus {
+                name: self.nexus.clone(),
+            })?;
+            let device_path = disk.get_path();
+            self.disk = Some(disk);
+            Ok(device_path)
+        })
+    }
+
+    fn unpublish(&mut self) -> TransportFuture<'_, ()> {
+        Box::pin(async move {
+            // writes acknowledged to the user may still be in the page cache
+            // of the device, which is dropped when it is disconnected. The
+            // disk is kept if that fails, failing unshare, and destroy with
+            // it, such that the teardown can be retried without data loss.
+            if let Some(ref disk) = self.disk {
+                if let Err(e) = disk.flush() {
+                    error!(
+                        "{}: keeping {} as it failed to flush",
+                        self.nexus, disk
+                    );
+                    return Err(e).context(UnshareNbdNexus {
+                        name: self.nexus.clone(),
+                    });
+                }
+            }
+            if let Some(disk) = self.disk.take() {
+                disk.destroy();
+            }
+            Ok(())
+        })
+    }
+
+    fn uri(&self) -> Option<String> {
+        self.disk.as_ref().map(|disk| disk.get_path())
+    }
+}
+
+/// Publishes a nexus as an iscsi target with a single LUN, LUN0 by default
+#[derive(Debug)]
+pub struct IscsiTransport {
+    nexus: String,
+    side: Side,
+    queue_depth: Option<u32>,
+    lun_id: Option<u32>,
+    target: Option<NexusIscsiTarget>,
+}
+
+impl ShareTransport for IscsiTransport {
+    fn protocol(&self) -> ShareProtocolNexus {
+        ShareProtocolNexus::NexusIscsi
+    }
+
+    fn publish<'a>(
+        &'a mut self,
+        bdev_name: &'a str,
+    ) -> TransportFuture<'a, String> {
+        Box::pin(async move {
+            let target = NexusIscsiTarget::create(
+                bdev_name,
+                self.side,
+                self.queue_depth,
+                self.lun_id,
+            )
+            .context(ShareIscsiNexus {
+                name: self.nexus.clone(),
+            })?;
+            let uri = target.as_uri();
+            self.target = Some(target);
+            Ok(uri)
+        })
+    }
+
+    fn unpublish(&mut self) -> TransportFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some(target) = self.target.take() {
+                target.destroy().await;
+            }
+            Ok(())
+        })
+    }
+
+    fn uri(&self) -> Option<String> {
+        self.target.as_ref().map(|target| target.as_uri())
+    }
+}
+
+/// Publishes a nexus as a nvmf subsystem with a single namespace
+#[derive(Debug)]
+pub struct NvmfTransport {
+    nexus: String,
+    ns_uuid: String,
+    target: Option<NexusNvmfTarget>,
+}
+
+impl ShareTransport for NvmfTransport {
+    fn protocol(&self) -> ShareProtocolNexus {
+        ShareProtocolNexus::NexusNvmf
+    }
+
+    fn publish<'a>(
+        &'a mut self,
+        bdev_name: &'a str,
+    ) -> TransportFuture<'a, String> {
+        Box::pin(async move {
+            let target = NexusNvmfTarget::create(bdev_name, &self.ns_uuid)
+                .await
+                .context(ShareNvmfNexus {
+                    name: self.nexus.clone(),
+                })?;
+            let uri = target.as_uri();
+            self.target = Some(target);
+            Ok(uri)
+        })
+    }
+
+    fn unpublish(&mut self) -> TransportFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some(target) = self.target.take() {
+                target.destroy().await;
+            }
+            Ok(())
+        })
+    }
+
+    fn uri(&self) -> Option<String> {
+        self.target.as_ref().map(|target| target.as_uri())
+    }
+
+    fn ns_uuid(&self) -> Option<String> {
+        self.target
+            .as_ref()
+            .map(|target| target.ns_uuid().to_string())
+    }
+}
+
+/// Publishes a nexus as a vhost-user-blk controller for local VMs
+#[derive(Debug)]
+pub struct VhostTransport {
+    nexus: String,
+    read_only: bool,
+    target: Option<NexusVhostTarget>,
+}
+
+impl ShareTransport for VhostTransport {
+    fn protocol(&self) -> ShareProtocolNexus {
+        ShareProtocolNexus::NexusVhost
+    }
+
+    fn publish<'a>(
+        &'a mut self,
+        bdev_name: &'a str,
+    ) -> TransportFuture<'a, String> {
+        Box::pin(async move {
+            let target = NexusVhostTarget::create(bdev_name, self.read_only)
+                .context(ShareVhostNexus {
+                    name: self.nexus.clone(),
+                })?;
+            let uri = target.as_uri();
+            self.target = Some(target);
+            Ok(uri)
+        })
+    }
+
+    fn unpublish(&mut self) -> TransportFuture<'_, ()> {
+        Box::pin(async move {
+            // the controller stays around if it is still in use
+            if let Some(ref target) = self.target {
+                target.destroy().context(UnshareVhostNexus {
+                    name: self.nexus.clone(),
+                })?;
+            }
+            self.target = None;
+            Ok(())
+        })
+    }
+
+    fn uri(&self) -> Option<String> {
+        self.target.as_ref().map(|target| target.as_uri())
+    }
+
+    fn supports_read_only(&self) -> bool {
+        true
+    }
+}
+
+/// Return the, yet unpublished, transport for sharing the nexus with the
+/// given name and options.
+fn transport_for(
+    nexus: &str,
+    options: &ShareOptions,
+    ns_uuid: String,
+) -> Box<dyn ShareTransport> {
+    let nexus = nexus.to_string();
+    match options.protocol {
+        ShareProtocolNexus::NexusNbd => Box::new(NbdTransport {
+            nexus,
+            nbd_index: options.nbd_index,
+            disk: None,
+        }),
+        // a nexus is consumed by the hosts, so it is exported through the
+        // front end portal group
+        ShareProtocolNexus::NexusIscsi => Box::new(IscsiTransport {
+            nexus,
+            side: Side::Nexus,
+            queue_depth: options.queue_depth,
+            lun_id: options.lun_id,
+            target: None,
+        }),
+        ShareProtocolNexus::NexusNvmf => Box::new(NvmfTransport {
+            nexus,
+            ns_uuid,
+            target: None,
+        }),
+        ShareProtocolNexus::NexusVhost => Box::new(VhostTransport {
+            nexus,
+            read_only: options.read_only,
+            target: None,
+        }),
+    }
+}
+
+/// Describes how a nexus is published
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareInfo {
+    /// protocol used to share the nexus
+    pub protocol: ShareProtocolNexus,
+    /// nbd device path or iscsi uri of the share
+    pub uri: String,
+    /// true if the nexus is shared through a crypto bdev
+    pub encrypted: bool,
+    /// name of the bdev which is actually shared
+    pub share_handle: String,
+    /// uuid of the namespace when shared over nvmf
+    pub ns_uuid: Option<String>,
+}
+
+/// How the encryption key given to share a nexus is encoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyFormat {
+    /// the characters of the key are the key bytes themselves
+    Raw,
+    /// the key bytes are encoded as a hex string, two digits per byte, as
+    /// handed out by most key management systems
+    Hex,
+}
+
+impl Default for KeyFormat {
+    fn default() -> Self {
+        KeyFormat::Raw
+    }
+}
+
+impl KeyFormat {
+    /// Returns the key bytes the key given in this format stands for. A hex
+    /// encoded key must decode to exactly the key size of the cipher. As the
+    /// key is handed to the crypto bdev as a C string, it cannot contain NUL
+    /// bytes in either format.
+    pub fn decode(self, key: &str) -> Result<Vec<u8>, Error> {
+        let bytes = match self {
+            KeyFormat::Raw => key.as_bytes().to_vec(),
+            KeyFormat::Hex => {
+                if let Some((pos, c)) = key
+                    .chars()
+                    .enumerate()
+                    .find(|(_, c)| !c.is_ascii_hexdigit())
+                {
+                    return Err(Error::InvalidKeyEncoding {
+                        reason: format!(
+                            "invalid hex digit {:?} at position {}",
+                            c, pos
+                        ),
+                    });
+                }
+                if key.len() % 2 != 0 {
+                    return Err(Error::InvalidKeyEncoding {
+                        reason: format!(
+                            "odd number of hex digits ({})",
+                            key.len()
+                        ),
+                    });
+                }
+                let bytes = (0 .. key.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&key[i .. i + 2], 16).unwrap())
+                    .collect::<Vec<_>>();
+                if bytes.len() != CRYPTO_KEY_LEN {
+                    return Err(Error::InvalidKeyLength {
+                        len: bytes.len(),
+                        expected: CRYPTO_KEY_LEN,
+                    });
+                }
+                bytes
+            }
+        };
+
+        if bytes.contains(&0) {
+            return Err(Error::InvalidKeyEncoding {
+                reason: "the key contains a NUL byte".into(),
+            });
+        }
+        Ok(bytes)
+    }
+}
+
+/// When writes to a shared nexus, encrypted or not, are acknowledged
+///
+/// Write-through is only possible when none of the children has a volatile
+/// write cache. The nexus does not cache writes itself, and does not flush
+/// its children after each write either, which would cost a flush round trip
+/// per write on every child. The crypto bdev of an encrypted share inherits
+/// the caching behaviour of the nexus.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CachePolicy {
+    /// writes are acknowledged once the children have them, which may be in
+    /// their write caches only, such that they are durable once flushed. The
+    /// fastest option, and what the children do anyway.
+    WriteBack,
+    /// writes are acknowledged once they are durable on all children. This
+    /// requires children without a volatile write cache, which are typically
+    /// slower to write to.
+    WriteThrough,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy::WriteBack
+    }
+}
+
+/// Options for sharing a nexus, see Nexus::share_with_options()
+#[derive(Debug, Clone)]
+pub struct ShareOptions {
+    /// protocol used to share the nexus
+    pub protocol: ShareProtocolNexus,
+    /// encryption key of the nexus
+    pub key: Option<String>,
+    /// how the key is encoded
+    pub key_format: KeyFormat,
+    /// when writes are acknowledged, see CachePolicy
+    pub cache_policy: CachePolicy,
+    /// share through a crypto bdev using the key, when not set the key is
+    /// merely carried along and the nexus is shared as is
+    pub encrypt: bool,
+    /// verify the key against the key marker before the crypto bdev is
+    /// created, failing with CryptoKeyMismatch if it does not match, or with
+    /// NoKeyMarker if the nexus has none. The marker lives in the meta
+    /// partition of the children, out of reach of the consumer, and is
+    /// encrypted with the key.
+    pub verify_key: bool,
+    /// write the key marker for the key if the nexus has none yet, which is
+    /// how the first share with a key should be done for verify_key to work
+    /// later on. A nexus which has a marker is verified against it.
+    pub record_key: bool,
+    /// share the nexus read only, which only vhost supports
+    pub read_only: bool,
+    /// maximum queue depth of the target, only used by iscsi
+    pub queue_depth: Option<u32>,
+    /// id of the LUN the nexus is exported as, only used by iscsi and
+    /// defaults to 0
+    pub lun_id: Option<u32>,
+    /// uuid of the namespace when shared over nvmf, defaults to the uuid of
+    /// the nexus
+    pub ns_uuid: Option<String>,
+    /// index of the /dev/nbd device to use, defaults to the first free one
+    pub nbd_index: Option<u32>,
+}
+
+impl ShareOptions {
+    /// options which encrypt the nexus if, and only if, a key is given
+    pub fn new(protocol: ShareProtocolNexus, key: Option<String>) -> Self {
+        Self {
+            protocol,
+            encrypt: key.is_some(),
+            key,
+            key_format: KeyFormat::default(),
+            cache_policy: CachePolicy::default(),
+            verify_key: false,
+            record_key: false,
+            read_only: false,
+            queue_depth: None,
+            lun_id: None,
+            ns_uuid: None,
+            nbd_index: None,
+        }
+    }
+}
 
 impl Nexus {
     pub async fn share(
@@ -35,115 +645,278 @@ impl Nexus {
         share_protocol: ShareProtocolNexus,
         key: Option<String>,
     ) -> Result<String, Error> {
+        self.share_with_options(ShareOptions::new(share_protocol, key))
+            .await
+    }
+
+    /// Same as share() but gives full control over how the nexus is shared
+    pub async fn share_with_options(
+        &mut self,
+        options: ShareOptions,
+    ) -> Result<String, Error> {
+        let share_protocol = options.protocol;
+
         // We could already be shared -- as CSI is idempotent chances are we get
         // called for some odd reason. Validate indeed -- that we are
         // shared by walking the target. If so, and the protocol is
         // correct simply return Ok(). If so, and the protocol is
         // incorrect, return Error(). If we are not shared but the
         // variant says we should be, carry on to correct the state.
-        match self.nexus_target {
-            Some(NexusTarget::NbdDisk(ref nbd_disk)) => {
-                if share_protocol != ShareProtocolNexus::NexusNbd {
-                    return Err(Error::AlreadyShared {
-                        name: self.name.clone(),
-                    });
-                } else {
-                    warn!("{} is already shared", self.name);
-                    return Ok(nbd_disk.get_path());
-                }
+        if let Some(ref transport) = self.nexus_target {
+            if transport.protocol() != share_protocol {
+                return Err(Error::AlreadyShared {
+                    name: self.name.clone(),
+                });
+            } else {
+                warn!("{} is already shared", self.name);
+                return Ok(transport.uri().unwrap_or_default());
             }
-            Some(NexusTarget::NexusIscsiTarget(ref iscsi_target)) => {
-                if share_protocol != ShareProtocolNexus::NexusIscsi {
-                    return Err(Error::AlreadyShared {
-                        name: self.name.clone(),
-                    });
-                } else {
-                    warn!("{} is already shared", self.name);
-                    return Ok(iscsi_target.as_uri());
-                }
-            }
-            None => (),
         }
 
         assert_eq!(self.share_handle, None);
 
-        let name = if let Some(key) = key {
-            let name = format!("crypto-{}", self.name);
-
-            // constant
-            let flavour = CString::new(CRYPTO_FLAVOUR).unwrap();
-            // name of the crypto device
-            let cname = CString::new(name.clone()).unwrap();
-            // the nexus device itself
-            let base = CString::new(self.name.clone()).unwrap();
-            // the keys to the castle
-            let key = CString::new(key).unwrap();
-
-            let errno = unsafe {
-                create_crypto_disk(
-                    base.as_ptr(),
-                    cname.as_ptr(),
-                    flavour.as_ptr(),
-                    key.as_ptr(),
-                )
-            };
-            errno_result_from_i32(name, errno).context(CreateCryptoBdev {
+        // validate before anything is created which would have to be undone
+        if options.encrypt && options.key.is_none() {
+            return Err(Error::InvalidKey {});
+        }
+        self.check_cache_policy(options.cache_policy)?;
+        if options.encrypt {
+            let available = available_crypto_drivers();
+            if !available.iter().any(|d| d == CRYPTO_FLAVOUR) {
+                return Err(Error::CryptoDriverUnavailable {
+                    requested: CRYPTO_FLAVOUR.to_string(),
+                    available,
+                });
+            }
+        }
+        let ns_uuid = match options.ns_uuid.clone() {
+            Some(uuid) => match uuid::Uuid::parse_str(&uuid) {
+                Ok(uuid) => uuid.to_hyphenated().to_string(),
+                Err(_) => {
+                    return Err(Error::InvalidNsUuid {
+                        uuid,
+                    })
+                }
+            },
+            None => self.bdev.uuid_as_string(),
+        };
+        let mut transport = transport_for(&self.name, &options, ns_uuid);
+        if options.read_only && !transport.supports_read_only() {
+            return Err(Error::ReadOnlyShare {
                 name: self.name.clone(),
-            })?
+                protocol: share_protocol,
+            });
+        }
+
+        let key = match options.key.as_ref().filter(|_| options.encrypt) {
+            Some(key) => Some(options.key_format.decode(key)?),
+            None => None,
+        };
+        let crypto_bdev = if let Some(key) = key {
+            if options.verify_key || options.record_key {
+                self.verify_crypto_key(&key, options.record_key).await?;
+            }
+
+            let name = format!("{}{}", CRYPTO_PREFIX, self.name);
+            let name = create_crypto_bdev(&self.name, &name, &key).context(
+                CreateCryptoBdev {
+                    name: self.name.clone(),
+                },
+            )?;
+            Some(name)
         } else {
-            self.name.clone()
+            None
         };
+        let name = crypto_bdev.clone().unwrap_or_else(|| self.name.clone());
 
         debug!("creating share handle for {}", name);
         // The share handle is the actual bdev that is shared through the
         // various protocols.
 
-        let device_id = match share_protocol {
-            ShareProtocolNexus::NexusNbd => {
-                // Publish the nexus to system using nbd device and return the
-                // path to nbd device.
-                let nbd_disk =
-                    NbdDisk::create(&name).await.context(ShareNbdNexus {
-                        name: self.name.clone(),
-                    })?;
-                let device_path = nbd_disk.get_path();
-                self.nexus_target = Some(NexusTarget::NbdDisk(nbd_disk));
-                device_path
-            }
-            ShareProtocolNexus::NexusIscsi => {
-                // Publish the nexus to system using an iscsi target and return
-                // the IQN
-                let iscsi_target = NexusIscsiTarget::create(&name).context(
-                    ShareIscsiNexus {
-                        name: self.name.clone(),
-                    },
-                )?;
-                let uri = iscsi_target.as_uri();
-                self.nexus_target =
-                    Some(NexusTarget::NexusIscsiTarget(iscsi_target));
-                uri
-            }
-            ShareProtocolNexus::NexusNvmf => {
-                return Err(Error::InvalidShareProtocol {
-                    sp_value: share_protocol as i32,
-                })
+        let device_id = match transport.publish(&name).await {
+            Ok(device_id) => device_id,
+            Err(e) => {
+                // the crypto bdev is of no use without the share, and would
+                // collide with the next attempt to share with a key
+                if let Some(bdev) =
+                    crypto_bdev.as_ref().and_then(|n| Bdev::lookup_by_name(n))
+                {
+                    if let Err(err) = destroy_crypto_bdev(&bdev).await {
+                        error!(
+                            "{}: failed to delete crypto bdev {}: {}",
+                            self.name, name, err
+                        );
+                    }
+                }
+                return Err(e);
             }
         };
+        self.crypto_bdev = crypto_bdev;
+        self.nexus_target = Some(transport);
         self.share_handle = Some(name);
+        self.share_key = options.key;
         Ok(device_id)
     }
 
+    /// Check the key against the key marker, the last block of the meta
+    /// partition, of the open children and fail with CryptoKeyMismatch if a
+    /// marker does not decrypt with it. The marker is a known plaintext which
+    /// is encrypted, and decrypted, by a crypto bdev stacked on the child for
+    /// the time being, so it never holds anything the key can be derived
+    /// from. Without any marker this fails with NoKeyMarker, unless record is
+    /// set in which case the marker is written with the key at hand. Children
+    /// which lack the marker, such as those added since it has been written,
+    /// get it once the key has been verified.
+    async fn verify_crypto_key(
+        &self,
+        key: &[u8],
+        record: bool,
+    ) -> Result<(), Error> {
+        if self.meta_ent_blocks == 0 {
+            return Err(Error::NoKeyMarker {
+                name: self.name.clone(),
+            });
+        }
+
+        let block_len = u64::from(self.bdev.block_len());
+        let offset =
+            (self.meta_ent_offset + self.meta_ent_blocks - 1) * block_len;
+        let uuid = self.bdev.uuid().as_bytes();
+        let marker = [CRYPTO_KEY_MARKER, &uuid[..]].concat();
+        let mut buf = DmaBuf::new(block_len as usize, self.bdev.alignment())
+            .context(AllocKeyMarker {
+                name: self.name.clone(),
+            })?;
+
+        let mut verified = false;
+        let mut lacking = Vec::new();
+        for child in self.children_in_state(ChildState::Open) {
+            // a marker block which reads as zeroes below the crypto bdev has
+            // never been written
+            child
+                .read_at(offset, &mut buf)
+                .await
+                .context(VerifyCryptoKey {
+                    name: self.name.clone(),
+                })?;
+            if buf.as_slice().iter().all(|b| *b == 0) {
+                lacking.push(child);
+                continue;
+            }
+
+            self.key_marker_io(child, key, offset, &mut buf, false)
+                .await?;
+            if !buf.as_slice().starts_with(&marker) {
+                return Err(Error::CryptoKeyMismatch {
+                    name: self.name.clone(),
+                });
+            }
+            verified = true;
+        }
+
+        if !verified && !record {
+            return Err(Error::NoKeyMarker {
+                name: self.name.clone(),
+            });
+        }
+        if lacking.is_empty() {
+            return Ok(());
+        }
+
+        info!(
+            "{}: writing the crypto key marker to {} children",
+            self.name,
+            lacking.len()
+        );
+        for child in lacking {
+            buf.fill(0);
+            buf.as_mut_slice()[.. marker.len()].copy_from_slice(&marker);
+            self.key_marker_io(child, key, offset, &mut buf, true)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Read the key marker block at offset of the child into buf, or write
+    /// buf to it if write is set, through a crypto bdev with the key which is
+    /// stacked on the child for as long as it takes.
+    async fn key_marker_io(
+        &self,
+        child: &NexusChild,
+        key: &[u8],
+        offset: u64,
+        buf: &mut DmaBuf,
+        write: bool,
+    ) -> Result<(), Error> {
+        // an open child always has its bdev
+        let base = child.bdev.as_ref().expect("open child without a bdev");
+        let name =
+            format!("{}{}{}", CRYPTO_PREFIX, self.name, CRYPTO_MARKER_SUFFIX);
+        create_crypto_bdev(&base.name(), &name, key).context(
+            CreateCryptoBdev {
+                name: self.name.clone(),
+            },
+        )?;
+
+        let result = bdev_block_io(&name, offset, buf, write).await.context(
+            KeyMarkerIo {
+                child: child.name.clone(),
+                name: self.name.clone(),
+            },
+        );
+
+        // which releases the child again
+        if let Some(bdev) = Bdev::lookup_by_name(&name) {
+            destroy_crypto_bdev(&bdev)
+                .await
+                .context(DestroyCryptoBdev {
+                    name: self.name.clone(),
+                })?;
+        }
+        result
+    }
+
+    /// Fails with CachePolicyUnsupported if writes to the nexus cannot be
+    /// acknowledged the way the policy demands, see CachePolicy
+    fn check_cache_policy(&self, policy: CachePolicy) -> Result<(), Error> {
+        if policy == CachePolicy::WriteBack {
+            return Ok(());
+        }
+
+        let cached = self
+            .children
+            .iter()
+            .filter_map(|c| c.bdev.as_ref().map(|b| (c, b)))
+            .filter(|(_, b)| b.has_write_cache())
+            .map(|(c, _)| c.name.clone())
+            .collect::<Vec<_>>();
+        if cached.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::CachePolicyUnsupported {
+                policy,
+                name: self.name.clone(),
+                reason: format!(
+                    "children {:?} have a volatile write cache",
+                    cached
+                ),
+            })
+        }
+    }
+
     /// Undo share operation on nexus. To the chain of bdevs are all claimed
     /// where the top-level dev is claimed by the subsystem that exports the
     /// bdev. As such, we must first destroy the share and move our way down
     /// from there.
     pub async fn unshare(&mut self) -> Result<(), Error> {
         match self.nexus_target.take() {
-            Some(NexusTarget::NbdDisk(disk)) => {
-                disk.destroy();
-            }
-            Some(NexusTarget::NexusIscsiTarget(iscsi_target)) => {
-                iscsi_target.destroy().await;
+            Some(mut transport) => {
+                // keep the transport if it could not be torn down, such that
+                // unshare can be retried
+                if let Err(e) = transport.unpublish().await {
+                    self.nexus_target = Some(transport);
+                    return Err(e);
+                }
             }
             None => {
                 warn!("{} was not shared", self.name);
@@ -151,28 +924,29 @@ impl Nexus {
             }
         };
 
-        let bdev_name = self.share_handle.take().unwrap();
-        if let Some(bdev) = Bdev::lookup_by_name(&bdev_name) {
-            // if the share handle is the same as bdev name it
-            // implies there is no top level bdev, and we are done
-            if self.name != bdev.name() {
-                let (s, r) = oneshot::channel::<ErrnoResult<()>>();
-                // currently, we only have the crypto vbdev
-                unsafe {
-                    spdk_sys::delete_crypto_disk(
-                        bdev.as_ptr(),
-                        Some(done_errno_cb),
-                        cb_arg(s),
-                    );
-                }
-                r.await.expect("crypto delete sender is gone").context(
+        self.share_handle.take();
+        self.share_key.take();
+        if let Some(crypto_bdev) = self.crypto_bdev.take() {
+            if let Some(bdev) = Bdev::lookup_by_name(&crypto_bdev) {
+                destroy_crypto_bdev(&bdev).await.context(
                     DestroyCryptoBdev {
                         name: self.name.clone(),
                     },
                 )?;
+                // a lingering reference keeps the bdev around, which would
+                // make the next share with a key fail on the name
+                if Bdev::lookup_by_name(&crypto_bdev).is_some() {
+                    return Err(Error::CryptoBdevStillPresent {
+                        bdev: crypto_bdev,
+                        name: self.name.clone(),
+                    });
+                }
+            } else {
+                warn!(
+                    "Missing crypto bdev {} for a shared device",
+                    crypto_bdev
+                );
             }
-        } else {
-            warn!("Missing bdev for a shared device");
         }
         Ok(())
     }
@@ -181,8 +955,81 @@ impl Nexus {
     /// shared as nbd.
     pub fn get_share_path(&self) -> Option<String> {
         match self.nexus_target {
-            Some(NexusTarget::NbdDisk(ref disk)) => Some(disk.get_path()),
+            Some(ref transport)
+                if transport.protocol() == ShareProtocolNexus::NexusNbd =>
+            {
+                transport.uri()
+            }
             _ => None,
         }
     }
+
+    /// Return the key the nexus has been shared with, also when it has not
+    /// been encrypted with it, see ShareOptions::encrypt.
+    pub fn share_key(&self) -> Option<&str> {
+        self.share_key.as_deref()
+    }
+
+    /// Return true if the nexus is shared through a crypto bdev.
+    pub fn is_encrypted(&self) -> bool {
+        self.crypto_bdev.is_some()
+    }
+
+    /// Return a description of how the nexus is shared or None if it is not
+    /// shared at all.
+    pub fn share_info(&self) -> Option<ShareInfo> {
+        let transport = self.nexus_target.as_ref()?;
+        let share_handle = self.share_handle.clone()?;
+
+        Some(ShareInfo {
+            protocol: transport.protocol(),
+            uri: transport.uri()?,
+            encrypted: self.is_encrypted(),
+            share_handle,
+            ns_uuid: transport.ns_uuid(),
+        })
+    }
+}
+
+/// Unshare the nexuses with the given names, as when draining a node, with at
+/// most max_concurrent of them being unshared at the same time. A failure to
+/// unshare one nexus does not stop the others from being unshared. Returns
+/// the result for each name, in the order given, with NexusNotFound for
+/// names which do not belong to a nexus. Names given more than once are
+/// unshared, and reported, once.
+pub async fn unshare_nexuses(
+    names: &[String],
+    max_concurrent: usize,
+) -> Vec<(String, Result<(), Error>)> {
+    let mut unique: Vec<&String> = Vec::with_capacity(names.len());
+    for name in names {
+        if !unique.contains(&name) {
+            unique.push(name);
+        }
+    }
+
+    stream::iter(unique.into_iter().map(|name| async move {
+        let result = match nexus_lookup(name) {
+            Some(nexus) => nexus.unshare().await,
+            None => Err(Error::NexusNotFound {
+                name: name.clone(),
+            }),
+        };
+        if let Err(ref e) = result {
+            error!("Failed to unshare nexus {}: {}", name, e);
+        }
+        (name.clone(), result)
+    }))
+    .buffered(std::cmp::max(max_concurrent, 1))
+    .collect()
+    .await
+}
+
+/// Returns the name and share info of every nexus which is shared, in the
+/// order the nexuses have been created in.
+pub fn list_shared_nexuses() -> Vec<(String, ShareInfo)> {
+    instances()
+        .iter()
+        .filter_map(|n| n.share_info().map(|info| (n.name.clone(), info)))
+        .collect()
 }
@@ -0,0 +1,174 @@
+//! Introspection of the stack of bdevs a nexus is made of, from the target
+//! it is shared through, if any, down to the bdevs of its children, such
+//! that an operator can see what sits on top of what and which module holds
+//! the claim on each bdev, e.g. when a teardown does not make progress.
+//!
+//! The stack is returned as a graph by `Nexus::topology()`, which serializes
+//! to JSON as is and renders as graphviz DOT with `BdevGraph::to_dot()`.
+
+use serde::Serialize;
+
+use crate::{
+    bdev::nexus::{nexus_bdev::Nexus, nexus_child::NexusChild},
+    core::Bdev,
+};
+
+/// The role of a node in the stack of a nexus
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum NodeKind {
+    /// the target the nexus is shared through, named after its uri
+    Share,
+    /// the crypto bdev of an encrypted share
+    Crypto,
+    /// the nexus bdev itself
+    Nexus,
+    /// the bdev of a child, or the child alone if it has no bdev
+    Child,
+}
+
+/// A node of the graph, i.e. a bdev or the target on top of the stack
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BdevNode {
+    pub name: String,
+    pub kind: NodeKind,
+    /// module of the bdev, None for the share and for children without bdev
+    pub driver: Option<String>,
+    /// module which holds the claim on the bdev, if any
+    pub claimed_by: Option<String>,
+    /// state of the child, for children only
+    pub state: Option<String>,
+}
+
+/// An edge of the graph, from a node to the bdev it is built on
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BdevEdge {
+    pub from: String,
+    pub to: String,
+}
+
+/// The stack of bdevs of a nexus, see Nexus::topology(). Nodes are ordered
+/// top down, the share first and the children last.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BdevGraph {
+    pub nodes: Vec<BdevNode>,
+    pub edges: Vec<BdevEdge>,
+}
+
+impl BdevNode {
+    fn from_bdev(bdev: &Bdev, kind: NodeKind) -> Self {
+        Self {
+            name: bdev.name(),
+            kind,
+            driver: Some(bdev.driver()),
+            claimed_by: bdev.claimed_by(),
+            state: None,
+        }
+    }
+
+    fn from_child(child: &NexusChild) -> Self {
+        let mut node = match child.bdev.as_ref() {
+            Some(bdev) => Self::from_bdev(bdev, NodeKind::Child),
+            None => Self {
+                name: child.name.clone(),
+                kind: NodeKind::Child,
+                driver: None,
+                claimed_by: None,
+                state: None,
+            },
+        };
+        node.state = Some(child.state.to_string());
+        node
+    }
+}
+
+impl BdevGraph {
+    /// add an edge from the node to the bdev it is built on
+    fn edge(&mut self, from: &str, to: &str) {
+        self.edges.push(BdevEdge {
+            from: from.to_string(),
+            to: to.to_string(),
+        });
+    }
+
+    /// render the graph in the DOT language of graphviz, top down
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph nexus {\n");
+        for node in &self.nodes {
+            let mut label = format!("{:?}\\n{}", node.kind, node.name);
+            if let Some(ref driver) = node.driver {
+                label.push_str(&format!("\\ndriver: {}", driver));
+            }
+            if let Some(ref module) = node.claimed_by {
+                label.push_str(&format!("\\nclaimed by: {}", module));
+            }
+            if let Some(ref state) = node.state {
+                label.push_str(&format!("\\nstate: {}", state));
+            }
+            dot.push_str(&format!(
+                "  {:?} [label=\"{}\"];\n",
+                node.name,
+                label.replace('"', "\\\"")
+            ));
+        }
+        for edge in &self.edges {
+            dot.push_str(&format!("  {:?} -> {:?};\n", edge.from, edge.to));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+impl Nexus {
+    /// Return the stack of bdevs the nexus is made of: the share on top, if
+    /// the nexus is shared, followed by the crypto bdev of an encrypted
+    /// share, the nexus and the bdevs of its children. Each edge points from
+    /// a node to the bdev it is built on.
+    pub fn topology(&self) -> BdevGraph {
+        let mut graph = BdevGraph::default();
+        let nexus = BdevNode::from_bdev(&self.bdev, NodeKind::Nexus);
+
+        // a crypto bdev which has gone missing is shown nevertheless, as
+        // that is exactly the kind of thing the graph is meant to reveal
+        let crypto = self.crypto_bdev.as_ref().map(|name| {
+            match Bdev::lookup_by_name(name) {
+                Some(bdev) => BdevNode::from_bdev(&bdev, NodeKind::Crypto),
+                None => BdevNode {
+                    name: name.clone(),
+                    kind: NodeKind::Crypto,
+                    driver: None,
+                    claimed_by: None,
+                    state: None,
+                },
+            }
+        });
+
+        if let Some(info) = self.share_info() {
+            let top = crypto.as_ref().unwrap_or(&nexus).name.clone();
+            graph.edge(&info.uri, &top);
+            graph.nodes.push(BdevNode {
+                name: info.uri,
+                kind: NodeKind::Share,
+                driver: None,
+                claimed_by: None,
+                state: None,
+            });
+        }
+
+        if let Some(crypto) = crypto {
+            graph.edge(&crypto.name, &nexus.name);
+            graph.nodes.push(crypto);
+        }
+
+        let children = self
+            .children
+            .iter()
+            .map(BdevNode::from_child)
+            .collect::<Vec<_>>();
+        for child in &children {
+            graph.edge(&nexus.name, &child.name);
+        }
+        graph.nodes.push(nexus);
+        graph.nodes.extend(children);
+        graph
+    }
+}
@@ -0,0 +1,144 @@
+//! Utility functions and wrappers for working with vhost-user-blk controllers
+//! in SPDK.
+
+use std::{ffi::CString, fmt, path::Path, ptr};
+
+use nix::errno::Errno;
+use snafu::Snafu;
+
+use spdk_sys::{
+    spdk_vhost_blk_construct,
+    spdk_vhost_dev_find,
+    spdk_vhost_dev_remove,
+    spdk_vhost_lock,
+    spdk_vhost_set_socket_path,
+    spdk_vhost_unlock,
+};
+
+use crate::core::Bdev;
+
+/// directory in which the sockets of the vhost controllers are created
+const VHOST_SOCKET_DIR: &str = "/var/tmp";
+
+#[derive(Debug, Snafu)]
+pub enum NexusVhostError {
+    #[snafu(display("Bdev not found {}", dev))]
+    BdevNotFound { dev: String },
+    #[snafu(display("Vhost socket {} already exists", path))]
+    SocketExists { path: String },
+    #[snafu(display("Failed to set vhost socket directory {}", dir))]
+    SocketDir { dir: String },
+    #[snafu(display("Failed to create vhost controller for bdev {}", dev))]
+    CreateController { source: Errno, dev: String },
+    #[snafu(display("Vhost controller {} not found", name))]
+    ControllerNotFound { name: String },
+    #[snafu(display("Failed to remove vhost controller {}", name))]
+    RemoveController { source: Errno, name: String },
+}
+
+/// Vhost-user-blk controller representation.
+pub struct NexusVhostTarget {
+    /// name of the controller, which is the name of the bdev as well
+    ctrlr_name: String,
+    /// path of the unix socket VMs connect to
+    socket: String,
+}
+
+impl NexusVhostTarget {
+    /// Create a vhost-user-blk controller for the bdev, listening on a socket
+    /// named after the bdev. A socket left behind by someone else is not
+    /// touched, rather it is an error. A read only controller fails writes
+    /// from the VM.
+    pub fn create(
+        bdev_name: &str,
+        read_only: bool,
+    ) -> Result<Self, NexusVhostError> {
+        if Bdev::lookup_by_name(bdev_name).is_none() {
+            return Err(NexusVhostError::BdevNotFound {
+                dev: bdev_name.to_string(),
+            });
+        }
+
+        let socket = Path::new(VHOST_SOCKET_DIR)
+            .join(bdev_name)
+            .to_string_lossy()
+            .into_owned();
+        if Path::new(&socket).exists() {
+            return Err(NexusVhostError::SocketExists {
+                path: socket,
+            });
+        }
+
+        let c_dir = CString::new(VHOST_SOCKET_DIR).unwrap();
+        if unsafe { spdk_vhost_set_socket_path(c_dir.as_ptr()) } != 0 {
+            return Err(NexusVhostError::SocketDir {
+                dir: VHOST_SOCKET_DIR.to_string(),
+            });
+        }
+
+        let c_name = CString::new(bdev_name).unwrap();
+        let rc = unsafe {
+            spdk_vhost_blk_construct(
+                c_name.as_ptr(), // controller name
+                ptr::null(),     // default cpumask
+                c_name.as_ptr(), // bdev name
+                read_only,       // read only
+            )
+        };
+        if rc != 0 {
+            return Err(NexusVhostError::CreateController {
+                source: Errno::from_i32(rc.abs()),
+                dev: bdev_name.to_string(),
+            });
+        }
+
+        info!("Created vhost controller {} at {}", bdev_name, socket);
+        Ok(Self {
+            ctrlr_name: bdev_name.to_string(),
+            socket,
+        })
+    }
+
+    /// Remove the controller, which fails if a VM is still connected to it.
+    pub fn destroy(&self) -> Result<(), NexusVhostError> {
+        info!("Destroying vhost controller {}", self.ctrlr_name);
+        let c_name = CString::new(self.ctrlr_name.clone()).unwrap();
+
+        // the vhost device list may only be accessed with the lock held
+        unsafe { spdk_vhost_lock() };
+        let vdev = unsafe { spdk_vhost_dev_find(c_name.as_ptr()) };
+        let rc = if vdev.is_null() {
+            None
+        } else {
+            Some(unsafe { spdk_vhost_dev_remove(vdev) })
+        };
+        unsafe { spdk_vhost_unlock() };
+
+        match rc {
+            None => Err(NexusVhostError::ControllerNotFound {
+                name: self.ctrlr_name.clone(),
+            }),
+            Some(0) => Ok(()),
+            Some(rc) => Err(NexusVhostError::RemoveController {
+                source: Errno::from_i32(rc.abs()),
+                name: self.ctrlr_name.clone(),
+            }),
+        }
+    }
+
+    pub fn as_uri(&self) -> String {
+        format!("vhost://{}", self.socket)
+    }
+}
+
+impl fmt::Debug for NexusVhostTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{:?}", self.as_uri(), self.ctrlr_name)
+    }
+}
+
+impl fmt::Display for NexusVhostTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_uri())
+    }
+}
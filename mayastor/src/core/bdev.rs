@@ -18,6 +18,7 @@ use spdk_sys::{
     spdk_bdev_get_num_blocks,
     spdk_bdev_get_product_name,
     spdk_bdev_get_uuid,
+    spdk_bdev_has_write_cache,
     spdk_bdev_io_stat,
     spdk_bdev_io_type_supported,
     spdk_bdev_next,
@@ -93,6 +94,19 @@ impl Bdev {
         unsafe { !(*self.0).internal.claim_module.is_null() }
     }
 
+    /// returns the name of the module which claimed the bdev, if any
+    pub fn claimed_by(&self) -> Option<String> {
+        let module = unsafe { (*self.0).internal.claim_module };
+        if module.is_null() {
+            return None;
+        }
+        Some(
+            unsafe { CStr::from_ptr((*module).name) }
+                .to_string_lossy()
+                .into(),
+        )
+    }
+
     /// lookup a bdev by its name
     pub fn lookup_by_name(name: &str) -> Option<Bdev> {
         let name = std::ffi::CString::new(name).unwrap();
@@ -133,6 +147,12 @@ impl Bdev {
         self.num_blocks() * self.block_len() as u64
     }
 
+    /// returns true if the bdev has a volatile write cache, i.e. writes are
+    /// only durable once flushed
+    pub fn has_write_cache(&self) -> bool {
+        unsafe { spdk_bdev_has_write_cache(self.0) }
+    }
+
     /// returns the alignment of the bdev
     pub fn alignment(&self) -> u8 {
         unsafe { (*self.0).required_alignment }
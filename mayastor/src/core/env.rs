@@ -41,6 +41,7 @@ use spdk_sys::{
 };
 
 use crate::{
+    bdev::nexus::{self, nexus_share::cleanup_orphan_crypto_bdevs},
     core::{
         reactor,
         reactor::{Reactor, Reactors},
@@ -522,11 +523,24 @@ impl MayastorEnvironment {
             Err(_) => "127.0.0.1".to_owned(),
         };
 
-        if let Err(msg) = target::iscsi::init(&address) {
-            error!("Failed to initialize Mayastor iSCSI target: {}", msg);
-            return Err(EnvError::InitTarget {
-                target: "iscsi".into(),
-            });
+        // iscsi is usable even if only one of the sides could be set up
+        match target::iscsi::init(&address, true) {
+            Ok(state) => {
+                if state.sides.len() < 2 {
+                    warn!(
+                        "iSCSI target initialized for {:?} only: {:?}",
+                        state.sides, state
+                    );
+                } else {
+                    debug!("iSCSI target initialized: {:?}", state);
+                }
+            }
+            Err(msg) => {
+                error!("Failed to initialize Mayastor iSCSI target: {}", msg);
+                return Err(EnvError::InitTarget {
+                    target: "iscsi".into(),
+                });
+            }
         }
 
         let f = async move {
@@ -534,6 +548,13 @@ impl MayastorEnvironment {
                 error!("Failed to initialize Mayastor nvmf target: {}", msg);
                 mayastor_env_stop(-1);
             }
+
+            // reclaim crypto bdevs left behind by a previous instance
+            let known = nexus::instances()
+                .iter()
+                .map(|n| n.name.clone())
+                .collect::<Vec<_>>();
+            cleanup_orphan_crypto_bdevs(&known).await;
         };
 
         Reactor::block_on(f);
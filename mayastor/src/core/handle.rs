@@ -12,11 +12,14 @@ use serde::export::{fmt::Error, Formatter};
 
 use spdk_sys::{
     spdk_bdev_desc,
+    spdk_bdev_flush,
     spdk_bdev_free_io,
     spdk_bdev_io,
+    spdk_bdev_nvme_io_passthru,
     spdk_bdev_read,
     spdk_bdev_write,
     spdk_io_channel,
+    spdk_nvme_cmd,
 };
 
 use crate::{
@@ -168,6 +171,90 @@ impl BdevHandle {
             })
         }
     }
+
+    /// flush the given range, any write that completed before the flush was
+    /// submitted is durable once the flush completes.
+    pub async fn flush(&self, offset: u64, len: u64) -> Result<(), CoreError> {
+        let (s, r) = oneshot::channel::<bool>();
+        let errno = unsafe {
+            spdk_bdev_flush(
+                self.desc.as_ptr(),
+                self.channel.as_ptr(),
+                offset,
+                len,
+                Some(Self::io_completion_cb),
+                cb_arg(s),
+            )
+        };
+
+        if errno != 0 {
+            return Err(CoreError::FlushDispatch {
+                source: Errno::from_i32(errno),
+                offset,
+                len,
+            });
+        }
+
+        if r.await.expect("Failed awaiting flush IO") {
+            Ok(())
+        } else {
+            Err(CoreError::FlushFailed {
+                offset,
+                len,
+            })
+        }
+    }
+
+    /// submit an NVMe IO command with the given opcode and command dword 10
+    /// to the namespace backing the bdev, transferring the buffer if any. The
+    /// namespace id is filled in by the nvme bdev itself. Only bdevs which
+    /// support SPDK_BDEV_IO_TYPE_NVME_IO can do this.
+    pub async fn nvme_io_passthru(
+        &self,
+        opcode: u8,
+        cdw10: u32,
+        buffer: Option<&mut DmaBuf>,
+    ) -> Result<(), CoreError> {
+        // the command is built from its dwords as laid out by the NVMe spec
+        // (opcode in the low byte of dword 0), which is what spdk_nvme_cmd
+        // maps onto
+        let mut cmd = [0u32; 16];
+        cmd[0] = u32::from(opcode);
+        cmd[10] = cdw10;
+
+        let (buf, nbytes) = match buffer {
+            Some(buffer) => (**buffer, buffer.len() as u64),
+            None => (std::ptr::null_mut(), 0),
+        };
+
+        let (s, r) = oneshot::channel::<bool>();
+        let errno = unsafe {
+            spdk_bdev_nvme_io_passthru(
+                self.desc.as_ptr(),
+                self.channel.as_ptr(),
+                cmd.as_ptr() as *const spdk_nvme_cmd,
+                buf,
+                nbytes,
+                Some(Self::io_completion_cb),
+                cb_arg(s),
+            )
+        };
+
+        if errno != 0 {
+            return Err(CoreError::NvmeIoPassthruDispatch {
+                source: Errno::from_i32(errno),
+                opcode,
+            });
+        }
+
+        if r.await.expect("Failed awaiting NVMe IO passthru") {
+            Ok(())
+        } else {
+            Err(CoreError::NvmeIoPassthruFailed {
+                opcode,
+            })
+        }
+    }
 }
 
 impl Drop for BdevHandle {
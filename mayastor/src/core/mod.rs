@@ -77,4 +77,124 @@ pub enum CoreError {
         offset: u64,
         len: usize,
     },
+    #[snafu(display(
+        "Failed to dispatch flush at offset {} length {}",
+        offset,
+        len
+    ))]
+    FlushDispatch {
+        source: Errno,
+        offset: u64,
+        len: u64,
+    },
+    #[snafu(display("Flush failed at offset {} length {}", offset, len))]
+    FlushFailed {
+        offset: u64,
+        len: u64,
+    },
+    #[snafu(display("Failed to dispatch NVMe IO passthru opcode {}", opcode))]
+    NvmeIoPassthruDispatch {
+        source: Errno,
+        opcode: u8,
+    },
+    #[snafu(display("NVMe IO passthru opcode {} failed", opcode))]
+    NvmeIoPassthruFailed {
+        opcode: u8,
+    },
+}
+
+/// The category of a failure, which determines how it should be dealt with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorClass {
+    /// the device has run out of space
+    NoSpace,
+    /// the device is gone
+    DeviceRemoved,
+    /// the device did not respond in time
+    Timeout,
+    /// resources were temporarily exhausted, retrying may succeed
+    Transient,
+    /// the request itself was invalid, the device is not to blame
+    Invalid,
+    /// the device failed the IO
+    Failed,
+}
+
+impl ErrorClass {
+    fn from_errno(errno: Errno) -> Self {
+        match errno {
+            Errno::ENOSPC => ErrorClass::NoSpace,
+            Errno::ENODEV | Errno::ENXIO => ErrorClass::DeviceRemoved,
+            Errno::ETIMEDOUT => ErrorClass::Timeout,
+            Errno::ENOMEM | Errno::EAGAIN | Errno::EBUSY => {
+                ErrorClass::Transient
+            }
+            Errno::EINVAL => ErrorClass::Invalid,
+            _ => ErrorClass::Failed,
+        }
+    }
+}
+
+impl CoreError {
+    /// return the category of the error
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            CoreError::BdevNotFound {
+                ..
+            }
+            | CoreError::InvalidDescriptor {
+                ..
+            } => ErrorClass::DeviceRemoved,
+            CoreError::GetIoChannel {
+                ..
+            } => ErrorClass::Transient,
+            CoreError::InvalidOffset {
+                ..
+            } => ErrorClass::Invalid,
+            CoreError::OpenBdev {
+                source,
+            }
+            | CoreError::WriteDispatch {
+                source, ..
+            }
+            | CoreError::ReadDispatch {
+                source, ..
+            }
+            | CoreError::FlushDispatch {
+                source, ..
+            }
+            | CoreError::NvmeIoPassthruDispatch {
+                source, ..
+            } => ErrorClass::from_errno(*source),
+            CoreError::WriteFailed {
+                ..
+            }
+            | CoreError::ReadFailed {
+                ..
+            }
+            | CoreError::FlushFailed {
+                ..
+            } => ErrorClass::Failed,
+            // a passthru command which completes with an error status has
+            // been rejected by the device (i.e. a reservation conflict),
+            // which says nothing about its health
+            CoreError::NvmeIoPassthruFailed {
+                ..
+            } => ErrorClass::Invalid,
+        }
+    }
+
+    /// returns true if the device the error originates from can no longer
+    /// be trusted with IO and should be faulted, rather than the IO retried
+    /// or the error returned to the submitter
+    pub fn should_fault(&self) -> bool {
+        match self.class() {
+            ErrorClass::NoSpace
+            | ErrorClass::DeviceRemoved
+            | ErrorClass::Failed => true,
+            ErrorClass::Timeout
+            | ErrorClass::Transient
+            | ErrorClass::Invalid => false,
+        }
+    }
 }
@@ -31,4 +31,5 @@ macro_rules! CPS_INIT {
 
 pub extern "C" fn cps_init() {
     bdev::nexus::register_module();
+    target::iscsi_lun::register_module();
 }
@@ -118,7 +118,9 @@ pub fn nexus_uri_parse_vec(
 }
 
 /// Parse the given URI into a ChildBdev
-fn nexus_parse_uri(uri: &str) -> Result<BdevType, BdevCreateDestroy> {
+pub(crate) fn nexus_parse_uri(
+    uri: &str,
+) -> Result<BdevType, BdevCreateDestroy> {
     let parsed_uri = Url::parse(uri).context(UriInvalid {
         uri: uri.to_owned(),
     })?;
@@ -4,7 +4,7 @@ use crate::{
 };
 use crossbeam::channel::{unbounded, Receiver, Sender};
 use snafu::{ResultExt, Snafu};
-use std::fmt;
+use std::{cell::Cell, fmt};
 
 #[derive(Debug, Snafu)]
 #[snafu(visibility = "pub(crate)")]
@@ -26,6 +26,8 @@ pub enum RebuildState {
     Stopped,
     Failed,
     Completed,
+    /// cancelled through abort(), the destination has not been rebuilt
+    Aborted,
 }
 
 impl fmt::Display for RebuildState {
@@ -36,6 +38,7 @@ impl fmt::Display for RebuildState {
             RebuildState::Stopped => write!(f, "stopped"),
             RebuildState::Failed => write!(f, "failed"),
             RebuildState::Completed => write!(f, "completed"),
+            RebuildState::Aborted => write!(f, "aborted"),
         }
     }
 }
@@ -50,12 +53,16 @@ pub struct RebuildTask {
     block_size: u64,
     start: u64,
     end: u64,
+    /// the (start, end) block ranges within start..end to copy, in order
+    ranges: Vec<(u64, u64)>,
     current: u64,
     segment_size_blks: u64,
     copy_buffer: DmaBuf,
     complete_fn: fn(String, String) -> (),
     pub complete_chan: (Sender<RebuildState>, Receiver<RebuildState>),
     pub state: RebuildState,
+    /// checked by the copy loop between segments, see abort()
+    cancelled: Cell<bool>,
 }
 
 pub struct RebuildStats {}
@@ -75,6 +82,7 @@ impl RebuildTask {
         destination: String,
         start: u64,
         end: u64,
+        ranges: Vec<(u64, u64)>,
         complete_fn: fn(String, String) -> (),
     ) -> Result<RebuildTask, RebuildError> {
         let source_hdl =
@@ -89,7 +97,8 @@ impl RebuildTask {
         if !RebuildTask::validate(
             &source_hdl.get_bdev(),
             &destination_hdl.get_bdev(),
-        ) {
+        ) || ranges.iter().any(|&(s, e)| s >= e || s < start || e > end)
+        {
             return Err(RebuildError::InvalidParameters {});
         };
 
@@ -110,6 +119,7 @@ impl RebuildTask {
             destination_hdl,
             start,
             end,
+            ranges,
             current: start,
             block_size,
             segment_size_blks,
@@ -117,46 +127,80 @@ impl RebuildTask {
             complete_fn,
             complete_chan: unbounded::<RebuildState>(),
             state: RebuildState::Pending,
+            cancelled: Cell::new(false),
         })
     }
 
-    /// rebuild a non-healthy child from a healthy child from start to end
+    /// rebuild a non-healthy child from a healthy child, copying the ranges
+    /// of the task only
     async fn run(&mut self) {
         self.state = RebuildState::Running;
         self.current = self.start;
         self.stats();
 
-        while self.current < self.end {
-            if let Err(e) = self.copy_one().await {
-                error!("Failed to copy segment {}", e);
-                self.state = RebuildState::Failed;
-                self.send_complete();
-            }
-            // TODO: check if the task received a "pause" request, eg suspend
-            // rebuild
-            if self.state == RebuildState::Stopped {
-                return self.send_complete();
+        'copy: for (start, end) in self.ranges.clone() {
+            self.current = start;
+            while self.current < end {
+                if self.cancelled.get() {
+                    break 'copy;
+                }
+                if let Err(e) = self.copy_one(end).await {
+                    error!("Failed to copy segment {}", e);
+                    self.state = RebuildState::Failed;
+                    return self.send_complete();
+                }
+                // TODO: check if the task received a "pause" request, eg
+                // suspend rebuild
+                if self.state == RebuildState::Stopped {
+                    return self.send_complete();
+                }
             }
         }
 
-        self.state = RebuildState::Completed;
+        // an abort after the last segment still wins, as the caller has
+        // been told the destination will not be rebuilt
+        if self.cancelled.get() {
+            self.change_state(RebuildState::Aborted);
+        } else {
+            self.state = RebuildState::Completed;
+        }
         self.send_complete();
     }
 
-    /// copy one segment worth of data from source into destination
-    async fn copy_one(&mut self) -> Result<(), RebuildError> {
-        // Adjust size of the last segment
-        if (self.current + self.segment_size_blks) >= self.start + self.end {
-            self.segment_size_blks = self.end - self.current;
+    /// Cancel the rebuild, which stops at the next segment boundary and
+    /// completes with RebuildState::Aborted. A segment being copied is
+    /// copied in full. Returns false if the task has already finished,
+    /// in which case its outcome stands.
+    pub fn abort(&mut self) -> bool {
+        match self.state {
+            RebuildState::Pending | RebuildState::Running => {
+                self.cancelled.set(true);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// returns true if the rebuild has been aborted, or is about to be
+    pub fn is_aborted(&self) -> bool {
+        self.cancelled.get()
+    }
+
+    /// copy one segment worth of data from source into destination, or less
+    /// if the range being copied ends before that
+    async fn copy_one(&mut self, end: u64) -> Result<(), RebuildError> {
+        let blocks = std::cmp::min(self.segment_size_blks, end - self.current);
 
+        // Adjust size of the last segment of the range
+        if self.copy_buffer.len() as u64 != blocks * self.block_size {
             self.copy_buffer = self
                 .source_hdl
-                .dma_malloc((self.segment_size_blks * self.block_size) as usize)
+                .dma_malloc((blocks * self.block_size) as usize)
                 .context(NoCopyBuffer {})?;
 
             info!(
                 "Adjusting segment size to {}. offset: {}, start: {}, end: {}",
-                self.segment_size_blks, self.current, self.start, self.end
+                blocks, self.current, self.start, end
             );
         }
 
@@ -174,7 +218,7 @@ impl RebuildTask {
                 bdev: &self.destination,
             })?;
 
-        self.current += self.segment_size_blks;
+        self.current += blocks;
         Ok(())
     }
 
@@ -286,8 +286,10 @@ impl Replica {
                 .await
                 .context(ShareNvmf {})?,
             ShareType::Iscsi => {
+                // the replica does not keep any state of its own, the target
+                // is found again by its name when it is needed
                 target::iscsi::share(&uuid, &bdev, target::Side::Replica)
-                    .context(ShareIscsi {})?
+                    .context(ShareIscsi {})?;
             }
         }
         Ok(())
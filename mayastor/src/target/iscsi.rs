@@ -0,0 +1,1165 @@
+//! Methods for creating iscsi targets.
+//!
+//! We create a wildcard portal and initiator groups when mayastor starts up.
+//! These groups allow unauthenticated access for any initiator. Then when
+//! exporting a replica we use these default groups and create one target per
+//! replica with one lun - LUN0 unless another LUN id is asked for.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env,
+    ffi::{CStr, CString},
+    os::raw::{c_char, c_int, c_void},
+    ptr,
+    time::Duration,
+};
+
+use futures::{
+    channel::oneshot,
+    future::{self, Either},
+};
+use futures_timer::Delay;
+use nix::errno::Errno;
+use serde::Serialize;
+use snafu::Snafu;
+
+use spdk_sys::{
+    spdk_bdev_get_name,
+    spdk_iscsi_find_tgt_node,
+    spdk_iscsi_init_grp_create_from_initiator_list,
+    spdk_iscsi_init_grp_destroy,
+    spdk_iscsi_init_grp_unregister,
+    spdk_iscsi_portal_create,
+    spdk_iscsi_portal_grp_add_portal,
+    spdk_iscsi_portal_grp_create,
+    spdk_iscsi_portal_grp_find_by_tag,
+    spdk_iscsi_portal_grp_open,
+    spdk_iscsi_portal_grp_register,
+    spdk_iscsi_portal_grp_release,
+    spdk_iscsi_portal_grp_unregister,
+    spdk_iscsi_shutdown_tgt_node_by_name,
+    spdk_iscsi_tgt_node_add_pg_ig_maps,
+    spdk_iscsi_tgt_node_construct,
+    spdk_iscsi_tgt_node_delete_pg_ig_maps,
+    spdk_scsi_dev_get_lun,
+    SPDK_SCSI_DEV_MAX_LUN,
+};
+
+use crate::{
+    core::Bdev,
+    ffihelper::{cb_arg, errno_result_from_i32, ErrnoResult},
+    jsonrpc::{Code, RpcErrorCode},
+    target::{iscsi_lun, Side},
+};
+
+/// iSCSI target related errors
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Failed to create default portal group"))]
+    CreatePortalGroup {},
+    #[snafu(display("Failed to create default iscsi portal"))]
+    CreatePortal {},
+    #[snafu(display("Failed to add default portal to portal group"))]
+    AddPortal {},
+    #[snafu(display("Failed to register default portal group"))]
+    RegisterPortalGroup {},
+    #[snafu(display("Portal group {} does not exist", pg_no))]
+    PortalGroupNotFound { pg_no: c_int },
+    #[snafu(display(
+        "Failed to map portal group {} to iscsi target {}",
+        pg_no,
+        iqn
+    ))]
+    MapPortalGroup { pg_no: c_int, iqn: String },
+    #[snafu(display("Failed to create default initiator group"))]
+    CreateInitiatorGroup {},
+    #[snafu(display("Failed to create iscsi target"))]
+    CreateTarget {},
+    #[snafu(display("Failed to destroy iscsi target"))]
+    DestroyTarget { source: Errno },
+    #[snafu(display(
+        "Timed out after {:?} waiting for iscsi target {} to be destroyed",
+        timeout,
+        iqn
+    ))]
+    DestroyTimeout { iqn: String, timeout: Duration },
+    #[snafu(display("Invalid iqn prefix \"{}\"", prefix))]
+    InvalidIqnPrefix { prefix: String },
+    #[snafu(display("Reached the limit of {} iscsi targets", max))]
+    TargetLimitReached { max: usize },
+    #[snafu(display("Target must have at least one LUN"))]
+    NoLuns {},
+    #[snafu(display("Invalid LUN id {}, the maximum is {}", lun_id, max))]
+    InvalidLunId { lun_id: u32, max: u32 },
+    #[snafu(display("Failed to create the bdev of LUN{} {}", lun_id, name))]
+    CreateLunBdev {
+        source: Errno,
+        lun_id: u32,
+        name: String,
+    },
+    #[snafu(display("The {} contains a NUL character", which))]
+    InvalidString { which: String },
+}
+
+impl RpcErrorCode for Error {
+    fn rpc_error_code(&self) -> Code {
+        match self {
+            Error::InvalidString {
+                ..
+            } => Code::InvalidParams,
+            _ => Code::InternalError,
+        }
+    }
+}
+
+type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// iscsi target port number
+const ISCSI_PORT_NEXUS: u16 = 3260;
+const ISCSI_PORT_REPLICA: u16 = 3262;
+
+const ISCSI_PORTAL_GROUP_NEXUS: c_int = 0;
+const ISCSI_PORTAL_GROUP_REPLICA: c_int = 2;
+
+const ISCSI_INITIATOR_GROUP: c_int = 0; //only 1 for now
+
+/// default maximum queue depth of a target
+pub const ISCSI_QUEUE_DEPTH: c_int = 128;
+
+/// maximum number of target nodes supported by spdk (MAX_ISCSI_TARGET_NODE)
+const ISCSI_MAX_TARGETS: usize = 4096;
+
+/// highest LUN id a target can have
+pub const ISCSI_MAX_LUN_ID: u32 = SPDK_SCSI_DEV_MAX_LUN - 1;
+
+/// number of times destroying a busy target is retried by unshare()
+const DESTROY_RETRIES: u32 = 3;
+/// delay before the first retry, doubled after each attempt
+const DESTROY_RETRY_DELAY: Duration = Duration::from_millis(100);
+/// default time to wait for spdk to destroy a target, see
+/// set_destroy_timeout()
+const DEFAULT_DESTROY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// naming authority used for iqns unless configured otherwise
+const DEFAULT_IQN_PREFIX: &str = "iqn.2019-05.io.openebs";
+/// environment variable used to override the naming authority
+const IQN_PREFIX_ENV: &str = "MAYASTOR_IQN_PREFIX";
+
+thread_local! {
+    /// iscsi global state.
+    ///
+    /// It is thread-local because TLS is safe to access in rust without any
+    /// synchronization overhead. It should be accessed only from
+    /// reactor_0 thread.
+    ///
+    /// A counter used for assigning idx to newly created iscsi targets.
+    static ISCSI_IDX: RefCell<i32> = RefCell::new(0);
+    /// iscsi targets which currently exist, keyed by iqn, with the portal
+    /// and initiator group they have been created with.
+    static ISCSI_TARGETS: RefCell<HashMap<String, (c_int, c_int)>> =
+        RefCell::new(HashMap::new());
+    /// Bdevs created for the LUNs of iscsi targets which have flags, keyed by
+    /// the iqn of the target, see iscsi_lun.
+    static LUN_BDEVS: RefCell<HashMap<String, Vec<String>>> =
+        RefCell::new(HashMap::new());
+    /// IP address of iscsi portal used for all created iscsi targets.
+    static ADDRESS: RefCell<Option<String>> = RefCell::new(None);
+    /// Portal groups which have been created so far, keyed by the address
+    /// and the side they are serving.
+    static PORTAL_GROUPS: RefCell<HashMap<(String, Side), c_int>> =
+        RefCell::new(HashMap::new());
+    /// Portal groups created by add_portal_to_group(), keyed by the index of
+    /// the group whose targets they export on another address.
+    static PORTAL_ALIASES: RefCell<HashMap<c_int, Vec<c_int>>> =
+        RefCell::new(HashMap::new());
+    /// A counter used for assigning idx to portal groups created on demand.
+    static PORTAL_GROUP_IDX: RefCell<c_int> =
+        RefCell::new(ISCSI_PORTAL_GROUP_REPLICA + 1);
+    /// What has been created by init(), None if not initialized or if it
+    /// has been torn down by fini() already.
+    static INIT_STATE: RefCell<Option<IscsiInitState>> = RefCell::new(None);
+    /// Naming authority prefix of all iqns we generate.
+    static IQN_PREFIX: RefCell<String> =
+        RefCell::new(DEFAULT_IQN_PREFIX.to_owned());
+    /// How long to wait for each attempt of destroying a target to complete.
+    static DESTROY_TIMEOUT: RefCell<Duration> =
+        RefCell::new(DEFAULT_DESTROY_TIMEOUT);
+}
+
+/// Ports and indices of the portal and initiator groups created by init().
+#[derive(Debug, Clone, PartialEq)]
+pub struct IscsiInitState {
+    /// port of the portal used by nexus (frontend) targets
+    pub fe_port: u16,
+    /// port of the portal used by replica (backend) targets
+    pub be_port: u16,
+    /// index of the frontend portal group
+    pub fe_pg: c_int,
+    /// index of the backend portal group
+    pub be_pg: c_int,
+    /// index of the initiator group used by all targets
+    pub ig: c_int,
+    /// sides for which the portal group has been created, that is the roles
+    /// in which targets can be exported on the default address
+    pub sides: Vec<Side>,
+}
+
+impl IscsiInitState {
+    /// return true if targets of the given side can be exported
+    pub fn serves(&self, side: Side) -> bool {
+        self.sides.contains(&side)
+    }
+}
+
+/// IO statistics of an iscsi target, as returned by target_stats(). The
+/// counters are those of the bdev behind the target since it was created;
+/// spdk does not count failed IOs, so there are no error counters.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TargetStats {
+    /// iqn of the target
+    pub iqn: String,
+    pub num_read_ops: u64,
+    pub num_write_ops: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// maximum queue depth of the target
+    pub queue_depth: u32,
+    /// number of initiator connections to the target
+    pub active_connections: u32,
+}
+
+/// Per-LUN flags of an iscsi target. The default is a read-write LUN which
+/// follows the cache policy of the device. A LUN with other flags exports a
+/// bdev stacked on top of its own, which enforces them, see iscsi_lun.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LunFlags {
+    /// reject writes to the LUN
+    pub read_only: bool,
+    /// report the LUN as having no volatile write cache
+    pub write_through: bool,
+}
+
+/// A bdev exported as a LUN of an iscsi target.
+#[derive(Debug)]
+pub struct Lun<'a> {
+    pub bdev: &'a Bdev,
+    pub flags: LunFlags,
+}
+
+/// Return the name of the bdev created for the LUN with the given id of the
+/// target named after bdev_name, if the LUN has flags.
+pub fn lun_bdev_name(bdev_name: &str, lun_id: u32) -> String {
+    format!("{}-lun{}", bdev_name, lun_id)
+}
+
+/// What has been created when sharing a bdev, to be kept by the caller such
+/// that the target can be queried and torn down without deriving everything
+/// from the name of the bdev again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShareHandle {
+    /// iqn of the target
+    pub iqn: String,
+    /// index the target has been created with
+    pub target_idx: c_int,
+    /// name of the bdev the target is named after
+    pub bdev_name: String,
+    /// side the target is serving
+    pub side: Side,
+    /// index of the portal group the target is exported through
+    pub pg_idx: c_int,
+    /// index of the initiator group allowed to access the target
+    pub ig_idx: c_int,
+    /// id of the first LUN of the target
+    pub first_lun_id: u32,
+}
+
+impl ShareHandle {
+    /// Return the IO statistics of the target, see target_stats().
+    pub async fn stats(&self) -> Option<TargetStats> {
+        iqn_stats(&self.iqn, &self.bdev_name).await
+    }
+
+    /// Destroy the target, see unshare().
+    pub async fn unshare(self) -> Result<()> {
+        destroy_target(&self.iqn, DESTROY_RETRIES, DESTROY_RETRY_DELAY).await
+    }
+}
+
+/// Convert a string passed on to spdk into a C string, failing if the string
+/// contains a NUL character, which as the string may originate from a
+/// request would otherwise bring down the reactor. The kind of string is
+/// given by which, for the error message.
+fn c_string(s: &str, which: &str) -> Result<CString> {
+    CString::new(s).map_err(|_| Error::InvalidString {
+        which: which.to_owned(),
+    })
+}
+
+/// Generate iqn based on provided bdev_name
+pub fn target_name(bdev_name: &str) -> String {
+    IQN_PREFIX.with(|prefix| format!("{}:{}", prefix.borrow(), bdev_name))
+}
+
+/// The inverse of target_name(), returns the bdev name (uuid) of the iqn or
+/// None if the iqn has not been named by us with the configured prefix.
+pub fn uuid_from_target_name(iqn: &str) -> Option<String> {
+    IQN_PREFIX.with(|prefix| {
+        let prefix = prefix.borrow();
+        if !iqn.starts_with(prefix.as_str()) {
+            return None;
+        }
+        let name = &iqn[prefix.len() ..];
+        if name.len() > 1 && name.starts_with(':') {
+            Some(name[1 ..].to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Check that the prefix follows the iqn grammar "iqn.YYYY-MM.reverse.domain"
+/// as some initiators reject iqns which do not.
+pub fn valid_iqn_prefix(prefix: &str) -> bool {
+    let parts = prefix.splitn(3, '.').collect::<Vec<_>>();
+    if parts.len() != 3 || parts[0] != "iqn" {
+        return false;
+    }
+
+    let date = parts[1].split('-').collect::<Vec<_>>();
+    if date.len() != 2
+        || date[0].len() != 4
+        || date[1].len() != 2
+        || !date.iter().all(|d| d.chars().all(|c| c.is_ascii_digit()))
+    {
+        return false;
+    }
+    match date[1].parse::<u32>() {
+        Ok(month) if (1 ..= 12).contains(&month) => (),
+        _ => return false,
+    }
+
+    parts[2].split('.').all(|label| {
+        !label.is_empty()
+            && label.chars().all(|c| {
+                c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-'
+            })
+    })
+}
+
+/// Create iscsi portal and initiator group which will be used later when
+/// creating iscsi targets. The naming authority of the iqns can be changed
+/// by setting MAYASTOR_IQN_PREFIX. What has been created is returned and
+/// remembered for fini().
+///
+/// If allow_partial is set, failing to create the portal group of one side
+/// is not fatal, iscsi is then available for the other side only, as
+/// reported by the sides of the returned state.
+pub fn init(address: &str, allow_partial: bool) -> Result<IscsiInitState> {
+    if let Ok(prefix) = env::var(IQN_PREFIX_ENV) {
+        if !valid_iqn_prefix(&prefix) {
+            return Err(Error::InvalidIqnPrefix {
+                prefix,
+            });
+        }
+        IQN_PREFIX.with(move |p| {
+            *p.borrow_mut() = prefix;
+        });
+    }
+
+    let mut state = IscsiInitState {
+        fe_port: ISCSI_PORT_NEXUS,
+        be_port: ISCSI_PORT_REPLICA,
+        fe_pg: ISCSI_PORTAL_GROUP_NEXUS,
+        be_pg: ISCSI_PORTAL_GROUP_REPLICA,
+        ig: ISCSI_INITIATOR_GROUP,
+        sides: Vec::new(),
+    };
+
+    let groups = [
+        (Side::Replica, state.be_port, state.be_pg),
+        (Side::Nexus, state.fe_port, state.fe_pg),
+    ];
+    let mut failed = None;
+
+    for (side, port, pg_idx) in groups.iter() {
+        match create_portal_group(address, *port, *pg_idx) {
+            Ok(()) => {
+                register_portal_group(address, *side, *pg_idx);
+                state.sides.push(*side);
+            }
+            Err(e) => {
+                if !allow_partial || failed.is_some() {
+                    fini();
+                    return Err(e);
+                }
+                warn!(
+                    "Failed to create iscsi portal group for {:?} on {}:{}: {}",
+                    side, address, port, e
+                );
+                failed = Some(e);
+            }
+        }
+    }
+
+    if let Err(e) = create_initiator_group(state.ig) {
+        fini();
+        return Err(e);
+    }
+    ADDRESS.with(move |addr| {
+        *addr.borrow_mut() = Some(address.to_owned());
+    });
+    INIT_STATE.with(|s| *s.borrow_mut() = Some(state.clone()));
+    debug!(
+        "Created default iscsi initiator group and portal groups for {:?} on address {}",
+        state.sides, address
+    );
+
+    Ok(state)
+}
+
+/// Remember the portal group serving the given address and side.
+fn register_portal_group(address: &str, side: Side, pg_idx: c_int) {
+    PORTAL_GROUPS.with(move |groups| {
+        groups
+            .borrow_mut()
+            .insert((address.to_owned(), side), pg_idx);
+    });
+}
+
+/// Destroy all portal groups we have created, including those created on
+/// demand by portal_group_for().
+fn destroy_portal_groups() {
+    let aliases = PORTAL_ALIASES
+        .with(|aliases| aliases.borrow_mut().drain().collect::<Vec<_>>());
+    for pg_idx in aliases.into_iter().flat_map(|(_, tags)| tags) {
+        destroy_portal_group(pg_idx);
+    }
+    let groups = PORTAL_GROUPS
+        .with(|groups| groups.borrow_mut().drain().collect::<Vec<_>>());
+    for (_, pg_idx) in groups {
+        destroy_portal_group(pg_idx);
+    }
+}
+
+/// Return the port number used by portals of the given side.
+fn portal_port(side: Side) -> u16 {
+    match side {
+        Side::Nexus => ISCSI_PORT_NEXUS,
+        Side::Replica => ISCSI_PORT_REPLICA,
+    }
+}
+
+/// Return index of the portal group for the given address and side. If there
+/// is no such portal group yet, it is created and remembered, so that targets
+/// on multi-homed nodes can be exported on any of the node's addresses.
+pub fn portal_group_for(address: &str, side: Side) -> Result<c_int> {
+    let existing = PORTAL_GROUPS.with(|groups| {
+        groups.borrow().get(&(address.to_owned(), side)).copied()
+    });
+    if let Some(pg_idx) = existing {
+        return Ok(pg_idx);
+    }
+
+    let pg_idx = next_portal_group_idx();
+    create_portal_group(address, portal_port(side), pg_idx)?;
+    register_portal_group(address, side, pg_idx);
+    Ok(pg_idx)
+}
+
+/// Return a portal group index which has not been used yet.
+fn next_portal_group_idx() -> c_int {
+    PORTAL_GROUP_IDX.with(move |pg| {
+        let idx = *pg.borrow();
+        *pg.borrow_mut() = idx + 1;
+        idx
+    })
+}
+
+/// Return the index of the portal group and of the groups which have been
+/// added to it by add_portal_to_group(), that is all groups a target of the
+/// portal group is to be mapped to.
+fn portal_group_tags(pg_idx: c_int) -> Vec<c_int> {
+    let mut tags = vec![pg_idx];
+    PORTAL_ALIASES.with(|aliases| {
+        if let Some(extra) = aliases.borrow().get(&pg_idx) {
+            tags.extend(extra);
+        }
+    });
+    tags
+}
+
+/// Destroy the groups created by init() and any portal groups created on
+/// demand since. Only groups which exist are destroyed, so it is safe to
+/// call it more than once, or after init() failed half way.
+pub fn fini() {
+    // the initiator group is created last, hence it exists if, and only if,
+    // init() has succeeded
+    if let Some(state) = INIT_STATE.with(|s| s.borrow_mut().take()) {
+        destroy_initiator_group(state.ig);
+    }
+    // portal groups are registered as soon as they are created
+    destroy_portal_groups();
+}
+
+/// Return what has been created by init(), None if not initialized.
+pub fn init_state() -> Option<IscsiInitState> {
+    INIT_STATE.with(|s| s.borrow().clone())
+}
+
+/// Return index of the initiator group created by init().
+fn initiator_group() -> c_int {
+    INIT_STATE
+        .with(|s| s.borrow().as_ref().map(|s| s.ig))
+        .unwrap_or(ISCSI_INITIATOR_GROUP)
+}
+
+fn share_as_iscsi_target(
+    bdev_name: &str,
+    luns: &[Lun<'_>],
+    first_lun_id: u32,
+    address: &str,
+    side: Side,
+    ig_idx: c_int,
+    queue_depth: c_int,
+) -> Result<ShareHandle, Error> {
+    if luns.is_empty() {
+        return Err(Error::NoLuns {});
+    }
+    let last_lun_id = u64::from(first_lun_id) + luns.len() as u64 - 1;
+    if last_lun_id > u64::from(ISCSI_MAX_LUN_ID) {
+        return Err(Error::InvalidLunId {
+            lun_id: last_lun_id as u32,
+            max: ISCSI_MAX_LUN_ID,
+        });
+    }
+
+    let iqn = target_name(bdev_name);
+    let c_iqn = c_string(&iqn, "iqn")?;
+    let pg_idx = portal_group_for(address, side)?;
+    // the target is reachable on every address the portal group has been
+    // extended to
+    let mut pg_tags = portal_group_tags(pg_idx);
+    let mut ig_tags = vec![ig_idx; pg_tags.len()];
+    let num_maps = pg_tags.len() as c_int;
+
+    // LUNs are numbered, from the first id, in the order in which they are
+    // given
+    let lun_bdevs = create_lun_bdevs(bdev_name, luns, first_lun_id)?;
+    let mut bdev_names: Vec<*const c_char> = luns
+        .iter()
+        .zip(lun_bdevs.iter())
+        .map(|(lun, lun_bdev)| {
+            let bdev = lun_bdev.as_ref().unwrap_or(lun.bdev);
+            unsafe { spdk_bdev_get_name(bdev.as_ptr()) }
+        })
+        .collect();
+    let mut lun_ids: Vec<c_int> = (0 .. luns.len() as c_int)
+        .map(|i| first_lun_id as c_int + i)
+        .collect();
+    let lun_bdevs = lun_bdevs
+        .into_iter()
+        .filter_map(|b| b.map(|b| b.name()))
+        .collect::<Vec<_>>();
+    // the index is only used up once the target has been created
+    let idx = ISCSI_IDX.with(|iscsi_idx| *iscsi_idx.borrow());
+
+    let tgt = unsafe {
+        spdk_iscsi_tgt_node_construct(
+            idx,                     // target_index
+            c_iqn.as_ptr(),          // name
+            ptr::null(),             // alias
+            pg_tags.as_mut_ptr(),    // pg_tag_list
+            ig_tags.as_mut_ptr(),    // ig_tag_list
+            num_maps,                // portal and initiator group list length
+            bdev_names.as_mut_ptr(), // bdevs exported as LUNs
+            lun_ids.as_mut_ptr(),    // lun ids
+            luns.len() as c_int,     // length of lun id list
+            queue_depth,             // max queue depth
+            false,                   // disable chap
+            false,                   // require chap
+            false,                   // mutual chap
+            0,                       // chap group
+            false,                   // header digest
+            false,                   // data digest
+        )
+    };
+    if tgt.is_null() {
+        error!("Failed to create iscsi target {}", iqn);
+        lun_bdevs.iter().for_each(|name| iscsi_lun::destroy(name));
+        Err(Error::CreateTarget {})
+    } else {
+        ISCSI_IDX.with(|iscsi_idx| *iscsi_idx.borrow_mut() = idx + 1);
+        ISCSI_TARGETS.with(|targets| {
+            targets.borrow_mut().insert(iqn.clone(), (pg_idx, ig_idx))
+        });
+        if !lun_bdevs.is_empty() {
+            LUN_BDEVS.with(|bdevs| {
+                bdevs.borrow_mut().insert(iqn.clone(), lun_bdevs)
+            });
+        }
+        Ok(ShareHandle {
+            iqn,
+            target_idx: idx,
+            bdev_name: bdev_name.to_owned(),
+            side,
+            pg_idx,
+            ig_idx,
+            first_lun_id,
+        })
+    }
+}
+
+/// Create the bdevs of the LUNs which have flags, see iscsi_lun. The bdev of
+/// a LUN without flags is exported as is, hence has None in the returned
+/// list. Either all bdevs are created or none are.
+fn create_lun_bdevs(
+    bdev_name: &str,
+    luns: &[Lun<'_>],
+    first_lun_id: u32,
+) -> Result<Vec<Option<Bdev>>> {
+    let mut lun_bdevs = Vec::new();
+    for (lun_id, lun) in (first_lun_id ..).zip(luns.iter()) {
+        if lun.flags == LunFlags::default() {
+            lun_bdevs.push(None);
+            continue;
+        }
+        let name = lun_bdev_name(bdev_name, lun_id);
+        match iscsi_lun::create(&name, lun.bdev, lun.flags) {
+            Ok(bdev) => lun_bdevs.push(Some(bdev)),
+            Err(source) => {
+                lun_bdevs
+                    .iter()
+                    .flatten()
+                    .for_each(|bdev| iscsi_lun::destroy(&bdev.name()));
+                return Err(Error::CreateLunBdev {
+                    source,
+                    lun_id,
+                    name,
+                });
+            }
+        }
+    }
+    Ok(lun_bdevs)
+}
+
+/// Return the number of iscsi targets in use and the maximum number of
+/// targets which can be created.
+pub fn capacity() -> (usize, usize) {
+    let used = ISCSI_TARGETS.with(|targets| targets.borrow().len());
+    (used, ISCSI_MAX_TARGETS)
+}
+
+/// Export given bdev over iscsi. That involves creating iscsi target and
+/// adding the bdev as LUN to it. The returned handle describes the target.
+pub fn share(bdev_name: &str, bdev: &Bdev, side: Side) -> Result<ShareHandle> {
+    share_with_queue_depth(bdev_name, bdev, side, ISCSI_QUEUE_DEPTH)
+}
+
+/// Same as share() but with the given maximum queue depth for the target.
+pub fn share_with_queue_depth(
+    bdev_name: &str,
+    bdev: &Bdev,
+    side: Side,
+    queue_depth: c_int,
+) -> Result<ShareHandle> {
+    share_with_lun_id(bdev_name, bdev, side, queue_depth, 0)
+}
+
+/// Same as share_with_queue_depth() but the bdev is exported as the LUN with
+/// the given id rather than LUN0.
+pub fn share_with_lun_id(
+    bdev_name: &str,
+    bdev: &Bdev,
+    side: Side,
+    queue_depth: c_int,
+    lun_id: u32,
+) -> Result<ShareHandle> {
+    let address = ADDRESS.with(|a| a.borrow().clone().unwrap());
+    share_on(&address, bdev_name, bdev, side, queue_depth, lun_id)
+}
+
+/// Same as share_with_lun_id() but the target is exported through the
+/// portal group listening on the given address rather than the default one.
+pub fn share_on(
+    address: &str,
+    bdev_name: &str,
+    bdev: &Bdev,
+    side: Side,
+    queue_depth: c_int,
+    lun_id: u32,
+) -> Result<ShareHandle> {
+    share_luns_on(
+        address,
+        bdev_name,
+        &[Lun {
+            bdev,
+            flags: LunFlags::default(),
+        }],
+        lun_id,
+        side,
+        queue_depth,
+    )
+}
+
+/// Export the given bdevs as LUNs of a single iscsi target, named after
+/// bdev_name, through the default portal of the side. The first bdev is
+/// LUN0, the second LUN1 and so on. LUNs with the default flags export their
+/// bdev as is.
+pub fn share_luns(
+    bdev_name: &str,
+    luns: &[Lun<'_>],
+    side: Side,
+    queue_depth: c_int,
+) -> Result<ShareHandle> {
+    let address = ADDRESS.with(|a| a.borrow().clone().unwrap());
+    share_luns_on(&address, bdev_name, luns, 0, side, queue_depth)
+}
+
+fn share_luns_on(
+    address: &str,
+    bdev_name: &str,
+    luns: &[Lun<'_>],
+    first_lun_id: u32,
+    side: Side,
+    queue_depth: c_int,
+) -> Result<ShareHandle> {
+    let (used, max) = capacity();
+    if used >= max {
+        return Err(Error::TargetLimitReached {
+            max,
+        });
+    }
+
+    let handle = share_as_iscsi_target(
+        bdev_name,
+        luns,
+        first_lun_id,
+        address,
+        side,
+        initiator_group(),
+        queue_depth,
+    )?;
+    info!(
+        "Created iscsi target {} for {} with {} LUN(s)",
+        handle.iqn,
+        bdev_name,
+        luns.len()
+    );
+    Ok(handle)
+}
+
+/// Set how long unshare() waits for spdk to destroy a target, per attempt,
+/// before giving up with DestroyTimeout.
+pub fn set_destroy_timeout(timeout: Duration) {
+    DESTROY_TIMEOUT.with(|t| *t.borrow_mut() = timeout);
+}
+
+/// Return how long unshare() waits for spdk to destroy a target.
+pub fn destroy_timeout() -> Duration {
+    DESTROY_TIMEOUT.with(|t| *t.borrow())
+}
+
+/// Undo export of a bdev over iscsi done above.
+pub async fn unshare(bdev_name: &str) -> Result<()> {
+    unshare_with_retry(bdev_name, DESTROY_RETRIES, DESTROY_RETRY_DELAY).await
+}
+
+/// Same as unshare() but lets the caller choose how many times and after
+/// what initial delay the destruction of a target is retried, if the target
+/// is busy completing IO. The delay is doubled after each attempt. If spdk
+/// does not complete an attempt within destroy_timeout(), the target is
+/// considered to be stuck and DestroyTimeout is returned.
+pub async fn unshare_with_retry(
+    bdev_name: &str,
+    retries: u32,
+    delay: Duration,
+) -> Result<()> {
+    destroy_target(&target_name(bdev_name), retries, delay).await
+}
+
+/// Destroy the target with the given iqn, retrying as described for
+/// unshare_with_retry().
+async fn destroy_target(
+    iqn: &str,
+    retries: u32,
+    delay: Duration,
+) -> Result<()> {
+    let c_iqn = c_string(iqn, "iqn")?;
+    let mut delay = delay;
+    let mut attempt = 0;
+    let timeout = destroy_timeout();
+
+    info!("Destroying iscsi target {}", iqn);
+
+    loop {
+        let result = match shutdown_target(&c_iqn, timeout).await {
+            Some(result) => result,
+            None => {
+                error!(
+                    "iscsi target {} not destroyed within {:?}",
+                    iqn, timeout
+                );
+                return Err(Error::DestroyTimeout {
+                    iqn: iqn.to_owned(),
+                    timeout,
+                });
+            }
+        };
+        match result {
+            Ok(()) => break,
+            Err(errno)
+                if attempt < retries
+                    && (errno == Errno::EBUSY || errno == Errno::EAGAIN) =>
+            {
+                attempt += 1;
+                warn!(
+                    "iscsi target {} is busy, retrying in {:?} ({}/{})",
+                    iqn, delay, attempt, retries
+                );
+                Delay::new(delay).await;
+                delay *= 2;
+            }
+            Err(errno) => {
+                return Err(Error::DestroyTarget {
+                    source: errno,
+                })
+            }
+        }
+    }
+
+    ISCSI_TARGETS.with(|targets| targets.borrow_mut().remove(iqn));
+    // the target has let go of the bdevs of its LUNs
+    if let Some(lun_bdevs) =
+        LUN_BDEVS.with(|bdevs| bdevs.borrow_mut().remove(iqn))
+    {
+        lun_bdevs.iter().for_each(|name| iscsi_lun::destroy(name));
+    }
+    info!("Destroyed iscsi target {}", iqn);
+    Ok(())
+}
+
+/// Shutdown the target with given iqn once, returns None if spdk has not
+/// completed the shutdown within the given time
+async fn shutdown_target(
+    c_iqn: &CStr,
+    timeout: Duration,
+) -> Option<ErrnoResult<()>> {
+    let (sender, receiver) = oneshot::channel::<ErrnoResult<()>>();
+
+    unsafe {
+        spdk_iscsi_shutdown_tgt_node_by_name(
+            c_iqn.as_ptr(),
+            Some(shutdown_done_cb),
+            cb_arg(sender),
+        );
+    }
+
+    match future::select(receiver, Delay::new(timeout)).await {
+        Either::Left((result, _)) => {
+            Some(result.expect("Cancellation is not supported"))
+        }
+        Either::Right(_) => None,
+    }
+}
+
+/// Completion callback of shutdown_target(). Unlike done_errno_cb() it
+/// tolerates the receiver being gone, which happens when the shutdown
+/// completes after we have stopped waiting for it.
+extern "C" fn shutdown_done_cb(sender_ptr: *mut c_void, errno: i32) {
+    let sender = unsafe {
+        Box::from_raw(sender_ptr as *mut oneshot::Sender<ErrnoResult<()>>)
+    };
+
+    if sender.send(errno_result_from_i32((), errno)).is_err() {
+        warn!("iscsi target shutdown completed after timing out");
+    }
+}
+
+fn create_initiator_group(ig_idx: c_int) -> Result<()> {
+    let initiator_host = CString::new("ANY").unwrap();
+    let initiator_netmask = CString::new("ANY").unwrap();
+
+    unsafe {
+        if spdk_iscsi_init_grp_create_from_initiator_list(
+            ig_idx,
+            1,
+            &mut (initiator_host.as_ptr() as *mut c_char) as *mut _,
+            1,
+            &mut (initiator_netmask.as_ptr() as *mut c_char) as *mut _,
+        ) != 0
+        {
+            return Err(Error::CreateInitiatorGroup {});
+        }
+    }
+    Ok(())
+}
+
+fn destroy_initiator_group(ig_idx: c_int) {
+    unsafe {
+        let ig = spdk_iscsi_init_grp_unregister(ig_idx);
+        if !ig.is_null() {
+            spdk_iscsi_init_grp_destroy(ig);
+        }
+    }
+}
+
+fn create_portal_group(
+    address: &str,
+    port_no: u16,
+    pg_no: c_int,
+) -> Result<()> {
+    let portal_port = c_string(&port_no.to_string(), "portal port")?;
+    let portal_host = c_string(address, "portal address")?;
+    let pg = unsafe { spdk_iscsi_portal_grp_create(pg_no) };
+    if pg.is_null() {
+        return Err(Error::CreatePortalGroup {});
+    }
+    unsafe {
+        let p = spdk_iscsi_portal_create(
+            portal_host.as_ptr(),
+            portal_port.as_ptr(),
+        );
+        if p.is_null() {
+            spdk_iscsi_portal_grp_release(pg);
+            return Err(Error::CreatePortal {});
+        }
+        spdk_iscsi_portal_grp_add_portal(pg, p);
+        if spdk_iscsi_portal_grp_open(pg) != 0 {
+            spdk_iscsi_portal_grp_release(pg);
+            return Err(Error::AddPortal {});
+        }
+        if spdk_iscsi_portal_grp_register(pg) != 0 {
+            spdk_iscsi_portal_grp_release(pg);
+            return Err(Error::RegisterPortalGroup {});
+        }
+    }
+    info!(
+        "Created iscsi portal group no {}, address {}, port {}",
+        pg_no, address, port_no
+    );
+    Ok(())
+}
+
+/// Add a portal listening on the given address and port to the existing
+/// portal group, such that the targets of the group can be reached through
+/// more than one network interface.
+///
+/// spdk cannot open a portal of a group which is open already, so the portal
+/// gets a group of its own, which every target of the existing group, and
+/// every target created for it later on, is mapped to as well.
+pub fn add_portal_to_group(
+    pg_no: c_int,
+    address: &str,
+    port: u16,
+) -> Result<()> {
+    if unsafe { spdk_iscsi_portal_grp_find_by_tag(pg_no) }.is_null() {
+        return Err(Error::PortalGroupNotFound {
+            pg_no,
+        });
+    }
+
+    // the group is released by create_portal_group() if it cannot be set up
+    let alias = next_portal_group_idx();
+    create_portal_group(address, port, alias)?;
+
+    let targets = ISCSI_TARGETS.with(|targets| {
+        targets
+            .borrow()
+            .iter()
+            .filter(|(_, (pg_idx, _))| *pg_idx == pg_no)
+            .map(|(iqn, (_, ig_idx))| (iqn.clone(), *ig_idx))
+            .collect::<Vec<_>>()
+    });
+    let mut mapped = Vec::new();
+
+    for (iqn, ig_idx) in targets {
+        if let Err(e) = map_portal_group(&iqn, alias, ig_idx) {
+            for (iqn, ig_idx) in mapped {
+                unmap_portal_group(&iqn, alias, ig_idx);
+            }
+            destroy_portal_group(alias);
+            return Err(e);
+        }
+        mapped.push((iqn, ig_idx));
+    }
+
+    PORTAL_ALIASES.with(|aliases| {
+        aliases.borrow_mut().entry(pg_no).or_default().push(alias)
+    });
+
+    // targets of the side served by the group can now be exported on the
+    // new address without creating another group
+    let side = PORTAL_GROUPS.with(|groups| {
+        groups
+            .borrow()
+            .iter()
+            .find(|(_, idx)| **idx == pg_no)
+            .map(|((_, side), _)| *side)
+    });
+    if let Some(side) = side {
+        PORTAL_GROUPS.with(|groups| {
+            groups
+                .borrow_mut()
+                .entry((address.to_owned(), side))
+                .or_insert(pg_no);
+        });
+    }
+
+    info!(
+        "Added portal address {}, port {} to iscsi portal group no {} as group no {}",
+        address, port, pg_no, alias
+    );
+    Ok(())
+}
+
+/// Allow access to the target with the given iqn through the portal group.
+fn map_portal_group(iqn: &str, pg_idx: c_int, ig_idx: c_int) -> Result<()> {
+    let c_iqn = c_string(iqn, "iqn")?;
+    let mut pg_tag = pg_idx;
+    let mut ig_tag = ig_idx;
+
+    let rc = unsafe {
+        let tgt = spdk_iscsi_find_tgt_node(c_iqn.as_ptr());
+        if tgt.is_null() {
+            -1
+        } else {
+            spdk_iscsi_tgt_node_add_pg_ig_maps(tgt, &mut pg_tag, &mut ig_tag, 1)
+        }
+    };
+    if rc != 0 {
+        return Err(Error::MapPortalGroup {
+            pg_no: pg_idx,
+            iqn: iqn.to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Undo map_portal_group().
+fn unmap_portal_group(iqn: &str, pg_idx: c_int, ig_idx: c_int) {
+    let c_iqn = match c_string(iqn, "iqn") {
+        Ok(c_iqn) => c_iqn,
+        Err(_) => return,
+    };
+    let mut pg_tag = pg_idx;
+    let mut ig_tag = ig_idx;
+
+    unsafe {
+        let tgt = spdk_iscsi_find_tgt_node(c_iqn.as_ptr());
+        if !tgt.is_null()
+            && spdk_iscsi_tgt_node_delete_pg_ig_maps(
+                tgt,
+                &mut pg_tag,
+                &mut ig_tag,
+                1,
+            ) != 0
+        {
+            warn!("Failed to unmap portal group {} from {}", pg_idx, iqn);
+        }
+    }
+}
+
+fn destroy_portal_group(pg_idx: c_int) {
+    unsafe {
+        let pg = spdk_iscsi_portal_grp_unregister(pg_idx);
+        if !pg.is_null() {
+            spdk_iscsi_portal_grp_release(pg);
+        }
+    }
+}
+
+/// Return iscsi target URI understood by nexus
+pub fn get_uri(side: Side, bdev_name: &str) -> Option<String> {
+    let iqn = target_name(bdev_name);
+    let c_iqn = c_string(&iqn, "iqn").ok()?;
+    let tgt = unsafe { spdk_iscsi_find_tgt_node(c_iqn.as_ptr()) };
+
+    if tgt.is_null() {
+        return None;
+    }
+    Some(create_uri(side, &iqn))
+}
+
+/// Return the address and port the iscsi target of the bdev is listening on,
+/// that is those of the portal group it has been created with, or None if
+/// there is no such target.
+pub fn target_endpoint(bdev_name: &str) -> Option<(String, u16)> {
+    let iqn = target_name(bdev_name);
+    let c_iqn = c_string(&iqn, "iqn").ok()?;
+    let tgt = unsafe { spdk_iscsi_find_tgt_node(c_iqn.as_ptr()) };
+
+    if tgt.is_null() {
+        return None;
+    }
+    let (pg_idx, _) =
+        ISCSI_TARGETS.with(|targets| targets.borrow().get(&iqn).copied())?;
+    PORTAL_GROUPS.with(|groups| {
+        groups
+            .borrow()
+            .iter()
+            .find(|(_, idx)| **idx == pg_idx)
+            .map(|((address, side), _)| (address.clone(), portal_port(*side)))
+    })
+}
+
+/// Return the id of the first LUN of the iscsi target of the bdev, or None
+/// if there is no such target.
+pub fn target_lun_id(bdev_name: &str) -> Option<u32> {
+    let c_iqn = c_string(&target_name(bdev_name), "iqn").ok()?;
+    let tgt = unsafe { spdk_iscsi_find_tgt_node(c_iqn.as_ptr()) };
+
+    if tgt.is_null() {
+        return None;
+    }
+    (0 .. SPDK_SCSI_DEV_MAX_LUN as c_int)
+        .find(|id| unsafe { !spdk_scsi_dev_get_lun((*tgt).dev, *id).is_null() })
+        .map(|id| id as u32)
+}
+
+/// Return the IO statistics of the iscsi target of the bdev, or None if there
+/// is no such target or the statistics cannot be obtained.
+pub async fn target_stats(bdev_name: &str) -> Option<TargetStats> {
+    iqn_stats(&target_name(bdev_name), bdev_name).await
+}
+
+/// Return the IO statistics of the target with the given iqn which exports
+/// the given bdev.
+async fn iqn_stats(iqn: &str, bdev_name: &str) -> Option<TargetStats> {
+    let c_iqn = c_string(iqn, "iqn").ok()?;
+    let tgt = unsafe { spdk_iscsi_find_tgt_node(c_iqn.as_ptr()) };
+
+    if tgt.is_null() {
+        return None;
+    }
+    let (queue_depth, active_connections) =
+        unsafe { ((*tgt).queue_depth, (*tgt).num_active_conns) };
+
+    let bdev = Bdev::lookup_by_name(bdev_name)?;
+    // the target may be gone once the stats are in, which is fine as the
+    // counters are still what they were
+    match bdev.stats().await {
+        Ok(st) => Some(TargetStats {
+            iqn: iqn.to_owned(),
+            num_read_ops: st.num_read_ops,
+            num_write_ops: st.num_write_ops,
+            bytes_read: st.bytes_read,
+            bytes_written: st.bytes_written,
+            queue_depth: queue_depth as u32,
+            active_connections: active_connections as u32,
+        }),
+        Err(errno) => {
+            warn!("Failed to get stats for {} (errno={})", iqn, errno);
+            None
+        }
+    }
+}
+
+pub fn create_uri(side: Side, iqn: &str) -> String {
+    let port = portal_port(side);
+    ADDRESS.with(move |a| {
+        let a_borrow = a.borrow();
+        let address = a_borrow.as_ref().unwrap();
+        format!("iscsi://{}:{}/{}", address, port, iqn)
+    })
+}
@@ -0,0 +1,360 @@
+//! A bdev stacked on top of the bdev of an iscsi LUN, for LUNs which are to
+//! behave differently from the bdev they export (see iscsi::LunFlags). The
+//! spdk iscsi target has no notion of read-only or write-through LUNs, so the
+//! target exports this bdev in place of the bdev of the LUN, which fails any
+//! write to a read-only LUN and reports no volatile write cache for a
+//! write-through LUN. All other IO is passed on to the bdev below it as is.
+
+use std::{
+    ffi::{c_void, CString},
+    ptr,
+};
+
+use nix::errno::Errno;
+use once_cell::sync::Lazy;
+
+use spdk_sys::{
+    spdk_bdev,
+    spdk_bdev_close,
+    spdk_bdev_desc,
+    spdk_bdev_flush_blocks,
+    spdk_bdev_fn_table,
+    spdk_bdev_free_io,
+    spdk_bdev_get_io_channel,
+    spdk_bdev_io,
+    spdk_bdev_io_complete,
+    spdk_bdev_io_get_buf,
+    spdk_bdev_io_type,
+    spdk_bdev_io_type_supported,
+    spdk_bdev_module,
+    spdk_bdev_module_list_add,
+    spdk_bdev_open,
+    spdk_bdev_readv_blocks,
+    spdk_bdev_register,
+    spdk_bdev_reset,
+    spdk_bdev_unmap_blocks,
+    spdk_bdev_unregister,
+    spdk_bdev_write_zeroes_blocks,
+    spdk_bdev_writev_blocks,
+    spdk_io_channel,
+};
+
+use crate::{
+    bdev::nexus::nexus_io::{io_status, io_type},
+    core::Bdev,
+    ffihelper::{errno_result_from_i32, ErrnoResult},
+    target::iscsi::LunFlags,
+};
+
+const LUN_MODULE_NAME: &str = "ISCSI_LUN_MODULE";
+const LUN_PRODUCT_ID: &str = "iSCSI LUN";
+
+static LUN_MODULE: Lazy<LunModule> = Lazy::new(LunModule::new);
+static LUN_FN_TBL: Lazy<LunFnTable> = Lazy::new(LunFnTable::new);
+
+struct LunModule(*mut spdk_bdev_module);
+
+unsafe impl Sync for LunModule {}
+unsafe impl Send for LunModule {}
+
+impl LunModule {
+    fn new() -> Self {
+        let mut module = Box::new(spdk_bdev_module::default());
+        module.name = CString::new(LUN_MODULE_NAME).unwrap().into_raw();
+        module.async_init = false;
+        module.async_fini = false;
+        module.module_init = Some(Self::lun_mod_init);
+        LunModule(Box::into_raw(module))
+    }
+
+    /// the bdevs are created on demand, there is nothing to set up
+    extern "C" fn lun_mod_init() -> i32 {
+        0
+    }
+}
+
+/// register the module of the LUN bdevs with spdk, which must happen before
+/// the bdev subsystem is initialized
+pub fn register_module() {
+    unsafe { spdk_bdev_module_list_add(LUN_MODULE.0) }
+}
+
+struct LunFnTable {
+    f_tbl: spdk_bdev_fn_table,
+}
+
+unsafe impl Sync for LunFnTable {}
+unsafe impl Send for LunFnTable {}
+
+impl LunFnTable {
+    fn new() -> Self {
+        let mut f_tbl = spdk_bdev_fn_table::default();
+        f_tbl.io_type_supported = Some(LunBdev::io_supported);
+        f_tbl.submit_request = Some(LunBdev::io_submit);
+        f_tbl.get_io_channel = Some(LunBdev::io_channel);
+        f_tbl.destruct = Some(LunBdev::destruct);
+        LunFnTable {
+            f_tbl,
+        }
+    }
+}
+
+/// The bdev of a LUN, owned by spdk once registered and freed by destruct().
+struct LunBdev {
+    /// the bdev as registered with spdk
+    bdev: *mut spdk_bdev,
+    /// the bdev of the LUN which IO is passed on to
+    base: *mut spdk_bdev,
+    /// descriptor of the bdev below, read-only for a read-only LUN
+    desc: *mut spdk_bdev_desc,
+    flags: LunFlags,
+}
+
+impl LunBdev {
+    unsafe fn from_raw<'a>(ctx: *mut c_void) -> &'a LunBdev {
+        &*(ctx as *const LunBdev)
+    }
+
+    extern "C" fn io_supported(
+        ctx: *mut c_void,
+        io_type: spdk_bdev_io_type,
+    ) -> bool {
+        let lun = unsafe { Self::from_raw(ctx) };
+        match io_type {
+            io_type::WRITE | io_type::UNMAP | io_type::WRITE_ZEROES
+                if lun.flags.read_only =>
+            {
+                false
+            }
+            _ => unsafe { spdk_bdev_io_type_supported(lun.base, io_type) },
+        }
+    }
+
+    /// the IO channel of the bdev below is used as is, as there is nothing
+    /// to be kept per channel
+    extern "C" fn io_channel(ctx: *mut c_void) -> *mut spdk_io_channel {
+        let lun = unsafe { Self::from_raw(ctx) };
+        unsafe { spdk_bdev_get_io_channel(lun.desc) }
+    }
+
+    extern "C" fn io_submit(ch: *mut spdk_io_channel, io: *mut spdk_bdev_io) {
+        let lun = unsafe { Self::from_raw((*(*io).bdev).ctxt) };
+        let (offset, num_blocks) =
+            unsafe { ((*io).u.bdev.offset_blocks, (*io).u.bdev.num_blocks) };
+
+        let kind = unsafe { (*io).type_ } as u32;
+
+        let rc = match kind {
+            io_type::WRITE | io_type::UNMAP | io_type::WRITE_ZEROES
+                if lun.flags.read_only =>
+            {
+                unsafe { spdk_bdev_io_complete(io, io_status::FAILED) };
+                return;
+            }
+            io_type::READ => unsafe {
+                if (*(*io).u.bdev.iovs).iov_base.is_null() {
+                    let len = num_blocks * u64::from((*(*io).bdev).blocklen);
+                    spdk_bdev_io_get_buf(io, Some(Self::get_buf_cb), len);
+                    return;
+                }
+                Self::readv(lun, ch, io)
+            },
+            io_type::WRITE => unsafe {
+                spdk_bdev_writev_blocks(
+                    lun.desc,
+                    ch,
+                    (*io).u.bdev.iovs,
+                    (*io).u.bdev.iovcnt,
+                    offset,
+                    num_blocks,
+                    Some(Self::io_completion),
+                    io as *mut _,
+                )
+            },
+            io_type::UNMAP => unsafe {
+                spdk_bdev_unmap_blocks(
+                    lun.desc,
+                    ch,
+                    offset,
+                    num_blocks,
+                    Some(Self::io_completion),
+                    io as *mut _,
+                )
+            },
+            io_type::WRITE_ZEROES => unsafe {
+                spdk_bdev_write_zeroes_blocks(
+                    lun.desc,
+                    ch,
+                    offset,
+                    num_blocks,
+                    Some(Self::io_completion),
+                    io as *mut _,
+                )
+            },
+            io_type::FLUSH => unsafe {
+                spdk_bdev_flush_blocks(
+                    lun.desc,
+                    ch,
+                    offset,
+                    num_blocks,
+                    Some(Self::io_completion),
+                    io as *mut _,
+                )
+            },
+            io_type::RESET => unsafe {
+                spdk_bdev_reset(
+                    lun.desc,
+                    ch,
+                    Some(Self::io_completion),
+                    io as *mut _,
+                )
+            },
+            other => {
+                error!("Received unsupported IO type {} for a LUN", other);
+                -(Errno::ENOTSUP as i32)
+            }
+        };
+
+        if rc != 0 {
+            unsafe { spdk_bdev_io_complete(io, io_status::FAILED) };
+        }
+    }
+
+    unsafe fn readv(
+        lun: &LunBdev,
+        ch: *mut spdk_io_channel,
+        io: *mut spdk_bdev_io,
+    ) -> i32 {
+        spdk_bdev_readv_blocks(
+            lun.desc,
+            ch,
+            (*io).u.bdev.iovs,
+            (*io).u.bdev.iovcnt,
+            (*io).u.bdev.offset_blocks,
+            (*io).u.bdev.num_blocks,
+            Some(Self::io_completion),
+            io as *mut _,
+        )
+    }
+
+    /// called once a buffer has been allocated for a read which came without
+    extern "C" fn get_buf_cb(
+        ch: *mut spdk_io_channel,
+        io: *mut spdk_bdev_io,
+        success: bool,
+    ) {
+        let rc = if success {
+            unsafe { Self::readv(Self::from_raw((*(*io).bdev).ctxt), ch, io) }
+        } else {
+            -(Errno::ENOMEM as i32)
+        };
+        if rc != 0 {
+            unsafe { spdk_bdev_io_complete(io, io_status::FAILED) };
+        }
+    }
+
+    /// complete the IO of the LUN with the status of the IO passed on
+    extern "C" fn io_completion(
+        child_io: *mut spdk_bdev_io,
+        success: bool,
+        parent_io: *mut c_void,
+    ) {
+        let status = if success {
+            io_status::SUCCESS
+        } else {
+            io_status::FAILED
+        };
+        unsafe {
+            spdk_bdev_io_complete(parent_io as *mut _, status);
+            spdk_bdev_free_io(child_io);
+        }
+    }
+
+    /// the bdev below is going away, so must the LUN
+    extern "C" fn hot_remove(ctx: *mut c_void) {
+        let lun = unsafe { Self::from_raw(ctx) };
+        unsafe { spdk_bdev_unregister(lun.bdev, None, ptr::null_mut()) };
+    }
+
+    /// called by spdk once the bdev has been unregistered and all its
+    /// descriptors have been closed
+    extern "C" fn destruct(ctx: *mut c_void) -> i32 {
+        let lun = unsafe { Box::from_raw(ctx as *mut LunBdev) };
+        unsafe {
+            spdk_bdev_close(lun.desc);
+            let bdev = Box::from_raw(lun.bdev);
+            let _ = CString::from_raw(bdev.name);
+            let _ = CString::from_raw(bdev.product_name);
+        }
+        0
+    }
+}
+
+/// Create and register a bdev named name on top of the given bdev, which
+/// behaves as the flags demand.
+pub(crate) fn create(
+    name: &str,
+    base: &Bdev,
+    flags: LunFlags,
+) -> ErrnoResult<Bdev> {
+    let lun = Box::into_raw(Box::new(LunBdev {
+        bdev: ptr::null_mut(),
+        base: base.as_ptr(),
+        desc: ptr::null_mut(),
+        flags,
+    }));
+
+    let rc = unsafe {
+        spdk_bdev_open(
+            base.as_ptr(),
+            !flags.read_only,
+            Some(LunBdev::hot_remove),
+            lun as *mut c_void,
+            &mut (*lun).desc,
+        )
+    };
+    if let Err(e) = errno_result_from_i32((), rc) {
+        drop(unsafe { Box::from_raw(lun) });
+        return Err(e);
+    }
+
+    let mut b = Box::new(spdk_bdev::default());
+    b.name = CString::new(name).unwrap().into_raw();
+    b.product_name = CString::new(LUN_PRODUCT_ID).unwrap().into_raw();
+    b.fn_table = &LUN_FN_TBL.f_tbl;
+    b.module = LUN_MODULE.0;
+    b.ctxt = lun as *mut c_void;
+    b.blocklen = base.block_len();
+    b.blockcnt = base.num_blocks();
+    b.write_cache = if flags.write_through {
+        0
+    } else {
+        base.has_write_cache() as i32
+    };
+    unsafe {
+        b.required_alignment = (*base.as_ptr()).required_alignment;
+        b.optimal_io_boundary = (*base.as_ptr()).optimal_io_boundary;
+        b.split_on_optimal_io_boundary =
+            (*base.as_ptr()).split_on_optimal_io_boundary;
+        (*lun).bdev = Box::into_raw(b);
+    }
+
+    let rc = unsafe { spdk_bdev_register((*lun).bdev) };
+    if let Err(e) = errno_result_from_i32((), rc) {
+        // never registered, so destruct() is not called by spdk
+        LunBdev::destruct(lun as *mut c_void);
+        return Err(e);
+    }
+    Ok(Bdev::from(unsafe { (*lun).bdev }))
+}
+
+/// Unregister the bdev of a LUN, which is freed by spdk once the target which
+/// exported it has let go of it.
+pub(crate) fn destroy(name: &str) {
+    match Bdev::lookup_by_name(name) {
+        Some(bdev) if bdev.product_name() == LUN_PRODUCT_ID => unsafe {
+            spdk_bdev_unregister(bdev.as_ptr(), None, ptr::null_mut())
+        },
+        _ => warn!("LUN bdev {} does not exist", name),
+    }
+}
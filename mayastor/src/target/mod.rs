@@ -0,0 +1,15 @@
+pub mod iscsi;
+pub(crate) mod iscsi_lun;
+pub mod nvmf;
+
+/// Which kind of target interface to use for a bdev, that is the role of the
+/// portal it is exported through. Each side has its own port and portal
+/// groups, such that the two never get mixed up, and multi-homed nodes map
+/// each side to addresses of their own (see iscsi::portal_group_for()).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    /// front end, facing the hosts which consume a nexus
+    Nexus,
+    /// back end, facing the nexus which consumes a replica
+    Replica,
+}
@@ -21,6 +21,9 @@ static BDEVNAME2: &str = "aio:///tmp/disk2.img?blk_size=512";
 static DISKNAME3: &str = "/tmp/disk3.img";
 static BDEVNAME3: &str = "uring:///tmp/disk3.img?blk_size=512";
 
+static DISKNAME4: &str = "/tmp/disk4.img";
+static BDEVNAME4: &str = "aio:///tmp/disk4.img?blk_size=512";
+
 static mut DO_URING: bool = false;
 static INIT: Once = Once::new();
 
@@ -47,7 +50,7 @@ async fn create_nexus() {
     } else {
         vec![BDEVNAME1.to_string(), BDEVNAME2.to_string()]
     };
-    nexus_create("core_nexus", 64 * 1024 * 1024, None, &ch)
+    nexus_create("core_nexus", 32 * 1024 * 1024, None, &ch)
         .await
         .unwrap();
 }
@@ -58,9 +61,11 @@ fn core() {
     common::truncate_file(DISKNAME1, 64 * 1024);
     common::truncate_file(DISKNAME2, 64 * 1024);
     common::truncate_file(DISKNAME3, 64 * 1024);
+    common::truncate_file(DISKNAME4, 32 * 1024);
 
     Reactor::block_on(async {
         works().await;
+        sized_after_smallest_child().await;
     });
 }
 
@@ -76,7 +81,28 @@ async fn works() {
     drop(desc);
 
     let n = nexus_lookup("core_nexus").expect("nexus not found");
-    n.destroy().await;
+    assert_eq!(n.block_size(), b.block_len());
+
+    let capacity = n.capacity();
+    assert_eq!(capacity.child_count as usize, n.children.len());
+    assert!(capacity.usable_bytes >= 32 * 1024 * 1024);
+    assert!(capacity.smallest_child_bytes > capacity.usable_bytes);
+    assert_eq!(n.children[0].driver_name(), Some("AIO disk".to_string()));
+    n.destroy().await.unwrap();
+}
+
+async fn sized_after_smallest_child() {
+    let ch = vec![BDEVNAME1.to_string(), BDEVNAME4.to_string()];
+    nexus_create("core_nexus_sized", 0, None, &ch)
+        .await
+        .unwrap();
+
+    let n = nexus_lookup("core_nexus_sized").expect("nexus not found");
+    let capacity = n.capacity();
+    assert!(n.size() > 0);
+    assert!(n.size() <= capacity.usable_bytes);
+    assert!(n.size() < 32 * 1024 * 1024);
+    n.destroy().await.unwrap();
 }
 
 #[test]
@@ -100,7 +126,7 @@ fn core_2() {
         // we must drop the descriptors before we destroy the nexus
         drop(dbg!(d1));
         drop(dbg!(d2));
-        n.destroy().await;
+        n.destroy().await.unwrap();
     });
 }
 
@@ -0,0 +1,210 @@
+use std::{net::TcpStream, process::Command, time::Duration};
+
+use futures_timer::Delay;
+
+use mayastor::{
+    core::{
+        mayastor_env_stop,
+        Bdev,
+        BdevHandle,
+        MayastorCliArgs,
+        MayastorEnvironment,
+        Reactor,
+    },
+    nexus_uri::bdev_create,
+    target::{
+        iscsi::{
+            add_portal_to_group,
+            init_state,
+            lun_bdev_name,
+            share,
+            share_luns,
+            share_on,
+            target_endpoint,
+            target_name,
+            uuid_from_target_name,
+            valid_iqn_prefix,
+            Error,
+            Lun,
+            LunFlags,
+            ISCSI_QUEUE_DEPTH,
+        },
+        Side,
+    },
+};
+
+static DISKNAME: &str = "/tmp/iscsi-portal.img";
+static BDEVNAME: &str = "aio:///tmp/iscsi-portal.img?blk_size=512";
+
+static LUN_DISKNAME: &str = "/tmp/iscsi-lun.img";
+static LUN_BDEVNAME: &str = "aio:///tmp/iscsi-lun.img?blk_size=512";
+
+/// address of the portal added to the nexus portal group
+static EXTRA_PORTAL: &str = "127.0.0.1:3264";
+
+pub mod common;
+
+#[test]
+fn iqn_prefix() {
+    assert!(valid_iqn_prefix("iqn.2019-05.io.openebs"));
+    assert!(valid_iqn_prefix("iqn.2001-04.com.example"));
+    assert!(valid_iqn_prefix("iqn.1992-01.com.example-storage.node1"));
+
+    assert!(!valid_iqn_prefix(""));
+    assert!(!valid_iqn_prefix("iqn"));
+    assert!(!valid_iqn_prefix("iqn.2019-05"));
+    assert!(!valid_iqn_prefix("eui.2019-05.io.openebs"));
+    assert!(!valid_iqn_prefix("iqn.2019-5.io.openebs"));
+    assert!(!valid_iqn_prefix("iqn.19-05.io.openebs"));
+    assert!(!valid_iqn_prefix("iqn.2019-13.io.openebs"));
+    assert!(!valid_iqn_prefix("iqn.2019-00.io.openebs"));
+    assert!(!valid_iqn_prefix("iqn.2019-05.io..openebs"));
+    assert!(!valid_iqn_prefix("iqn.2019-05.io.OpenEBS"));
+    assert!(!valid_iqn_prefix("iqn.2019-05.io.openebs:"));
+}
+
+#[test]
+fn iqn_round_trip() {
+    let uuid = "3a2f5a3e-6f5a-4c6f-9e4d-7c4d1f5e2b11";
+    let iqn = target_name(uuid);
+    assert_eq!(iqn, format!("iqn.2019-05.io.openebs:{}", uuid));
+    assert_eq!(uuid_from_target_name(&iqn).as_deref(), Some(uuid));
+
+    assert_eq!(uuid_from_target_name("iqn.2019-05.io.openebs:"), None);
+    assert_eq!(uuid_from_target_name("iqn.2019-05.io.openebs"), None);
+    assert_eq!(uuid_from_target_name("iqn.2019-05.io.openebsx:a"), None);
+    assert_eq!(uuid_from_target_name("iqn.2001-04.com.example:a"), None);
+}
+
+#[test]
+fn add_portal() {
+    common::mayastor_test_init();
+
+    let output = Command::new("truncate")
+        .args(&["-s", "64m", DISKNAME, LUN_DISKNAME])
+        .output()
+        .expect("failed exec truncate");
+    assert_eq!(output.status.success(), true);
+
+    let rc = MayastorEnvironment::new(MayastorCliArgs::default())
+        .start(|| Reactor::block_on(start()).unwrap())
+        .unwrap();
+    assert_eq!(rc, 0);
+
+    let output = Command::new("rm")
+        .args(&["-rf", DISKNAME, LUN_DISKNAME])
+        .output()
+        .expect("failed delete test file");
+    assert_eq!(output.status.success(), true);
+}
+
+async fn start() {
+    add_portal_to_existing_group().await;
+    lun_flags().await;
+    endpoints().await;
+    mayastor_env_stop(0);
+}
+
+async fn add_portal_to_existing_group() {
+    let name = bdev_create(BDEVNAME).await.unwrap();
+    let bdev = Bdev::lookup_by_name(&name).unwrap();
+    let handle = share(&name, &bdev, Side::Nexus).unwrap();
+    let fe_pg = init_state().unwrap().fe_pg;
+
+    assert!(TcpStream::connect(EXTRA_PORTAL).is_err());
+    add_portal_to_group(fe_pg, "127.0.0.1", 3264).unwrap();
+    assert!(TcpStream::connect(EXTRA_PORTAL).is_ok());
+
+    // the port is taken now, and the failed attempt must not disturb the
+    // portal which has been added
+    assert!(add_portal_to_group(fe_pg, "127.0.0.1", 3264).is_err());
+    assert!(TcpStream::connect(EXTRA_PORTAL).is_ok());
+
+    match add_portal_to_group(999, "127.0.0.1", 3266) {
+        Err(Error::PortalGroupNotFound {
+            pg_no,
+        }) => assert_eq!(pg_no, 999),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert!(TcpStream::connect("127.0.0.1:3266").is_err());
+
+    handle.unshare().await.unwrap();
+}
+
+/// a LUN with flags is exported through a bdev of its own which enforces
+/// them, and which goes away with the target
+async fn lun_flags() {
+    let bdev = Bdev::lookup_by_name(BDEVNAME).unwrap();
+    let name = bdev_create(LUN_BDEVNAME).await.unwrap();
+    let lun_bdev = Bdev::lookup_by_name(&name).unwrap();
+    let flags = LunFlags {
+        read_only: true,
+        write_through: true,
+    };
+
+    let handle = share_luns(
+        "lun-flags",
+        &[
+            Lun {
+                bdev: &bdev,
+                flags: LunFlags::default(),
+            },
+            Lun {
+                bdev: &lun_bdev,
+                flags,
+            },
+        ],
+        Side::Nexus,
+        ISCSI_QUEUE_DEPTH,
+    )
+    .unwrap();
+
+    // LUN0 exports its bdev as is
+    assert!(Bdev::lookup_by_name(&lun_bdev_name("lun-flags", 0)).is_none());
+    let lun1 = Bdev::lookup_by_name(&lun_bdev_name("lun-flags", 1)).unwrap();
+    assert_eq!(lun1.num_blocks(), lun_bdev.num_blocks());
+    assert!(!lun1.has_write_cache());
+
+    let hdl = BdevHandle::open(&lun1.name(), true, false).unwrap();
+    let mut buf = hdl.dma_malloc(512).unwrap();
+    buf.fill(0xff);
+    assert!(hdl.write_at(0, &buf).await.is_err());
+    hdl.read_at(0, &mut buf).await.unwrap();
+    assert!(buf.as_slice().iter().all(|b| *b == 0));
+    hdl.close();
+
+    handle.unshare().await.unwrap();
+    // the bdev is freed once the target has closed it
+    Delay::new(Duration::from_millis(100)).await;
+    assert!(Bdev::lookup_by_name(&lun_bdev_name("lun-flags", 1)).is_none());
+}
+
+/// a target reports the address and port of the portal group it has been
+/// exported through
+async fn endpoints() {
+    let state = init_state().unwrap();
+    let bdev = Bdev::lookup_by_name(BDEVNAME).unwrap();
+    assert_eq!(target_endpoint(BDEVNAME), None);
+
+    let handle = share(BDEVNAME, &bdev, Side::Nexus).unwrap();
+    let (address, port) = target_endpoint(BDEVNAME).unwrap();
+    assert_ne!(address, "127.0.0.2");
+    assert_eq!(port, state.fe_port);
+    handle.unshare().await.unwrap();
+
+    let handle = share_on(
+        "127.0.0.2",
+        BDEVNAME,
+        &bdev,
+        Side::Replica,
+        ISCSI_QUEUE_DEPTH,
+        0,
+    )
+    .unwrap();
+    assert_eq!(
+        target_endpoint(BDEVNAME),
+        Some(("127.0.0.2".to_string(), state.be_port))
+    );
+    handle.unshare().await.unwrap();
+    assert_eq!(target_endpoint(BDEVNAME), None);
+}
@@ -0,0 +1,82 @@
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup},
+    core::{
+        mayastor_env_stop,
+        BdevHandle,
+        MayastorCliArgs,
+        MayastorEnvironment,
+        Reactor,
+    },
+};
+
+pub mod common;
+
+static DISKNAME1: &str = "/tmp/child1.img";
+static BDEVNAME1: &str = "aio:///tmp/child1.img?blk_size=512";
+
+static DISKNAME2: &str = "/tmp/child2.img";
+static BDEVNAME2: &str = "aio:///tmp/child2.img?blk_size=512";
+
+static NEXUS_NAME: &str = "child_test";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024; // 10MiB
+
+#[test]
+fn nexus_child() {
+    common::delete_file(&[DISKNAME1.into(), DISKNAME2.into()]);
+    common::truncate_file(DISKNAME1, 64 * 1024);
+    common::truncate_file(DISKNAME2, 64 * 1024);
+
+    let rc = MayastorEnvironment::new(MayastorCliArgs::default())
+        .start(|| Reactor::block_on(start()).unwrap())
+        .unwrap();
+    assert_eq!(rc, 0);
+
+    common::delete_file(&[DISKNAME1.into(), DISKNAME2.into()]);
+}
+
+async fn start() {
+    io_latency().await;
+    mayastor_env_stop(0);
+}
+
+/// the IO of the nexus is accounted for by the children which complete it
+async fn io_latency() {
+    let children = vec![BDEVNAME1.to_string(), BDEVNAME2.to_string()];
+    nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &children)
+        .await
+        .unwrap();
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+    for child in &nexus.children {
+        child.reset_latency_stats();
+        assert_eq!(child.latency_percentiles().count, 0);
+    }
+
+    {
+        let hdl = BdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(0xff);
+        for i in 0 .. 16 {
+            hdl.write_at(i * 4096, &buf).await.unwrap();
+        }
+        hdl.read_at(0, &mut buf).await.unwrap();
+    }
+
+    // writes go to every child, the read to one of them
+    for child in &nexus.children {
+        let stats = child.latency_percentiles();
+        assert!(stats.count >= 16, "{}: {:?}", child.name, stats);
+        assert!(stats.p50 <= stats.p95 && stats.p95 <= stats.p99);
+        assert!(stats.p99 <= stats.max);
+    }
+    let completed = nexus
+        .children
+        .iter()
+        .map(|c| c.latency_percentiles().count)
+        .sum::<u64>();
+    assert_eq!(completed, 2 * 16 + 1);
+
+    nexus.children[0].reset_latency_stats();
+    assert_eq!(nexus.children[0].latency_percentiles().count, 0);
+
+    nexus.destroy().await.unwrap();
+}
@@ -0,0 +1,126 @@
+use std::{fs::OpenOptions, os::unix::fs::FileExt, time::Duration};
+
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup, ChildState, FaultReason, NexusState},
+    core::{
+        mayastor_env_stop,
+        BdevHandle,
+        MayastorCliArgs,
+        MayastorEnvironment,
+        Reactor,
+    },
+};
+
+pub mod common;
+
+static DISKNAME1: &str = "/tmp/dirty-log1.img";
+static BDEVNAME1: &str = "aio:///tmp/dirty-log1.img?blk_size=512";
+
+static DISKNAME2: &str = "/tmp/dirty-log2.img";
+static BDEVNAME2: &str = "aio:///tmp/dirty-log2.img?blk_size=512";
+
+static NEXUS_NAME: &str = "dirty_log_test";
+static NEXUS_UUID: &str = "4a7bd1a0-4b2e-4f36-8c4e-2f0d3c1b6a01";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024; // 10MiB
+
+/// offset of the data partition on the children
+const DATA_OFFSET: u64 = 10240 * 512;
+
+/// offset of the region the children disagree on outside of the dirty log
+const STALE_OFFSET: u64 = 1024 * 1024;
+
+#[test]
+fn dirty_log() {
+    common::delete_file(&[DISKNAME1.into(), DISKNAME2.into()]);
+    common::truncate_file(DISKNAME1, 64 * 1024);
+    common::truncate_file(DISKNAME2, 64 * 1024);
+
+    let rc = MayastorEnvironment::new(MayastorCliArgs::default())
+        .start(|| Reactor::block_on(start()).unwrap())
+        .unwrap();
+    assert_eq!(rc, 0);
+
+    common::delete_file(&[DISKNAME1.into(), DISKNAME2.into()]);
+}
+
+async fn start() {
+    rebuild_after_reopen().await;
+    mayastor_env_stop(0);
+}
+
+async fn create_nexus() {
+    let children = vec![BDEVNAME1.to_string(), BDEVNAME2.to_string()];
+    nexus_create(NEXUS_NAME, NEXUS_SIZE, Some(NEXUS_UUID), &children)
+        .await
+        .unwrap();
+}
+
+async fn write_nexus(offset: u64, pattern: u8) {
+    let hdl = BdevHandle::open(NEXUS_NAME, true, false).unwrap();
+    let mut buf = hdl.dma_malloc(4096).unwrap();
+    buf.fill(pattern);
+    hdl.write_at(offset, &buf).await.unwrap();
+}
+
+/// write the pattern to the data partition of the disk behind the nexus
+fn write_disk(disk: &str, offset: u64, pattern: u8) {
+    let file = OpenOptions::new().write(true).open(disk).unwrap();
+    file.write_all_at(&[pattern; 4096], DATA_OFFSET + offset)
+        .unwrap();
+}
+
+/// check the data partition of the disk behind the nexus for the pattern
+fn check_disk(disk: &str, offset: u64, pattern: u8) {
+    let file = OpenOptions::new().read(true).open(disk).unwrap();
+    let mut buf = [0u8; 4096];
+    file.read_exact_at(&mut buf, DATA_OFFSET + offset).unwrap();
+    assert!(buf.iter().all(|b| *b == pattern));
+}
+
+/// a child which misses writes while faulted is faulted again when the
+/// nexus is reopened, and only what it has missed is copied to it
+async fn rebuild_after_reopen() {
+    create_nexus().await;
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+    assert_eq!(nexus.status(), NexusState::Online);
+
+    write_nexus(0, 0x11).await;
+    write_nexus(STALE_OFFSET, 0x11).await;
+
+    nexus
+        .fault_children(&[BDEVNAME2.to_string()], FaultReason::Admin)
+        .await
+        .unwrap();
+    write_nexus(0, 0x5a).await;
+    assert_eq!(nexus.children[1].dirty_bytes(), 4096);
+
+    nexus.persist_dirty_log().await.unwrap();
+    nexus.destroy().await.unwrap();
+
+    // the children now disagree on a region which is not in the log, which
+    // a full rebuild would bring in line
+    write_disk(DISKNAME1, STALE_OFFSET, 0x22);
+
+    create_nexus().await;
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+    let waiter = nexus_lookup(NEXUS_NAME).unwrap();
+    assert_eq!(nexus.status(), NexusState::Degraded);
+    nexus.children[1]
+        .wait_for_state(ChildState::Faulted, Duration::from_millis(0))
+        .await
+        .unwrap();
+    assert_eq!(nexus.children[1].dirty_bytes(), 4096);
+
+    let _complete = nexus.start_rebuild(BDEVNAME2).await.unwrap();
+    waiter.children[1]
+        .wait_for_state(ChildState::Open, Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(nexus.status(), NexusState::Online);
+    assert_eq!(nexus.children[1].dirty_bytes(), 0);
+
+    nexus.destroy().await.unwrap();
+
+    check_disk(DISKNAME2, 0, 0x5a);
+    check_disk(DISKNAME2, STALE_OFFSET, 0x11);
+}
@@ -7,7 +7,18 @@ use std::{
 use bincode::serialize_into;
 
 use mayastor::{
-    bdev::{nexus_create, nexus_lookup, GPTHeader, GptEntry},
+    bdev::{
+        nexus_create,
+        nexus_lookup,
+        GPTHeader,
+        GptEntry,
+        LabelDiff,
+        LabelStatus,
+        LabelType,
+        Nexus,
+        NexusLabel,
+        Pmbr,
+    },
     core::{
         mayastor_env_stop,
         BdevHandle,
@@ -28,6 +39,9 @@ static BDEVNAME1: &str = "aio:///tmp/disk1.img?blk_size=512";
 
 static DISKNAME2: &str = "/tmp/disk2.img";
 static BDEVNAME2: &str = "aio:///tmp/disk2.img?blk_size=512";
+
+static DISKNAME3: &str = "/tmp/disk3.img";
+static BDEVNAME3: &str = "aio:///tmp/disk3.img?blk_size=4096";
 pub mod common;
 
 #[test]
@@ -46,23 +60,335 @@ fn read_label() {
 
     assert_eq!(output.status.success(), true);
 
+    let output = Command::new("truncate")
+        .args(&["-s", "64m", DISKNAME3])
+        .output()
+        .expect("failed exec truncate");
+
+    assert_eq!(output.status.success(), true);
+
     let rc = MayastorEnvironment::new(MayastorCliArgs::default())
         .start(|| Reactor::block_on(start()).unwrap())
         .unwrap();
     assert_eq!(rc, 0);
 
     let output = Command::new("rm")
-        .args(&["-rf", DISKNAME1, DISKNAME2])
+        .args(&["-rf", DISKNAME1, DISKNAME2, DISKNAME3])
         .output()
         .expect("failed delete test file");
 
     assert_eq!(output.status.success(), true);
 }
 
+/// the protective MBR must end where the backup GPT header lives, which is
+/// what sgdisk does for the known label, and switch to the sentinel for
+/// devices of 2TiB and up
+#[test]
+fn pmbr_sectors() {
+    let mut file = std::fs::File::open("./gpt_test_data.bin").unwrap();
+    let mut buf: [u8; 1024] = [0; 1024];
+    file.read_exact(&mut buf).unwrap();
+
+    let pmbr = Pmbr::from_slice(&buf[.. 512]).unwrap();
+    let hdr = GPTHeader::from_slice(&buf[512 ..]).unwrap();
+    assert_eq!(pmbr.num_sectors(), 2_097_151);
+    assert!(Pmbr::is_consistent(pmbr.num_sectors(), hdr.lba_alt));
+    assert_eq!(Pmbr::protective_sectors(2_097_152), pmbr.num_sectors());
+
+    // exactly at the boundary, the last lba is the sentinel itself
+    let blocks = 1u64 << 32;
+    assert_eq!(Pmbr::protective_sectors(blocks - 1), 0xffff_fffe);
+    assert_eq!(Pmbr::protective_sectors(blocks), 0xffff_ffff);
+    assert_eq!(Pmbr::protective_sectors(blocks + 1), 0xffff_ffff);
+    assert!(Pmbr::is_consistent(0xffff_fffe, blocks - 2));
+    assert!(Pmbr::is_consistent(0xffff_ffff, blocks - 1));
+    assert!(Pmbr::is_consistent(0xffff_ffff, blocks));
+    assert!(!Pmbr::is_consistent(0xffff_ffff, blocks - 2));
+    assert!(!Pmbr::is_consistent(2_097_152, hdr.lba_alt));
+}
+
+/// a header of another revision is accepted, but reported as such
+#[test]
+fn gpt_revision() {
+    let mut file = std::fs::File::open("./gpt_test_data.bin").unwrap();
+    let mut buf: [u8; 1024] = [0; 1024];
+    file.read_exact(&mut buf).unwrap();
+
+    let mut hdr = GPTHeader::from_slice(&buf[512 ..]).unwrap();
+    assert!(!hdr.revision_mismatch());
+
+    hdr.revision = [0x00, 0x00, 0x02, 0x00];
+    hdr.checksum();
+    let hdr =
+        GPTHeader::from_slice(&bincode::serialize(&hdr).unwrap()).unwrap();
+    assert!(hdr.revision_mismatch());
+}
+
+/// headers which describe the partition table differently must not agree
+#[test]
+fn gpt_agreement() {
+    let mut file = std::fs::File::open("./gpt_test_data.bin").unwrap();
+    let mut buf: [u8; 1024] = [0; 1024];
+    file.read_exact(&mut buf).unwrap();
+
+    let hdr = GPTHeader::from_slice(&buf[512 ..]).unwrap();
+    assert!(hdr.agrees_with(&hdr.to_backup()));
+
+    let mut other = hdr;
+    other.entry_size = 256;
+    assert!(!hdr.agrees_with(&other));
+
+    let mut other = hdr;
+    other.num_entries = 2;
+    assert!(!hdr.agrees_with(&other));
+}
+
+/// the label of a 1GiB disk with 128 entries, as written by sgdisk, occupies
+/// the first and last 34 blocks
+#[test]
+fn reserved_ranges() {
+    let mut file = std::fs::File::open("./gpt_test_data.bin").unwrap();
+    let mut buf: [u8; 1024] = [0; 1024];
+    file.read_exact(&mut buf).unwrap();
+    let hdr = GPTHeader::from_slice(&buf[512 ..]).unwrap();
+
+    let label = NexusLabel::from_table(hdr, Vec::new());
+    let ranges = label.reserved_ranges(512);
+    let end = 2_097_152 * 512;
+    assert_eq!(
+        ranges,
+        vec![
+            (0, 512),
+            (512, 512),
+            (1024, 32 * 512),
+            (end - 33 * 512, 32 * 512),
+            (end - 512, 512),
+        ]
+    );
+}
+
+/// a label created from scratch is valid as is, and its data partition has
+/// the requested size and fits on the device
+#[test]
+fn new_for_device() {
+    let num_blocks = 131_072;
+    let label =
+        NexusLabel::new_for_device(num_blocks, 512, 32 << 20, None).unwrap();
+
+    let hdr =
+        GPTHeader::from_slice(&bincode::serialize(&label.primary).unwrap())
+            .unwrap();
+    assert_eq!(hdr.lba_alt, num_blocks - 1);
+    assert_eq!(hdr.table_crc, GptEntry::checksum(&label.partitions));
+
+    let data = &label.partitions[1];
+    assert_eq!(data.ent_start, label.partitions[0].ent_end + 1);
+    assert_eq!((data.ent_end - data.ent_start + 1) * 512, 32 << 20);
+    assert!(data.ent_end <= hdr.lba_end);
+
+    assert!(
+        NexusLabel::new_for_device(num_blocks, 511, 32 << 20, None).is_err()
+    );
+    assert!(NexusLabel::new_for_device(num_blocks, 512, 0, None).is_err());
+    assert!(NexusLabel::new_for_device(num_blocks, 512, 1000, None).is_err());
+    assert!(
+        NexusLabel::new_for_device(num_blocks, 512, 64 << 20, None).is_err()
+    );
+}
+
+/// growing a label moves the backup to the new end of the device, leaving
+/// the partitions as they are, and never shrinks it
+#[test]
+fn grow_label() {
+    let num_blocks = 131_072;
+    let mut label =
+        NexusLabel::new_for_device(num_blocks, 512, 32 << 20, None).unwrap();
+    let lba_end = label.primary.lba_end;
+    let partitions = label.partitions.clone();
+
+    assert!(label.grow(num_blocks).is_err());
+    assert!(label.grow(num_blocks - 1).is_err());
+    label.grow(2 * num_blocks).unwrap();
+
+    let hdr =
+        GPTHeader::from_slice(&label.primary_bytes(512).unwrap()).unwrap();
+    assert_eq!(hdr.lba_alt, 2 * num_blocks - 1);
+    assert_eq!(hdr.lba_end, lba_end + num_blocks);
+    assert_eq!(label.partitions, partitions);
+
+    // the backup header follows the backup table, in the last block
+    let backup = label.backup_bytes(512).unwrap();
+    assert_eq!(
+        (backup.len() / 512) as u64,
+        2 * num_blocks - (hdr.lba_end + 1)
+    );
+    let backup_hdr =
+        GPTHeader::from_slice(&backup[backup.len() - 512 ..]).unwrap();
+    assert_eq!(backup_hdr.lba_table, hdr.lba_end + 1);
+    assert!(hdr.agrees_with(&backup_hdr));
+}
+
+/// aligning the data partition moves its start up to the alignment, and the
+/// blocks skipped are reserved as well as no longer usable
+/// reblocking keeps the partitions on the same bytes of the device, and the
+/// GUIDs as they were
+#[test]
+fn reblock_label() {
+    let label =
+        NexusLabel::new_for_device(131_072, 512, 32 << 20, None).unwrap();
+
+    let reblocked = label.reblock(512, 4096).unwrap();
+    assert_eq!(reblocked.primary.lba_alt, 16_383);
+    assert_eq!(reblocked.primary.lba_start, 256);
+    assert_eq!(reblocked.primary.guid, label.primary.guid);
+    for (old, new) in label.partitions.iter().zip(reblocked.partitions.iter()) {
+        assert_eq!(new.ent_type, old.ent_type);
+        assert_eq!(new.ent_guid, old.ent_guid);
+        assert_eq!(new.ent_start * 4096, old.ent_start * 512);
+        assert_eq!((new.ent_end + 1) * 4096, (old.ent_end + 1) * 512);
+    }
+
+    // what is written to the reblocked device reads back as is
+    let hdr =
+        GPTHeader::from_slice(&reblocked.primary_bytes(4096).unwrap()).unwrap();
+    assert_eq!(hdr, reblocked.primary);
+    let backup = reblocked.backup_bytes(4096).unwrap();
+    let backup_hdr =
+        GPTHeader::from_slice(&backup[backup.len() - 4096 ..]).unwrap();
+    assert!(hdr.agrees_with(&backup_hdr));
+
+    // and going back yields the original partitions
+    let back = reblocked.reblock(4096, 512).unwrap();
+    assert_eq!(back.partitions, label.partitions);
+    assert_eq!(back.primary.lba_start, label.primary.lba_start);
+
+    // a partition ending half way through a 4K block cannot be relocated
+    let mut odd = label.clone();
+    odd.partitions[1].ent_end -= 1;
+    assert!(odd.reblock(512, 4096).is_err());
+    assert!(label.reblock(512, 1000).is_err());
+}
+
+#[test]
+fn new_for_device_aligned() {
+    let num_blocks = 131_072;
+    let label =
+        NexusLabel::new_for_device(num_blocks, 512, 32 << 20, Some(8 << 20))
+            .unwrap();
+
+    let meta = &label.partitions[0];
+    let data = &label.partitions[1];
+    assert_eq!(meta.ent_end, 10_239);
+    assert_eq!(data.ent_start, 16_384);
+    assert_eq!((data.ent_end - data.ent_start + 1) * 512, 32 << 20);
+
+    let ranges = label.reserved_ranges(512);
+    assert_eq!(ranges.len(), 6);
+    assert_eq!(ranges[3], (10_240 * 512, 6144 * 512));
+
+    // the default alignment does not leave any padding
+    let label =
+        NexusLabel::new_for_device(num_blocks, 512, 32 << 20, None).unwrap();
+    assert_eq!(label.partitions[1].ent_start, 10_240);
+    assert_eq!(label.reserved_ranges(512).len(), 5);
+
+    assert!(
+        NexusLabel::new_for_device(num_blocks, 512, 32 << 20, Some(1000))
+            .is_err()
+    );
+    assert!(
+        NexusLabel::new_for_device(num_blocks, 512, 32 << 20, Some(256))
+            .is_err()
+    );
+    assert!(NexusLabel::new_for_device(
+        num_blocks,
+        512,
+        56 << 20,
+        Some(32 << 20)
+    )
+    .is_err());
+}
+
+/// a label does not differ from itself, two labels created for the same
+/// device only differ in their GUIDs and resizing the data partition shows
+/// up as a different range of it
+#[test]
+fn label_diff() {
+    let num_blocks = 131_072;
+    let label =
+        NexusLabel::new_for_device(num_blocks, 512, 32 << 20, None).unwrap();
+    assert!(label.diff(&label).is_empty());
+
+    let other =
+        NexusLabel::new_for_device(num_blocks, 512, 32 << 20, None).unwrap();
+    let diffs = label.diff(&other);
+    assert_eq!(diffs.len(), 3);
+    assert!(diffs.iter().all(|d| match d {
+        LabelDiff::DiskGuid {
+            ..
+        }
+        | LabelDiff::PartitionGuid {
+            ..
+        } => true,
+        _ => false,
+    }));
+
+    let mut resized = label.clone();
+    resized.partitions[1].ent_end -= 8;
+    assert_eq!(
+        label.diff(&resized),
+        vec![LabelDiff::PartitionRange {
+            index: 1,
+            current: (
+                label.partitions[1].ent_start,
+                label.partitions[1].ent_end
+            ),
+            desired: (
+                label.partitions[1].ent_start,
+                label.partitions[1].ent_end - 8
+            ),
+        }]
+    );
+}
+
+/// partitions beyond our own two are kept, in order, such that writing the
+/// label back reproduces the partition table found on disk
+#[test]
+fn extra_partitions() {
+    let mut file = std::fs::File::open("./gpt_test_data.bin").unwrap();
+    let mut buf: [u8; 1024] = [0; 1024];
+    file.read_exact(&mut buf).unwrap();
+    let hdr = GPTHeader::from_slice(&buf[512 ..]).unwrap();
+
+    let mut part_buf: [u8; 128 * 128] = [0; 128 * 128];
+    file.read_exact(&mut part_buf).unwrap();
+    let mut partitions =
+        GptEntry::from_slice(&part_buf, hdr.num_entries).unwrap();
+    partitions[2] = partitions[1].clone();
+    partitions[2].ent_name.name = "someone_else".into();
+
+    let label = NexusLabel::from_table(hdr, partitions.clone());
+    assert_eq!(label.partitions.len(), 2);
+    assert_eq!(label.extra_partitions.len(), hdr.num_entries as usize - 2);
+    assert_eq!(label.extra_partitions[0].ent_name.name, "someone_else");
+
+    let table = label.partition_table().cloned().collect::<Vec<_>>();
+    assert_eq!(table, partitions);
+    assert_eq!(GptEntry::checksum(&table), GptEntry::checksum(&partitions));
+}
+
 async fn start() {
     test_known_label();
     make_nexus().await;
     label_child().await;
+    cached_label().await;
+    legacy_mbr().await;
+    inconsistent_pmbr().await;
+    probe_mixed_labels().await;
+    label_4kn().await;
+    grow_child_label().await;
+    relabel_foreign_child().await;
+    sync_needed_labels().await;
     mayastor_env_stop(0);
 }
 
@@ -86,6 +412,12 @@ fn test_known_label() {
 
     let partitions = GptEntry::from_slice(&part_buf, hdr.num_entries).unwrap();
 
+    // the table is padded to 128 entries, only two of which are in use
+    assert_eq!(partitions.len(), 128);
+    let used = GptEntry::non_empty(&partitions).collect::<Vec<_>>();
+    assert_eq!(used.len(), 2);
+    assert_eq!(used[1], &partitions[1]);
+
     assert_eq!(partitions[0].ent_guid.to_string(), PART0_GUID);
     assert_eq!(partitions[1].ent_guid.to_string(), PART1_GUID);
     assert_eq!(partitions[0].ent_name.name, "nexus_meta");
@@ -117,7 +449,7 @@ fn test_known_label() {
 /// device
 async fn make_nexus() {
     let ch = vec![BDEVNAME1.to_string(), BDEVNAME2.to_string()];
-    nexus_create("gpt_nexus", 512 * 131_072, None, &ch)
+    nexus_create("gpt_nexus", 512 * 65_536, None, &ch)
         .await
         .unwrap();
 }
@@ -136,6 +468,13 @@ async fn label_child() {
     // we also write the mbr here hence the offset is 0
     child.write_at(0, &buffer).await.unwrap();
 
+    // the backup header written when the nexus was created belongs to
+    // another label, which would disagree with the one written above
+    let mut zero = hdl.dma_malloc(512).unwrap();
+    zero.fill(0);
+    let num_blocks = hdl.get_bdev().num_blocks();
+    child.write_at((num_blocks - 1) * 512, &zero).await.unwrap();
+
     let mut read_buffer = hdl.dma_malloc(34 * 512).unwrap();
     child.read_at(0, &mut read_buffer).await.unwrap();
 
@@ -143,7 +482,322 @@ async fn label_child() {
         assert_eq!(i, o)
     }
 
-    let nl = child.probe_label().await.unwrap();
+    // a buffer too small for what is to be read is rejected up front
+    let mut small = hdl.dma_malloc(512).unwrap();
+    assert!(child.read_exact_at(0, &mut small, 1024).await.is_err());
+    child.read_exact_at(0, &mut small, 512).await.unwrap();
+
+    let nl = child.probe_label(true).await.unwrap();
     assert_eq!(&nl.partitions[0].ent_guid.to_string(), &PART0_GUID);
     assert_eq!(&nl.partitions[1].ent_guid.to_string(), &PART1_GUID);
 }
+
+/// the label of a child is only probed again once it has been written to
+async fn cached_label() {
+    let nexus = nexus_lookup("gpt_nexus").unwrap();
+    let child = &mut nexus.children[0];
+    let desc = child.get_descriptor().unwrap();
+    let hdl = BdevHandle::try_from(desc).unwrap();
+
+    let cached = child.probe_label(false).await.unwrap();
+    assert_eq!(&cached.partitions[0].ent_guid.to_string(), &PART0_GUID);
+
+    // with the backup header gone, wiping the primary header leaves no label
+    let mut header = hdl.dma_malloc(512).unwrap();
+    child.read_at(512, &mut header).await.unwrap();
+    let mut zero = hdl.dma_malloc(512).unwrap();
+    zero.fill(0);
+    child.write_at(512, &zero).await.unwrap();
+    assert_eq!(child.label_status(), LabelStatus::Unknown);
+    assert!(child.probe_label(false).await.is_err());
+    assert_eq!(child.label_status(), LabelStatus::Missing);
+    assert!(child.needs_label());
+
+    // an error is never cached, and neither is the label once restored
+    child.write_at(512, &header).await.unwrap();
+    assert_eq!(child.probe_label(false).await.unwrap(), cached);
+    assert_eq!(child.label_status(), LabelStatus::Valid);
+    assert!(!child.needs_label());
+}
+
+/// a disk with an MBR partition other than the protective one is not taken
+/// for a GPT labeled disk, even though the GPT headers are intact
+async fn legacy_mbr() {
+    let nexus = nexus_lookup("gpt_nexus").unwrap();
+    let child = &mut nexus.children[0];
+    let desc = child.get_descriptor().unwrap();
+    let hdl = BdevHandle::try_from(desc).unwrap();
+
+    let mut mbr = hdl.dma_malloc(512).unwrap();
+    child.read_at(0, &mut mbr).await.unwrap();
+    assert!(Pmbr::from_slice(mbr.as_slice()).unwrap().is_protective());
+    assert_eq!(child.detect_label_type().await.unwrap(), LabelType::Gpt);
+
+    let mut header = hdl.dma_malloc(512).unwrap();
+    child.read_at(512, &mut header).await.unwrap();
+
+    // the type of the first partition entry, 0x83 being a Linux partition
+    let protective = mbr.as_slice()[450];
+    mbr.as_mut_slice()[450] = 0x83;
+    child.write_at(0, &mbr).await.unwrap();
+    assert_eq!(
+        Pmbr::from_slice(mbr.as_slice()).unwrap().partition_type(),
+        0x83
+    );
+    assert!(child.probe_label(false).await.is_err());
+    assert_eq!(child.label_status(), LabelStatus::Invalid);
+    assert!(child.needs_label());
+    assert_eq!(
+        child.detect_label_type().await.unwrap(),
+        LabelType::LegacyMbr
+    );
+
+    mbr.as_mut_slice()[450] = protective;
+    child.write_at(0, &mbr).await.unwrap();
+    child.probe_label(false).await.unwrap();
+
+    // without the MBR, the primary header gives the label away
+    let mut zero = hdl.dma_malloc(512).unwrap();
+    zero.fill(0);
+    child.write_at(0, &zero).await.unwrap();
+    assert_eq!(child.detect_label_type().await.unwrap(), LabelType::Gpt);
+    child.write_at(512, &zero).await.unwrap();
+    assert_eq!(child.detect_label_type().await.unwrap(), LabelType::Unknown);
+    child.write_at(0, &mbr).await.unwrap();
+    child.write_at(512, &header).await.unwrap();
+    child.probe_label(false).await.unwrap();
+}
+
+/// a protective MBR which does not end where the backup header lives does
+/// not belong to the GPT label found on the disk
+async fn inconsistent_pmbr() {
+    let nexus = nexus_lookup("gpt_nexus").unwrap();
+    let child = &mut nexus.children[0];
+    let desc = child.get_descriptor().unwrap();
+    let hdl = BdevHandle::try_from(desc).unwrap();
+
+    let mut mbr = hdl.dma_malloc(512).unwrap();
+    child.read_at(0, &mut mbr).await.unwrap();
+    let mut pmbr = Pmbr::from_slice(mbr.as_slice()).unwrap();
+    let num_sectors = pmbr.num_sectors();
+
+    // off by one, as if the protective partition included LBA 0
+    pmbr.set_num_sectors(num_sectors + 1);
+    pmbr.write_into(mbr.as_mut_slice()).unwrap();
+    child.write_at(0, &mbr).await.unwrap();
+    assert!(child.probe_label(false).await.is_err());
+    assert_eq!(child.label_status(), LabelStatus::Invalid);
+
+    pmbr.set_num_sectors(num_sectors);
+    pmbr.write_into(mbr.as_mut_slice()).unwrap();
+    child.write_at(0, &mbr).await.unwrap();
+    child.probe_label(false).await.unwrap();
+}
+
+/// wipe the primary label of the second child and probe all children at once,
+/// the results must be in the order of the children
+async fn probe_mixed_labels() {
+    let nexus = nexus_lookup("gpt_nexus").unwrap();
+    let child = &mut nexus.children[1];
+    let desc = child.get_descriptor().unwrap();
+    let hdl = BdevHandle::try_from(desc).unwrap();
+
+    let mut buffer = hdl.dma_malloc(512).unwrap();
+    buffer.fill(0);
+    child.write_at(512, &buffer).await.unwrap();
+
+    let results = nexus.probe_child_labels().await;
+    assert_eq!(results.len(), 2);
+
+    let nl = results[0].as_ref().unwrap();
+    assert_eq!(&nl.partitions[0].ent_guid.to_string(), &PART0_GUID);
+
+    // the backup header of the second child survives, and with it its label
+    let nl = results[1].as_ref().unwrap();
+    assert_ne!(&nl.partitions[0].ent_guid.to_string(), &PART0_GUID);
+}
+
+/// on a 4Kn device the MBR is in the first 512 bytes of block 0, the primary
+/// header in block 1 and the backup header in the last block, which must
+/// still be found when the primary table or header is gone
+async fn label_4kn() {
+    let ch = vec![BDEVNAME3.to_string()];
+    nexus_create("gpt_nexus_4kn", 4096 * 8192, None, &ch)
+        .await
+        .unwrap();
+
+    let nexus = nexus_lookup("gpt_nexus_4kn").unwrap();
+    let child = &mut nexus.children[0];
+    let desc = child.get_descriptor().unwrap();
+    let hdl = BdevHandle::try_from(desc).unwrap();
+    let num_blocks = hdl.get_bdev().num_blocks();
+    assert_eq!(hdl.get_bdev().block_len(), 4096);
+
+    let mut buffer = hdl.dma_malloc(2 * 4096).unwrap();
+    child.read_at(0, &mut buffer).await.unwrap();
+
+    let pmbr = Pmbr::from_slice(&buffer.as_slice()[.. 4096]).unwrap();
+    let hdr = GPTHeader::from_slice(&buffer.as_slice()[4096 ..]).unwrap();
+    assert_eq!(hdr.lba_self, 1);
+    assert_eq!(hdr.lba_alt, num_blocks - 1);
+    assert_eq!(u64::from(pmbr.num_sectors()), num_blocks - 1);
+    assert!(Pmbr::is_consistent(pmbr.num_sectors(), hdr.lba_alt));
+
+    let mut backup = hdl.dma_malloc(4096).unwrap();
+    child
+        .read_at((num_blocks - 1) * 4096, &mut backup)
+        .await
+        .unwrap();
+    GPTHeader::from_slice(backup.as_slice()).unwrap();
+
+    // corrupt the primary partition table, the backup table is used instead
+    let mut garbage = hdl.dma_malloc(4096).unwrap();
+    garbage.fill(0xff);
+    child.write_at(2 * 4096, &garbage).await.unwrap();
+
+    let nl = child.probe_label(true).await.unwrap();
+    assert!(nl.primary_table_corrupt);
+    assert_eq!(nl.partitions[1].ent_start, hdr.lba_start + 1024);
+
+    // wipe the primary header, the label is then read from the backup
+    let mut zero = hdl.dma_malloc(4096).unwrap();
+    zero.fill(0);
+    child.write_at(4096, &zero).await.unwrap();
+
+    let nl = child.probe_label(true).await.unwrap();
+    assert_eq!(nl.primary.guid, hdr.guid);
+    assert_eq!(nl.partitions[1].ent_start, hdr.lba_start + 1024);
+}
+
+/// write the label of a device half the size of the child and grow it to the
+/// size of the child
+async fn grow_child_label() {
+    let nexus = nexus_lookup("gpt_nexus").unwrap();
+    let child = &mut nexus.children[1];
+    let desc = child.get_descriptor().unwrap();
+    let hdl = BdevHandle::try_from(desc).unwrap();
+    let num_blocks = hdl.get_bdev().num_blocks();
+
+    let label = NexusLabel::new_for_device(num_blocks / 2, 512, 16 << 20, None)
+        .unwrap();
+    write_whole_label(nexus, 1, &label).await;
+    let child = &mut nexus.children[1];
+    assert_eq!(child.probe_label(true).await.unwrap(), label);
+
+    assert!(child.grow_label(num_blocks + 1).await.is_err());
+    let grown = child.grow_label(num_blocks).await.unwrap();
+    assert_eq!(grown.primary.lba_alt, num_blocks - 1);
+    assert_eq!(grown.partitions, label.partitions);
+    assert!(child.grow_label(num_blocks).await.is_err());
+
+    let mut mbr = hdl.dma_malloc(512).unwrap();
+    child.read_at(0, &mut mbr).await.unwrap();
+    let pmbr = Pmbr::from_slice(mbr.as_slice()).unwrap();
+    assert!(Pmbr::is_consistent(
+        pmbr.num_sectors(),
+        grown.primary.lba_alt
+    ));
+}
+
+/// write both halves of the label to the child, but not the protective MBR
+async fn write_whole_label(
+    nexus: &mut Nexus,
+    index: usize,
+    label: &NexusLabel,
+) {
+    let child = &mut nexus.children[index];
+    let desc = child.get_descriptor().unwrap();
+    let hdl = BdevHandle::try_from(desc).unwrap();
+
+    let backup = label.backup_bytes(512).unwrap();
+    let table_len = backup.len() - 512;
+
+    let mut buf = hdl.dma_malloc(512 + table_len).unwrap();
+    buf.as_mut_slice()[.. 512]
+        .copy_from_slice(&label.primary_bytes(512).unwrap());
+    buf.as_mut_slice()[512 ..].copy_from_slice(&backup[.. table_len]);
+    child.write_at(512, &buf).await.unwrap();
+
+    let mut buf = hdl.dma_malloc(backup.len()).unwrap();
+    buf.as_mut_slice().copy_from_slice(&backup);
+    child
+        .write_at((label.primary.lba_end + 1) * 512, &buf)
+        .await
+        .unwrap();
+}
+
+/// a child which carries the valid label of another nexus is relabeled, even
+/// though its old backup header is still in place while the primary one is
+/// being replaced
+async fn relabel_foreign_child() {
+    let nexus = nexus_lookup("gpt_nexus").unwrap();
+    let num_blocks = nexus.children[1]
+        .get_descriptor()
+        .unwrap()
+        .get_bdev()
+        .num_blocks();
+
+    let foreign =
+        NexusLabel::new_for_device(num_blocks, 512, 16 << 20, None).unwrap();
+    write_whole_label(nexus, 1, &foreign).await;
+    assert_eq!(nexus.children[1].probe_label(true).await.unwrap(), foreign);
+
+    nexus.sync_labels().await.unwrap();
+    let label = nexus.children[0].probe_label(true).await.unwrap();
+    let relabeled = nexus.children[1].probe_label(true).await.unwrap();
+    assert_eq!(relabeled, label);
+    assert_ne!(relabeled.primary.guid, foreign.primary.guid);
+}
+
+/// wipe both headers of the child, such that it has no label at all
+async fn wipe_label(nexus: &mut Nexus, index: usize) {
+    let child = &mut nexus.children[index];
+    let desc = child.get_descriptor().unwrap();
+    let hdl = BdevHandle::try_from(desc).unwrap();
+    let num_blocks = hdl.get_bdev().num_blocks();
+
+    let mut zero = hdl.dma_malloc(512).unwrap();
+    zero.fill(0);
+    child.write_at(512, &zero).await.unwrap();
+    child.write_at((num_blocks - 1) * 512, &zero).await.unwrap();
+}
+
+/// only the children which lack the label of the nexus are written to when
+/// the labels are synced, the others keep theirs as it is
+async fn sync_needed_labels() {
+    let nexus = nexus_lookup("gpt_nexus").unwrap();
+
+    // the first child carries a label of another disk, which is replaced
+    wipe_label(nexus, 1).await;
+    nexus.sync_labels().await.unwrap();
+    assert!(!nexus.children[0].needs_label());
+    assert!(!nexus.children[1].needs_label());
+
+    // mark the boot code of the MBR of the first child, which is zeroed
+    // whenever the protective MBR is written
+    let child = &mut nexus.children[0];
+    let desc = child.get_descriptor().unwrap();
+    let hdl = BdevHandle::try_from(desc).unwrap();
+    let mut mbr = hdl.dma_malloc(512).unwrap();
+    child.read_at(0, &mut mbr).await.unwrap();
+    mbr.as_mut_slice()[0] = 0xeb;
+    child.write_at(0, &mbr).await.unwrap();
+    let label = child.probe_label(true).await.unwrap();
+
+    wipe_label(nexus, 1).await;
+    nexus.sync_labels().await.unwrap();
+
+    let child = &mut nexus.children[0];
+    child.read_at(0, &mut mbr).await.unwrap();
+    assert_eq!(mbr.as_slice()[0], 0xeb);
+    assert_eq!(child.probe_label(true).await.unwrap(), label);
+
+    // the second child got the very label of the first one
+    let child = &mut nexus.children[1];
+    assert_eq!(child.probe_label(true).await.unwrap(), label);
+    let desc = child.get_descriptor().unwrap();
+    let hdl = BdevHandle::try_from(desc).unwrap();
+    let mut mbr = hdl.dma_malloc(512).unwrap();
+    child.read_at(0, &mut mbr).await.unwrap();
+    assert_eq!(mbr.as_slice()[0], 0);
+}
@@ -1,12 +1,20 @@
-use crossbeam::channel::{after, select, unbounded};
+use crossbeam::channel::{after, select, unbounded, Receiver};
+use futures_timer::Delay;
 use log::info;
-use std::time::Duration;
+use std::{fs::OpenOptions, os::unix::fs::FileExt, time::Duration};
 
 pub mod common;
 
 use mayastor::{
-    bdev::{nexus_create, nexus_lookup},
-    core::{mayastor_env_stop, MayastorCliArgs, MayastorEnvironment, Reactor},
+    bdev::{nexus_create, nexus_lookup, ChildState, FaultReason, NexusState},
+    core::{
+        mayastor_env_stop,
+        BdevHandle,
+        MayastorCliArgs,
+        MayastorEnvironment,
+        Reactor,
+    },
+    rebuild::RebuildState,
 };
 
 use rpc::mayastor::ShareProtocolNexus;
@@ -17,26 +25,41 @@ static BDEVNAME1: &str = "aio:///tmp/disk1.img?blk_size=512";
 static DISKNAME2: &str = "/tmp/disk2.img";
 static BDEVNAME2: &str = "aio:///tmp/disk2.img?blk_size=512";
 
+static DISKNAME3: &str = "/tmp/disk3.img";
+static BDEVNAME3: &str = "aio:///tmp/disk3.img?blk_size=512";
+
+static DISKNAME4: &str = "/tmp/disk4.img";
+static BDEVNAME4: &str = "aio:///tmp/disk4.img?blk_size=512";
+
 static NEXUS_NAME: &str = "rebuild_test";
+static SPARSE_NEXUS_NAME: &str = "sparse_rebuild_test";
 static NEXUS_SIZE: u64 = 10 * 1024 * 1024; // 10MiB
 
 #[test]
 fn rebuild_test() {
-    common::delete_file(&[DISKNAME1.into(), DISKNAME2.into()]);
-    common::truncate_file(DISKNAME1, NEXUS_SIZE / 1024);
-    common::truncate_file(DISKNAME2, NEXUS_SIZE / 1024);
+    let disks = [
+        DISKNAME1.to_string(),
+        DISKNAME2.to_string(),
+        DISKNAME3.to_string(),
+        DISKNAME4.to_string(),
+    ];
+    common::delete_file(&disks);
+    for disk in &disks {
+        common::truncate_file(disk, 2 * NEXUS_SIZE / 1024);
+    }
 
     test_init!();
 
     Reactor::block_on(rebuild_test_start());
 
-    common::delete_file(&[DISKNAME1.into(), DISKNAME2.into()]);
+    common::delete_file(&disks);
 }
 
 async fn rebuild_test_start() {
     create_nexus().await;
 
     let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
     let device = nexus
         .share(ShareProtocolNexus::NexusNbd, None)
         .await
@@ -69,6 +92,26 @@ async fn rebuild_test_start() {
 
     // add the second child
     nexus.add_child(BDEVNAME2).await.unwrap();
+    assert_eq!(nexus.children[1].dirty_bytes(), 0);
+
+    // writes which bypass the faulted child are accounted for, once
+    {
+        let hdl = BdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(0xff);
+        hdl.write_at(0, &buf).await.unwrap();
+        hdl.write_at(2048, &buf).await.unwrap();
+        hdl.write_at(64 * 1024, &buf).await.unwrap();
+    }
+    assert_eq!(nexus.children[1].dirty_bytes(), 6144 + 4096);
+
+    // the log survives a round trip through the meta partition
+    let log = nexus.dirty_log();
+    assert_eq!(log.children.len(), 1);
+    assert_eq!(log.children[0].1, vec![(0, 6144), (65536, 69632)]);
+    nexus.persist_dirty_log().await.unwrap();
+    assert_eq!(nexus.load_dirty_log().await.unwrap(), 1);
+    assert_eq!(nexus.children[1].dirty_bytes(), 6144 + 4096);
 
     // kick's off the rebuild (NOWAIT) so we have to wait on a channel
     let rebuild_complete = nexus.start_rebuild(BDEVNAME2).await.unwrap();
@@ -82,15 +125,149 @@ async fn rebuild_test_start() {
     });
     reactor_poll!(r);
 
+    // a finished rebuild can no longer be aborted
+    assert!(nexus.abort_rebuild(BDEVNAME2).is_err());
+
     let (s, r) = unbounded::<String>();
     std::thread::spawn(move || {
         s.send(common::compare_devices(DISKNAME1, DISKNAME2, true))
     });
     reactor_poll!(r);
 
+    abort_rebuild_mid_copy().await;
+    sparse_rebuild().await;
+
     mayastor_env_stop(0);
 }
 
+/// wait for the rebuild to complete, returning the state it ended in
+fn wait_for_rebuild(complete: Receiver<RebuildState>) -> RebuildState {
+    let (s, r) = unbounded::<RebuildState>();
+    std::thread::spawn(move || {
+        let state = select! {
+            recv(complete) -> state => state.unwrap(),
+            recv(after(Duration::from_secs(5))) -> _ => panic!("timed out waiting for the rebuild to complete"),
+        };
+        s.send(state)
+    });
+    let state;
+    reactor_poll!(r, state);
+    state
+}
+
+/// abort a rebuild while it is copying, the child stays faulted and cannot
+/// be rebuilt again before the aborted task has wound down
+async fn abort_rebuild_mid_copy() {
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+    nexus
+        .fault_children(&[BDEVNAME2.to_string()], FaultReason::Admin)
+        .await
+        .unwrap();
+
+    // the child misses all of the nexus, which takes a while to copy
+    {
+        let hdl = BdevHandle::open(NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(1024 * 1024).unwrap();
+        buf.fill(0xa5);
+        for i in 0 .. NEXUS_SIZE / (1024 * 1024) {
+            hdl.write_at(i * 1024 * 1024, &buf).await.unwrap();
+        }
+    }
+    assert_eq!(nexus.children[1].dirty_bytes(), NEXUS_SIZE);
+
+    let complete = nexus.start_rebuild(BDEVNAME2).await.unwrap();
+    while nexus.get_rebuild_state(BDEVNAME2).await.unwrap().state != "running" {
+        Delay::new(Duration::from_millis(1)).await;
+    }
+    nexus.abort_rebuild(BDEVNAME2).unwrap();
+    assert!(nexus.start_rebuild(BDEVNAME2).await.is_err());
+
+    assert_eq!(wait_for_rebuild(complete), RebuildState::Aborted);
+    nexus.children[1]
+        .wait_for_state(ChildState::Faulted, Duration::from_millis(0))
+        .await
+        .unwrap();
+    assert!(nexus.get_rebuild_state(BDEVNAME2).await.is_err());
+    assert_eq!(nexus.status(), NexusState::Degraded);
+
+    // the child is rebuilt in full once it is started over
+    let complete = nexus.start_rebuild(BDEVNAME2).await.unwrap();
+    assert_eq!(wait_for_rebuild(complete), RebuildState::Completed);
+    nexus.children[1]
+        .wait_for_state(ChildState::Open, Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    let (s, r) = unbounded::<String>();
+    std::thread::spawn(move || {
+        s.send(common::compare_devices(DISKNAME1, DISKNAME2, true))
+    });
+    reactor_poll!(r);
+}
+
+/// a full rebuild from an aio child only copies what has been written to its
+/// file, the holes of the file are skipped
+async fn sparse_rebuild() {
+    // the destination holds data of its own where the source has holes
+    {
+        let file = OpenOptions::new().write(true).open(DISKNAME4).unwrap();
+        let pattern = vec![0x5au8; 1024 * 1024];
+        for i in 0 .. 2 * NEXUS_SIZE / (1024 * 1024) {
+            file.write_all_at(&pattern, i * 1024 * 1024).unwrap();
+        }
+    }
+
+    nexus_create(
+        SPARSE_NEXUS_NAME,
+        NEXUS_SIZE,
+        None,
+        &[BDEVNAME3.to_string()],
+    )
+    .await
+    .unwrap();
+    let nexus = nexus_lookup(SPARSE_NEXUS_NAME).unwrap();
+    {
+        let hdl = BdevHandle::open(SPARSE_NEXUS_NAME, true, false).unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        buf.fill(0xff);
+        hdl.write_at(1024 * 1024, &buf).await.unwrap();
+    }
+
+    let allocated = match nexus.children[0].allocated_ranges() {
+        Ok(ranges) => ranges,
+        Err(e) => {
+            println!("{}, skipping the sparse rebuild test", e);
+            nexus.destroy().await.unwrap();
+            return;
+        }
+    };
+    let allocated_bytes: u64 = allocated.iter().map(|(_, len)| len).sum();
+    assert!(allocated_bytes > 0);
+    assert!(allocated_bytes < NEXUS_SIZE);
+
+    nexus.add_child(BDEVNAME4).await.unwrap();
+    let complete = nexus.start_rebuild(BDEVNAME4).await.unwrap();
+    assert_eq!(wait_for_rebuild(complete), RebuildState::Completed);
+    nexus.children[1]
+        .wait_for_state(ChildState::Open, Duration::from_secs(5))
+        .await
+        .unwrap();
+
+    let data_offset = nexus.data_ent_offset * 512;
+    let file = OpenOptions::new().read(true).open(DISKNAME4).unwrap();
+    let mut buf = vec![0u8; 4096];
+    file.read_exact_at(&mut buf, data_offset + 1024 * 1024)
+        .unwrap();
+    assert!(buf.iter().all(|b| *b == 0xff));
+
+    // never written to through the nexus, so not copied either
+    file.read_exact_at(&mut buf, data_offset + 4 * 1024 * 1024)
+        .unwrap();
+    assert!(buf.iter().all(|b| *b == 0x5a));
+
+    nexus.destroy().await.unwrap();
+}
+
 async fn create_nexus() {
     let ch = vec![BDEVNAME1.to_string()];
     nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &ch)
@@ -0,0 +1,327 @@
+use std::{error::Error, fs::OpenOptions, os::unix::fs::FileExt};
+
+use mayastor::{
+    bdev::{
+        available_crypto_drivers,
+        list_shared_nexuses,
+        nexus_create,
+        nexus_lookup,
+        unshare_nexuses,
+        CachePolicy,
+        KeyFormat,
+        ShareOptions,
+    },
+    core::{Bdev, MayastorCliArgs, MayastorEnvironment, Reactor},
+    target::{
+        iscsi::{share_with_lun_id, ISCSI_QUEUE_DEPTH},
+        Side,
+    },
+};
+use rpc::mayastor::ShareProtocolNexus;
+
+pub mod common;
+
+static DISKNAME1: &str = "/tmp/share1.img";
+static BDEVNAME1: &str = "aio:///tmp/share1.img?blk_size=512";
+
+static DISKNAME2: &str = "/tmp/share2.img";
+static BDEVNAME2: &str = "aio:///tmp/share2.img?blk_size=512";
+
+static NEXUS_NAME: &str = "share_test";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024; // 10MiB
+
+/// offset of the last block of the meta partition of the children
+const KEY_MARKER_OFFSET: u64 = 10239 * 512;
+
+/// offset of the data partition on the children
+const DATA_OFFSET: u64 = 10240 * 512;
+
+#[test]
+fn key_format() {
+    let hex = "00112233445566778899aabbccddeeff";
+    assert!(KeyFormat::Hex.decode(hex).is_err());
+
+    let hex = "01112233445566778899aabbccddeeff";
+    let key = KeyFormat::Hex.decode(hex).unwrap();
+    assert_eq!(key.len(), 16);
+    assert_eq!(key[0], 0x01);
+    assert_eq!(key[15], 0xff);
+    assert_eq!(KeyFormat::Hex.decode(&hex.to_uppercase()).unwrap(), key);
+
+    // a bad digit is reported as such, whatever the length of the key
+    let err = KeyFormat::Hex.decode("0111g").unwrap_err().to_string();
+    assert!(
+        err.contains("invalid hex digit 'g' at position 4"),
+        "{}",
+        err
+    );
+    let err = KeyFormat::Hex.decode("01 11").unwrap_err().to_string();
+    assert!(
+        err.contains("invalid hex digit ' ' at position 2"),
+        "{}",
+        err
+    );
+
+    let err = KeyFormat::Hex.decode("01112").unwrap_err().to_string();
+    assert!(err.contains("odd number of hex digits (5)"), "{}", err);
+
+    let err = KeyFormat::Hex.decode("0111").unwrap_err().to_string();
+    assert!(err.contains("does not match the key size"), "{}", err);
+
+    assert_eq!(KeyFormat::Raw.decode("key").unwrap(), b"key".to_vec());
+    assert!(KeyFormat::Raw.decode("k\0y").is_err());
+}
+
+#[test]
+fn nexus_share() {
+    common::delete_file(&[DISKNAME1.into(), DISKNAME2.into()]);
+    common::truncate_file(DISKNAME1, 64 * 1024);
+    common::truncate_file(DISKNAME2, 64 * 1024);
+
+    test_init!();
+
+    Reactor::block_on(async {
+        list_shares().await;
+        cache_policy().await;
+        unshare_many().await;
+        verify_key().await;
+        failed_share().await;
+        existing_iscsi_target().await;
+    });
+
+    common::delete_file(&[DISKNAME1.into(), DISKNAME2.into()]);
+}
+
+async fn create_nexus() {
+    let children = vec![BDEVNAME1.to_string(), BDEVNAME2.to_string()];
+    nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &children)
+        .await
+        .unwrap();
+}
+
+/// a nexus is listed for as long as it is shared
+async fn list_shares() {
+    create_nexus().await;
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+    assert!(list_shared_nexuses().is_empty());
+
+    let device = nexus
+        .share(ShareProtocolNexus::NexusNbd, None)
+        .await
+        .unwrap();
+
+    let shared = list_shared_nexuses();
+    assert_eq!(shared.len(), 1);
+    assert_eq!(shared[0].0, NEXUS_NAME);
+    assert_eq!(shared[0].1.protocol, ShareProtocolNexus::NexusNbd);
+    assert_eq!(shared[0].1.uri, device);
+
+    nexus.unshare().await.unwrap();
+    assert!(list_shared_nexuses().is_empty());
+    nexus.destroy().await.unwrap();
+}
+
+/// aio bdevs have a write cache, which rules out write-through
+async fn cache_policy() {
+    create_nexus().await;
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+    let mut options = ShareOptions::new(ShareProtocolNexus::NexusNbd, None);
+    options.cache_policy = CachePolicy::WriteThrough;
+    let err = nexus.share_with_options(options.clone()).await.unwrap_err();
+    assert!(err.to_string().contains("volatile write cache"), "{}", err);
+    assert!(list_shared_nexuses().is_empty());
+
+    options.cache_policy = CachePolicy::WriteBack;
+    nexus.share_with_options(options).await.unwrap();
+    assert_eq!(list_shared_nexuses().len(), 1);
+
+    nexus.unshare().await.unwrap();
+    nexus.destroy().await.unwrap();
+}
+
+/// a nexus which does not exist does not keep the others from unsharing,
+/// and a nexus named twice is unshared once
+async fn unshare_many() {
+    let names = vec![NEXUS_NAME.to_string(), format!("{}-2", NEXUS_NAME)];
+    for (name, child) in names.iter().zip(&[BDEVNAME1, BDEVNAME2]) {
+        nexus_create(name, NEXUS_SIZE, None, &[child.to_string()])
+            .await
+            .unwrap();
+        nexus_lookup(name)
+            .unwrap()
+            .share(ShareProtocolNexus::NexusNbd, None)
+            .await
+            .unwrap();
+    }
+    assert_eq!(list_shared_nexuses().len(), 2);
+
+    let results = unshare_nexuses(
+        &[
+            names[0].clone(),
+            "nonexistent".to_string(),
+            names[1].clone(),
+            names[0].clone(),
+        ],
+        2,
+    )
+    .await;
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].0, names[0]);
+    assert!(results[0].1.is_ok());
+    assert_eq!(results[1].0, "nonexistent");
+    assert!(results[1].1.is_err());
+    assert_eq!(results[2].0, names[1]);
+    assert!(results[2].1.is_ok());
+    assert!(list_shared_nexuses().is_empty());
+
+    for name in &names {
+        nexus_lookup(name).unwrap().destroy().await.unwrap();
+    }
+}
+
+fn key_options(key: &str, verify: bool, record: bool) -> ShareOptions {
+    let mut options =
+        ShareOptions::new(ShareProtocolNexus::NexusNbd, Some(key.to_string()));
+    options.verify_key = verify;
+    options.record_key = record;
+    options
+}
+
+fn read_disk(disk: &str, offset: u64) -> Vec<u8> {
+    let file = OpenOptions::new().read(true).open(disk).unwrap();
+    let mut buf = vec![0u8; 512];
+    file.read_exact_at(&mut buf, offset).unwrap();
+    buf
+}
+
+/// the key is verified against a marker in the meta partition, which is
+/// only written when asked to and never touches the data of the nexus
+async fn verify_key() {
+    create_nexus().await;
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+    if !available_crypto_drivers()
+        .iter()
+        .any(|d| d == "crypto_aesni_mb")
+    {
+        println!("no crypto_aesni_mb device, skipping the key marker test");
+        nexus.destroy().await.unwrap();
+        return;
+    }
+
+    let key = "0123456789abcdef";
+    let wrong = "fedcba9876543210";
+
+    // there is nothing to verify against until the marker is recorded
+    let err = nexus
+        .share_with_options(key_options(key, true, false))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("has no key marker"), "{}", err);
+    assert!(list_shared_nexuses().is_empty());
+
+    nexus
+        .share_with_options(key_options(key, false, true))
+        .await
+        .unwrap();
+    nexus.unshare().await.unwrap();
+
+    // another key is refused, whether or not it is to be recorded
+    for record in &[false, true] {
+        let err = nexus
+            .share_with_options(key_options(wrong, true, *record))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("does not match the key marker"),
+            "{}",
+            err
+        );
+    }
+
+    nexus
+        .share_with_options(key_options(key, true, false))
+        .await
+        .unwrap();
+    nexus.unshare().await.unwrap();
+    nexus.destroy().await.unwrap();
+
+    // the crypto bdevs through which the markers are accessed are gone
+    let marker_bdev = format!("crypto-{}-key-marker", NEXUS_NAME);
+    assert!(Bdev::lookup_by_name(&marker_bdev).is_none());
+
+    // both children hold the marker, encrypted, and the data partition is
+    // untouched
+    for disk in &[DISKNAME1, DISKNAME2] {
+        let marker = read_disk(disk, KEY_MARKER_OFFSET);
+        assert!(marker.iter().any(|b| *b != 0));
+        assert!(!marker.starts_with(b"MAYASTOR-CRYPTO-KEY-MARKER"));
+        let last = DATA_OFFSET + NEXUS_SIZE - 512;
+        assert!(read_disk(disk, last).iter().all(|b| *b == 0));
+    }
+}
+
+/// a share which fails to be published leaves no crypto bdev behind
+async fn failed_share() {
+    create_nexus().await;
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+    if !available_crypto_drivers()
+        .iter()
+        .any(|d| d == "crypto_aesni_mb")
+    {
+        println!("no crypto_aesni_mb device, skipping the failed share test");
+        nexus.destroy().await.unwrap();
+        return;
+    }
+
+    let key = "0123456789abcdef";
+    let crypto_bdev = format!("crypto-{}", NEXUS_NAME);
+
+    // there is no such nbd device
+    let mut options = key_options(key, false, false);
+    options.nbd_index = Some(u32::max_value());
+    nexus.share_with_options(options).await.unwrap_err();
+    assert!(!nexus.is_encrypted());
+    assert!(nexus.share_info().is_none());
+    assert!(Bdev::lookup_by_name(&crypto_bdev).is_none());
+
+    // nothing is in the way of sharing it with the key after all
+    nexus
+        .share_with_options(key_options(key, false, false))
+        .await
+        .unwrap();
+    assert!(nexus.is_encrypted());
+    assert!(Bdev::lookup_by_name(&crypto_bdev).is_some());
+    nexus.unshare().await.unwrap();
+    nexus.destroy().await.unwrap();
+}
+
+/// an iscsi target left behind by an earlier attempt is only taken over if it
+/// exports the nexus as the LUN asked for
+async fn existing_iscsi_target() {
+    create_nexus().await;
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+    let bdev = Bdev::lookup_by_name(NEXUS_NAME).unwrap();
+    share_with_lun_id(NEXUS_NAME, &bdev, Side::Nexus, ISCSI_QUEUE_DEPTH, 3)
+        .unwrap();
+
+    let mut options = ShareOptions::new(ShareProtocolNexus::NexusIscsi, None);
+    options.lun_id = Some(1);
+    let err = nexus.share_with_options(options).await.unwrap_err();
+    let cause = err.source().unwrap().to_string();
+    assert!(cause.contains("exports LUN3, not LUN1"), "{}", cause);
+    assert!(nexus.share_info().is_none());
+
+    let uri = nexus
+        .share_with_options(ShareOptions::new(
+            ShareProtocolNexus::NexusIscsi,
+            None,
+        ))
+        .await
+        .unwrap();
+    assert!(uri.ends_with(&format!("{}/3", NEXUS_NAME)), "{}", uri);
+    nexus.unshare().await.unwrap();
+    nexus.destroy().await.unwrap();
+}
@@ -0,0 +1,79 @@
+use mayastor::{
+    bdev::{nexus_create, nexus_lookup, ChildState, FaultReason, NodeKind},
+    core::{mayastor_env_stop, MayastorCliArgs, MayastorEnvironment, Reactor},
+};
+use rpc::mayastor::ShareProtocolNexus;
+
+pub mod common;
+
+static DISKNAME1: &str = "/tmp/topology1.img";
+static BDEVNAME1: &str = "aio:///tmp/topology1.img?blk_size=512";
+
+static DISKNAME2: &str = "/tmp/topology2.img";
+static BDEVNAME2: &str = "aio:///tmp/topology2.img?blk_size=512";
+
+static NEXUS_NAME: &str = "topology_test";
+static NEXUS_SIZE: u64 = 10 * 1024 * 1024; // 10MiB
+
+#[test]
+fn nexus_topology() {
+    common::delete_file(&[DISKNAME1.into(), DISKNAME2.into()]);
+    common::truncate_file(DISKNAME1, 64 * 1024);
+    common::truncate_file(DISKNAME2, 64 * 1024);
+
+    let rc = MayastorEnvironment::new(MayastorCliArgs::default())
+        .start(|| Reactor::block_on(start()).unwrap())
+        .unwrap();
+    assert_eq!(rc, 0);
+
+    common::delete_file(&[DISKNAME1.into(), DISKNAME2.into()]);
+}
+
+async fn start() {
+    bdev_stack().await;
+    mayastor_env_stop(0);
+}
+
+/// the nexus and its children, with the share on top once it is shared,
+/// each built on the one below it
+async fn bdev_stack() {
+    let children = vec![BDEVNAME1.to_string(), BDEVNAME2.to_string()];
+    nexus_create(NEXUS_NAME, NEXUS_SIZE, None, &children)
+        .await
+        .unwrap();
+    let nexus = nexus_lookup(NEXUS_NAME).unwrap();
+
+    let graph = nexus.topology();
+    assert_eq!(graph.nodes.len(), 3);
+    assert_eq!(graph.nodes[0].kind, NodeKind::Nexus);
+    assert_eq!(graph.nodes[0].name, NEXUS_NAME);
+    assert!(graph.nodes[1 ..].iter().all(|n| n.kind == NodeKind::Child));
+    assert_eq!(graph.edges.len(), 2);
+    assert!(graph.edges.iter().all(|e| e.from == NEXUS_NAME));
+
+    nexus
+        .fault_children(&[BDEVNAME2.to_string()], FaultReason::Admin)
+        .await
+        .unwrap();
+    let device = nexus
+        .share(ShareProtocolNexus::NexusNbd, None)
+        .await
+        .unwrap();
+
+    let graph = nexus.topology();
+    assert_eq!(graph.nodes.len(), 4);
+    assert_eq!(graph.nodes[0].kind, NodeKind::Share);
+    assert_eq!(graph.nodes[0].name, device);
+    assert_eq!(graph.nodes[1].kind, NodeKind::Nexus);
+    assert_eq!(graph.edges.len(), 3);
+    assert_eq!(graph.edges[0].from, device);
+    assert_eq!(graph.edges[0].to, NEXUS_NAME);
+    assert_eq!(graph.nodes[3].state, Some(ChildState::Faulted.to_string()));
+
+    let dot = graph.to_dot();
+    assert!(dot.starts_with("digraph nexus {"));
+    assert!(dot.contains(&format!("{:?} -> {:?};", device, NEXUS_NAME)));
+
+    nexus.unshare().await.unwrap();
+    nexus.destroy().await.unwrap();
+}
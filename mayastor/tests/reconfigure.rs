@@ -1,9 +1,18 @@
 #![allow(clippy::cognitive_complexity)]
 
-use std::process::Command;
+use std::{process::Command, time::Duration};
+
+use futures::future::join;
 
 use mayastor::{
-    bdev::{nexus_create, nexus_lookup, NexusState},
+    bdev::{
+        nexus_create,
+        nexus_lookup,
+        ChildState,
+        FaultReason,
+        NexusState,
+        RecoveryPolicy,
+    },
     core::{
         mayastor_env_stop,
         Bdev,
@@ -11,6 +20,7 @@ use mayastor::{
         MayastorEnvironment,
         Reactor,
     },
+    nexus_uri::bdev_create,
 };
 
 static DISKNAME1: &str = "/tmp/disk1.img";
@@ -18,6 +28,9 @@ static BDEVNAME1: &str = "aio:///tmp/disk1.img?blk_size=512";
 
 static DISKNAME2: &str = "/tmp/disk2.img";
 static BDEVNAME2: &str = "aio:///tmp/disk2.img?blk_size=512";
+
+static DISKNAME3: &str = "/tmp/disk3.img";
+static BDEVNAME3: &str = "aio:///tmp/disk3.img?blk_size=512";
 pub mod common;
 #[test]
 fn reconfigure() {
@@ -40,13 +53,13 @@ fn reconfigure() {
     assert_eq!(output.status.success(), true);
 
     let rc = MayastorEnvironment::new(MayastorCliArgs::default())
-        .start(|| Reactor::block_on(works()).unwrap())
+        .start(|| Reactor::block_on(start()).unwrap())
         .unwrap();
 
     assert_eq!(rc, 0);
 
     let output = Command::new("rm")
-        .args(&["-rf", DISKNAME1, DISKNAME2])
+        .args(&["-rf", DISKNAME1, DISKNAME2, DISKNAME3])
         .output()
         .expect("failed delete test file");
 
@@ -66,13 +79,22 @@ async fn stats_compare(first: &Bdev, second: &Bdev) {
     assert_eq!(stats1.num_write_ops, stats2.num_write_ops);
 }
 
+async fn start() {
+    works().await;
+    reconcile_quiesced().await;
+    wait_for_child_state().await;
+    recover_faulted_child().await;
+    replace_bdev().await;
+    mayastor_env_stop(0);
+}
+
 async fn works() {
     let child1 = BDEVNAME1.to_string();
     let child2 = BDEVNAME2.to_string();
 
     let children = vec![child1.clone(), child2.clone()];
 
-    nexus_create("hello", 512 * 131_072, None, &children)
+    nexus_create("hello", 512 * 65_536, None, &children)
         .await
         .unwrap();
 
@@ -202,6 +224,143 @@ async fn works() {
     drop(cd1);
     drop(cd2);
     drop(nd);
+}
 
-    mayastor_env_stop(0);
+/// a quiesced child rejects IO, which must not be taken for a failed device
+async fn reconcile_quiesced() {
+    let nexus = nexus_lookup("hello").unwrap();
+    let child1 = BDEVNAME1.to_string();
+
+    nexus.children[0].quiesce();
+    let state = nexus.reconcile_child(&child1).await.unwrap();
+    assert_eq!(state, nexus.status());
+    assert!(nexus.children[0].can_rw());
+    nexus.children[0].resume();
+
+    // once resumed, it is probed as usual
+    nexus.reconcile_child(&child1).await.unwrap();
+    assert!(nexus.children[0].can_rw());
+}
+
+/// wait for the second child to be closed while it is taken offline
+async fn wait_for_child_state() {
+    let nexus = nexus_lookup("hello").unwrap();
+    let waiter = nexus_lookup("hello").unwrap();
+    let child2 = BDEVNAME2.to_string();
+
+    // the state the child is in is reached right away, any other state is
+    // never reached while nothing happens to the child
+    let timeout = Duration::from_millis(100);
+    nexus.children[1]
+        .wait_for_state(ChildState::Open, timeout)
+        .await
+        .unwrap();
+    assert!(nexus.children[1]
+        .wait_for_state(ChildState::Closed, timeout)
+        .await
+        .is_err());
+
+    let (closed, state) = join(
+        waiter.children[1]
+            .wait_for_state(ChildState::Closed, Duration::from_secs(5)),
+        nexus.offline_child(&child2),
+    )
+    .await;
+    closed.unwrap();
+    assert_eq!(state.unwrap(), NexusState::Degraded);
+
+    nexus.online_child(&child2).await.unwrap();
+}
+
+/// fault the second child and let the recovery loop bring it back
+async fn recover_faulted_child() {
+    let nexus = nexus_lookup("hello").unwrap();
+    let waiter = nexus_lookup("hello").unwrap();
+    let child2 = BDEVNAME2.to_string();
+
+    // policies which would rebuild without probing, or probe in a busy
+    // loop, are refused
+    for policy in &[
+        RecoveryPolicy {
+            probe_interval: Duration::from_millis(10),
+            success_threshold: 0,
+        },
+        RecoveryPolicy {
+            probe_interval: Duration::from_secs(0),
+            success_threshold: 2,
+        },
+    ] {
+        assert!(nexus.set_recovery_policy(Some(*policy)).is_err());
+    }
+
+    let state = nexus
+        .fault_children(&[child2.clone()], FaultReason::Admin)
+        .await
+        .unwrap();
+    assert_eq!(state, NexusState::Degraded);
+    nexus.children[1]
+        .wait_for_state(ChildState::Faulted, Duration::from_millis(0))
+        .await
+        .unwrap();
+
+    nexus
+        .set_recovery_policy(Some(RecoveryPolicy {
+            probe_interval: Duration::from_millis(10),
+            success_threshold: 2,
+        }))
+        .unwrap();
+    waiter.children[1]
+        .wait_for_state(ChildState::Open, Duration::from_secs(30))
+        .await
+        .unwrap();
+    assert_eq!(nexus.status(), NexusState::Online);
+
+    nexus.set_recovery_policy(None).unwrap();
+}
+
+/// move the second child over to a copy of its device, which is only
+/// possible once the child has been taken out of the IO path
+async fn replace_bdev() {
+    let nexus = nexus_lookup("hello").unwrap();
+    let child2 = BDEVNAME2.to_string();
+
+    // the bdev to replace it with must exist
+    assert!(nexus.replace_child_bdev(&child2, BDEVNAME3).await.is_err());
+
+    // nothing writes to the nexus, so the copy holds the data of the child
+    std::fs::copy(DISKNAME2, DISKNAME3).unwrap();
+    bdev_create(BDEVNAME3).await.unwrap();
+
+    // never swap the bdev under IO
+    assert!(nexus.replace_child_bdev(&child2, BDEVNAME3).await.is_err());
+
+    nexus.offline_child(&child2).await.unwrap();
+    nexus.replace_child_bdev(&child2, BDEVNAME3).await.unwrap();
+    assert_eq!(nexus.status(), NexusState::Degraded);
+    assert!(nexus.children[1].can_rw());
+
+    // writes to the nexus now end up on the new bdev, and not the old one
+    let nd = Bdev::lookup_by_name("hello")
+        .unwrap()
+        .open(true)
+        .unwrap()
+        .into_handle()
+        .unwrap();
+    let mut buf = nd.dma_malloc(4096).unwrap();
+    buf.fill(0x55);
+    nd.write_at(0, &buf).await.unwrap();
+
+    for (name, expected) in &[(BDEVNAME2, 0xAA), (BDEVNAME3, 0x55)] {
+        let hdl = Bdev::lookup_by_name(name)
+            .unwrap()
+            .open(false)
+            .unwrap()
+            .into_handle()
+            .unwrap();
+        let mut buf = hdl.dma_malloc(4096).unwrap();
+        hdl.read_at(10240 * 512, &mut buf).await.unwrap();
+        assert!(buf.as_slice().iter().all(|b| b == expected));
+    }
+
+    drop(nd);
 }